@@ -1,15 +1,13 @@
-use std::thread;
 use std::time::Duration;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
 
-use rusqlite::{params, Connection, Error, Result, Row, Rows, Statement};
+use rusqlite::Connection;
 
-use tabled::tables::*;
 
 extern crate simplelog;
 use simplelog::*;
 use std::fs::File;
-use log::{error, info, debug};
+use log::{error, info};
 
 mod cli;
 use crate::cli::*;
@@ -33,16 +31,34 @@ fn main() {
     let server_settings = ServerSettings::new("config.ini");
 
     
+    //a db from a newer version or a path that can't be opened can't be used, stop before touching it
+    let db_conn = match setup_db(&server_settings) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{e}");
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
     //secure the db access for multi-thread use
-    let mut db_conn_cli: std::sync::Arc<Mutex<Connection>>  = Arc::new(Mutex::new(setup_db(&server_settings.conn_type)));
+    let mut db_conn_cli: std::sync::Arc<Mutex<Connection>>  = Arc::new(Mutex::new(db_conn));
     let db_conn_srv: std::sync::Arc<Mutex<Connection>>  = Arc::clone(&db_conn_cli);
 
     //println!("server settings conn type is {:#?}", server_settings.conn_type);
     let srv_addr_and_port = String::from(&server_settings.address) + ":" + &server_settings.port.to_string();
-    thread::spawn(move || {
-        let mut netflow_server = NetflowServer::new(srv_addr_and_port , db_conn_srv);
-        netflow_server.run();
-    });
+    let server_settings_srv = server_settings.clone();
+    let netflow_server = NetflowServer::new(srv_addr_and_port , db_conn_srv, server_settings_srv);
+    let mut server_handle = Some(netflow_server.spawn());
+
+    //ctrl-c and SIGTERM, lets the server write what it has and snapshot an in memory db before exiting
+    ctrlc::set_handler(move || {
+        info!("Stopping ez_netflow_cli");
+        if let Some(server_handle) = server_handle.take() {
+            server_handle.shutdown();
+        }
+        std::process::exit(0);
+    }).expect("Unable to set the ctrl-c handler");
 
 
     loop {
@@ -50,6 +66,14 @@ fn main() {
         clear_console();
         let flow_table = get_all_flows_from_sender(&mut db_conn_cli, &server_settings);
         println!("{flow_table}");
+        if server_settings.show_interface_stats {
+            let interface_table = get_interface_table(&mut db_conn_cli);
+            println!("{interface_table}");
+        }
+        if server_settings.show_sender_stats {
+            let sender_table = get_sender_table(&mut db_conn_cli, &server_settings);
+            println!("{sender_table}");
+        }
     }
     
 }
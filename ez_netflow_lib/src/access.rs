@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::sync::{Mutex, RwLock};
+
+use log::warn;
+
+use crate::settings::ServerSettings;
+use crate::utils::Ipv4Subnet;
+
+//unknown sources can be spoofed, so only this many are remembered for the learning mode log
+const MAX_LOGGED_EXPORTERS: usize = 1024;
+
+//checked by the receivers before a datagram is queued, so a dropped exporter never reaches a decoder or the db
+pub struct ExporterAccess {
+    allowed: Vec<Ipv4Subnet>,
+    max_exporters: usize,
+    learning_mode: bool,
+    //the exporters let in so far, only kept when there is a limit
+    accepted: RwLock<HashSet<Ipv4Addr>>,
+    logged: Mutex<HashSet<Ipv4Addr>>,
+}
+
+impl ExporterAccess {
+    //the exporters already in the db are let in first, so a restart can't hand their places to new ones
+    pub fn new(server_settings: &ServerSettings, known_exporters: &[Ipv4Addr]) -> Self {
+        let access = ExporterAccess {
+            allowed: server_settings.allowed_exporters.clone(),
+            max_exporters: server_settings.max_exporters,
+            learning_mode: server_settings.exporter_learning_mode,
+            accepted: RwLock::new(HashSet::new()),
+            logged: Mutex::new(HashSet::new()),
+        };
+        if access.max_exporters > 0 {
+            let mut accepted = access.accepted.write().unwrap();
+            for sender_ip in known_exporters {
+                if accepted.len() < access.max_exporters && access.is_listed(*sender_ip) {
+                    accepted.insert(*sender_ip);
+                }
+            }
+        }
+        access
+    }
+
+    pub fn is_allowed(&self, sender_ip: Ipv4Addr) -> bool {
+        if !self.is_listed(sender_ip) {
+            self.log_unknown(sender_ip, "it isn't in allowed_exporters, add it there to accept it");
+            return false;
+        }
+        if self.max_exporters == 0 || self.accepted.read().unwrap().contains(&sender_ip) {
+            return true;
+        }
+        let mut accepted = self.accepted.write().unwrap();
+        if accepted.len() >= self.max_exporters && !accepted.contains(&sender_ip) {
+            drop(accepted);
+            self.log_unknown(sender_ip, "max_exporters was reached, raise it to accept it");
+            return false;
+        }
+        accepted.insert(sender_ip);
+        true
+    }
+
+    fn is_listed(&self, sender_ip: Ipv4Addr) -> bool {
+        self.allowed.is_empty() || self.allowed.iter().any(|subnet| subnet.contains(sender_ip))
+    }
+
+    fn log_unknown(&self, sender_ip: Ipv4Addr, reason: &str) {
+        if !self.learning_mode {
+            return;
+        }
+        let mut logged = self.logged.lock().unwrap();
+        if logged.len() < MAX_LOGGED_EXPORTERS && logged.insert(sender_ip) {
+            warn!("Dropping netflow from exporter {} because {}", sender_ip, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    use crate::pipeline::{dispatch_datagram, Datagram, PipelineStats};
+    use crate::replication::Replicator;
+    use crate::settings::parse_config_string;
+    use crate::utils::parse_subnets;
+
+    fn settings(allowed_exporters: &str, max_exporters: usize, exporter_learning_mode: bool) -> ServerSettings {
+        ServerSettings {
+            allowed_exporters: parse_subnets(allowed_exporters),
+            max_exporters,
+            exporter_learning_mode,
+            ..parse_config_string(String::new())
+        }
+    }
+
+    #[test]
+    fn an_exporter_outside_allowed_exporters_is_dropped_and_counted() {
+        let settings = settings("10.0.0.0/24", 0, false);
+        let (access, replicator, stats) = (ExporterAccess::new(&settings, &[]), Replicator::new(&settings), PipelineStats::default());
+        let (datagrams_tx, datagrams_rx) = sync_channel::<Datagram>(4);
+        let decoders = [datagrams_tx];
+        let receive_buffer = [0u8; 64];
+
+        dispatch_datagram(&receive_buffer, 24, "10.0.1.1:2055".parse().unwrap(), &decoders, &access, &replicator, &stats);
+        assert_eq!(stats.counters().rejected, 1);
+        assert!(datagrams_rx.try_recv().is_err());
+
+        dispatch_datagram(&receive_buffer, 24, "10.0.0.1:2055".parse().unwrap(), &decoders, &access, &replicator, &stats);
+        assert_eq!(stats.counters().rejected, 1);
+        assert!(datagrams_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn known_exporters_keep_their_places_under_max_exporters() {
+        let settings = settings("10.0.0.0/24", 2, false);
+        let known = [Ipv4Addr::new(10, 9, 9, 9), Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+        let access = ExporterAccess::new(&settings, &known);
+
+        //10.9.9.9 isn't allowed any more so it doesn't take a place
+        assert!(!access.is_allowed(Ipv4Addr::new(10, 9, 9, 9)));
+        assert!(!access.is_allowed(Ipv4Addr::new(10, 0, 0, 3)));
+        assert!(access.is_allowed(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(access.is_allowed(Ipv4Addr::new(10, 0, 0, 2)));
+    }
+
+    #[test]
+    fn new_exporters_are_let_in_until_max_exporters() {
+        let access = ExporterAccess::new(&settings("", 1, false), &[]);
+        assert!(access.is_allowed(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!access.is_allowed(Ipv4Addr::new(10, 0, 0, 2)));
+        assert!(access.is_allowed(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn the_learning_mode_log_is_capped() {
+        let access = ExporterAccess::new(&settings("192.168.0.0/16", 0, true), &[]);
+        for index in 0..(MAX_LOGGED_EXPORTERS as u32 + 100) {
+            let sender_ip = Ipv4Addr::from(0x0a00_0000 + index);
+            assert!(!access.is_allowed(sender_ip));
+            //each exporter is only logged once
+            assert!(!access.is_allowed(sender_ip));
+        }
+        assert_eq!(access.logged.lock().unwrap().len(), MAX_LOGGED_EXPORTERS);
+
+        let access = ExporterAccess::new(&settings("192.168.0.0/16", 0, false), &[]);
+        assert!(!access.is_allowed(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(access.logged.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusqlite::Connection;
+use chrono::prelude::*;
+use log::{info, warn};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinSet;
+
+use crate::events::*;
+use crate::pipeline::*;
+use crate::settings::ServerSettings;
+use crate::sockets::*;
+use crate::templates::*;
+
+
+//the same pipeline as NetflowServer, only the receive stage runs on the caller's runtime
+//decoding and the db stay on their own threads, so nothing here blocks the runtime for long
+pub struct AsyncNetflowServer {
+    //shared with the receive tasks, more than one with receive_sockets
+    pub sockets: Vec<Arc<UdpSocket>>,
+    pub db_conn: Arc<Mutex<Connection>>,
+    pub settings: ServerSettings,
+    subscribers: Vec<Box<dyn FlowSubscriber>>,
+    events: Option<FlowEvents>,
+    //events the channel consumers didn't take in time
+    channel_dropped: Arc<AtomicU64>,
+    stats: Arc<PipelineStats>,
+    drop_warnings: DropWarnings,
+}
+
+//the wait after a failed receive, doubled for every failure in a row
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RECEIVE_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+impl AsyncNetflowServer {
+    //the sockets are registered with the runtime, so this has to be called from inside it
+    pub fn new(addr_and_port: String, db_conn_srv: Arc<Mutex<Connection>>, server_settings: ServerSettings) -> Self {
+        let std_sockets = bind_receive_sockets(&addr_and_port, &server_settings)
+            .expect("Unable to bind socket");
+        let kernel_drops = KernelDrops::new(&std_sockets);
+        let mut sockets = Vec::new();
+        for socket in std_sockets {
+            socket.set_nonblocking(true).expect("Unable to make the socket non blocking");
+            sockets.push(Arc::new(UdpSocket::from_std(socket).expect("Unable to register the socket with tokio")));
+        }
+        AsyncNetflowServer {
+            sockets,
+            db_conn: db_conn_srv,
+            settings: server_settings,
+            subscribers: Vec::new(),
+            events: None,
+            channel_dropped: Arc::new(AtomicU64::new(0)),
+            stats: Arc::new(PipelineStats::default()),
+            drop_warnings: DropWarnings::new(kernel_drops),
+        }
+    }
+
+    //subscribers have to be added before run is called
+    pub fn subscribe(&mut self, subscriber: Box<dyn FlowSubscriber>) {
+        self.subscribers.push(subscriber);
+    }
+
+    //the events as a tokio channel for async consumers, also has to be called before run
+    //the dispatcher never waits for the consumer, an event that doesn't fit in the channel is dropped and counted
+    pub fn subscribe_channel(&mut self, queue_size: usize) -> mpsc::Receiver<FlowEvent> {
+        let (tx, rx) = mpsc::channel(queue_size.max(1));
+        self.subscribers.push(Box::new(ChannelSubscriber { tx, dropped: Arc::clone(&self.channel_dropped) }));
+        rx
+    }
+
+    //events lost because the subscribers or the channel consumers couldn't keep up
+    pub fn dropped_events(&self) -> u64 {
+        self.events.as_ref().map_or(0, |e| e.dropped()) + self.channel_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn pipeline_stats(&self) -> Arc<PipelineStats> {
+        Arc::clone(&self.stats)
+    }
+
+    //receives until shutdown completes, for example tokio::signal::ctrl_c() or a CancellationToken's cancelled()
+    //returns once everything received is written, the open flows are closed and the in memory db is snapshotted
+    pub async fn run(&mut self, shutdown: impl Future<Output = ()>) {
+        let subscribers = std::mem::take(&mut self.subscribers);
+        //starting closes the flows left open in the db, which can take a while, so it's kept off the runtime too
+        let (db_conn, settings, stats) = (Arc::clone(&self.db_conn), self.settings.clone(), Arc::clone(&self.stats));
+        let pipeline = tokio::task::spawn_blocking(move || start_pipeline(&db_conn, &settings, subscribers, &stats)).await
+            .expect("Unable to start the pipeline");
+        self.events = pipeline.events.clone();
+
+        let receive_buffer_size = get_receive_buffer_size(&self.settings);
+        let mut receivers = JoinSet::new();
+        for socket in &self.sockets {
+            let (socket, decoders, stats) = (Arc::clone(socket), pipeline.decoders.clone(), Arc::clone(&self.stats));
+            let (access, replicator) = (Arc::clone(&pipeline.access), Arc::clone(&pipeline.replicator));
+            receivers.spawn(async move {
+                let mut receive_buffer = vec![0u8; receive_buffer_size];
+                let mut backoff = RECEIVE_ERROR_BACKOFF;
+                loop {
+                    match socket.recv_from(&mut receive_buffer).await {
+                        Ok((byte_count, source)) => {
+                            backoff = RECEIVE_ERROR_BACKOFF;
+                            dispatch_datagram(&receive_buffer, byte_count, source, &decoders, &access, &replicator, &stats);
+                        },
+                        //windows reports an icmp port unreachable for an earlier send as an error here
+                        Err(e) => {
+                            warn!("Unable to receive on {:?}, retrying in {} ms: {e}", socket.local_addr().ok(), backoff.as_millis());
+                            tokio::time::sleep(backoff).await;
+                            backoff = (backoff * 2).min(MAX_RECEIVE_ERROR_BACKOFF);
+                        },
+                    }
+                }
+            });
+        }
+
+        tokio::pin!(shutdown);
+        let mut drop_check = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = drop_check.tick() => {
+                    self.drop_warnings.check(&self.stats, self.events.as_ref());
+                },
+            }
+        }
+
+        //the receive tasks hold on to the decoder queues, the decoders only finish once they are gone
+        receivers.shutdown().await;
+        self.events = None;
+        //joining the pipeline threads blocks, keep it off the runtime's worker threads
+        tokio::task::spawn_blocking(move || pipeline.stop()).await
+            .expect("Unable to stop the pipeline");
+        info!("Netflow server stopped");
+    }
+}
+
+
+//runs on the dispatcher thread, a consumer that falls behind would hold up every other subscriber if it waited
+struct ChannelSubscriber {
+    tx: mpsc::Sender<FlowEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ChannelSubscriber {
+    fn send(&self, event: FlowEvent) {
+        match self.tx.try_send(event) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            },
+            //the consumer dropped its receiver, nothing left to do
+            Err(TrySendError::Closed(_)) => {},
+        }
+    }
+}
+
+impl FlowSubscriber for ChannelSubscriber {
+    fn on_template(&mut self, sender_ip: Ipv4Addr, template: &NetflowTemplate) {
+        self.send(FlowEvent::Template(sender_ip, template.clone()));
+    }
+    fn on_record(&mut self, sender_ip: Ipv4Addr, record: &NetflowTemplate) {
+        self.send(FlowEvent::Record(sender_ip, record.clone()));
+    }
+    fn on_flow_created(&mut self, flow: &NetFlow) {
+        self.send(FlowEvent::FlowCreated(flow.clone()));
+    }
+    fn on_flow_updated(&mut self, flow: &NetFlow) {
+        self.send(FlowEvent::FlowUpdated(flow.clone()));
+    }
+    fn on_flow_expired(&mut self, flow: &NetFlow) {
+        self.send(FlowEvent::FlowExpired(flow.clone()));
+    }
+    fn on_exporter_added(&mut self, sender_ip: Ipv4Addr) {
+        self.send(FlowEvent::ExporterAdded(sender_ip));
+    }
+    fn on_exporter_silent(&mut self, sender_ip: Ipv4Addr, last_seen: DateTime<Local>) {
+        self.send(FlowEvent::ExporterSilent(sender_ip, last_seen));
+    }
+    fn on_exporter_resumed(&mut self, sender_ip: Ipv4Addr) {
+        self.send(FlowEvent::ExporterResumed(sender_ip));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket as StdUdpSocket;
+
+    use super::*;
+    use crate::migrations::migrate_db;
+    use crate::settings::parse_config_string;
+
+    //a template for src and dst address, protocol, ports, bytes and packets, and one record that uses it
+    fn v9_template_and_record() -> Vec<u8> {
+        let mut datagram = vec![0, 9, 0, 2, 0, 0, 0x27, 0x10, 0x65, 0x00, 0x00, 0x00, 0, 0, 0, 1, 0, 0, 0, 0];
+        datagram.extend_from_slice(&[0, 0, 0, 36, 1, 0, 0, 7, 0, 8, 0, 4, 0, 12, 0, 4, 0, 4, 0, 1, 0, 7, 0, 2, 0, 11, 0, 2, 0, 1, 0, 4, 0, 2, 0, 4]);
+        datagram.extend_from_slice(&[1, 0, 0, 28, 10, 0, 0, 1, 10, 0, 0, 2, 6, 0xc3, 0x50, 0x01, 0xbb, 0, 0, 0x05, 0xdc, 0, 0, 0, 10, 0, 0, 0]);
+        datagram
+    }
+
+    #[test]
+    fn datagram_is_written_and_shutdown_closes_the_flow() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        let db_conn = Arc::new(Mutex::new(db_conn));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let mut server = AsyncNetflowServer::new("127.0.0.1:0".to_string(), Arc::clone(&db_conn), parse_config_string(String::new()));
+            let server_addr = server.sockets[0].local_addr().unwrap();
+            let shutdown = async move {
+                let exporter = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+                exporter.send_to(&v9_template_and_record(), server_addr).unwrap();
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            };
+            tokio::time::timeout(Duration::from_secs(10), server.run(shutdown)).await
+                .expect("run didn't return after the shutdown future completed");
+            assert_eq!(server.pipeline_stats().counters().received, 1);
+        });
+
+        let db_conn = db_conn.lock().unwrap();
+        let (src_addr, dst_port, in_octets, closed): (String, u16, i64, bool) = db_conn.query_row(
+            "SELECT src_addr, dst_port, in_octets, end_time IS NOT NULL FROM flows", [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        assert_eq!((src_addr.as_str(), dst_port, in_octets, closed), ("10.0.0.1", 443, 1500, true));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use serde::Serialize;
+
+use crate::templates::*;
+
+//when dedup is enabled every record is tied to the place it was observed
+//a router with "ip flow monitor" applied in both directions reports the same packet twice,
+//once from the input interface (direction 0) and once from the output interface (direction 1)
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct ObservationPoint {
+    pub input_snmp: u32,
+    pub output_snmp: u32,
+    //None when the exporter doesn't send the direction field
+    pub direction: Option<u8>,
+}
+
+impl ObservationPoint {
+    pub fn is_ingress(&self) -> bool {
+        self.direction != Some(1)
+    }
+}
+
+//what a record's observation point means for the direction of the flow it belongs to
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ObservationMatch {
+    //the record is from the selected point, or the first one seen
+    Selected,
+    //the record took over from an egress point, what was counted from that point is a copy of the same packets
+    Replaced,
+    //a copy of packets already counted from the selected point
+    Other,
+}
+
+//picks the observation point a record must come from to be counted in a flow
+//the first point seen wins, but an ingress point replaces an egress one because
+//every packet is seen on ingress while egress depends on where the monitor is applied
+//without the direction field an ingress and an egress monitor report the same interfaces,
+//so their copies look like the same point and are both counted
+pub fn select_observation_point(selected: &mut Option<ObservationPoint>, observed: ObservationPoint) -> ObservationMatch {
+    match selected {
+        Some(s) if *s == observed => ObservationMatch::Selected,
+        Some(s) if !s.is_ingress() && observed.is_ingress() => {
+            *selected = Some(observed);
+            ObservationMatch::Replaced
+        },
+        Some(_) => ObservationMatch::Other,
+        None => {
+            *selected = Some(observed);
+            ObservationMatch::Selected
+        },
+    }
+}
+
+//the same conversation is reported by every exporter along the path
+//only the flow from the exporter that owns the conversation is used for totals,
+//the flows from the other exporters are kept for path analysis
+#[derive(Default)]
+pub struct DedupTable {
+    pub owners: HashMap<FlowKey, Ipv4Addr>,
+}
+
+impl DedupTable {
+    pub fn new() -> Self {
+        DedupTable {
+            owners: HashMap::new(),
+        }
+    }
+
+    //returns true if the sender owns the conversation, claiming it if nobody does
+    pub fn claim(&mut self, flow_key: &FlowKey) -> bool {
+        let owner = self.owners.entry(flow_key.conversation_key())
+            .or_insert(flow_key.sender_ip);
+        *owner == flow_key.sender_ip
+    }
+
+    //called when a flow is closed so another exporter can take over the conversation
+    pub fn release(&mut self, flow_key: &FlowKey) {
+        let conversation_key = flow_key.conversation_key();
+        if self.owners.get(&conversation_key) == Some(&flow_key.sender_ip) {
+            self.owners.remove(&conversation_key);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::*;
+    use crate::senders::NetflowSender;
+
+    const INGRESS: ObservationPoint = ObservationPoint { input_snmp: 1, output_snmp: 2, direction: Some(0) };
+    const EGRESS: ObservationPoint = ObservationPoint { input_snmp: 1, output_snmp: 2, direction: Some(1) };
+
+    //one record of 10.0.0.1:50000 -> 10.0.0.2:443 as seen at an observation point
+    fn record(observation: ObservationPoint, octets: u32, packets: u32) -> NetflowTemplate {
+        NetflowTemplate {
+            protocol: Some(U8Field::Value(6)),
+            src_addr: Some(Ipv4Field::Value(Ipv4Addr::new(10, 0, 0, 1))),
+            dst_addr: Some(Ipv4Field::Value(Ipv4Addr::new(10, 0, 0, 2))),
+            src_port: Some(U16Field::Value(50000)),
+            dst_port: Some(U16Field::Value(443)),
+            in_octets: Some(U32Field::Value(octets)),
+            in_packets: Some(U32Field::Value(packets)),
+            input_snmp: Some(U32Field::Value(observation.input_snmp)),
+            output_snmp: Some(U32Field::Value(observation.output_snmp)),
+            direction: observation.direction.map(U8Field::Value),
+            ..Default::default()
+        }
+    }
+
+    fn parse(records: Vec<NetflowTemplate>, dedup: bool) -> NetFlow {
+        let mut sender = NetflowSender::new(Ipv4Addr::new(192, 168, 0, 1));
+        let mut table = DedupTable::new();
+        for record in records {
+            sender.flow_packets.push(record);
+            sender.parse_packet_to_flow(if dedup { Some(&mut table) } else { None }, &[]);
+        }
+        assert_eq!(sender.flow_stats.len(), 1);
+        sender.flow_stats.into_values().next().unwrap()
+    }
+
+    fn delta_octets(flow: &NetFlow) -> i64 {
+        flow.deltas.iter().map(|d| d.in_octets).sum()
+    }
+
+    #[test]
+    fn ingress_replaces_egress() {
+        let mut selected = Some(EGRESS);
+        assert_eq!(select_observation_point(&mut selected, INGRESS), ObservationMatch::Replaced);
+        assert_eq!(selected, Some(INGRESS));
+        assert_eq!(select_observation_point(&mut selected, EGRESS), ObservationMatch::Other);
+        assert_eq!(select_observation_point(&mut selected, INGRESS), ObservationMatch::Selected);
+    }
+
+    #[test]
+    fn first_point_wins_between_ingress_points() {
+        let other_ingress = ObservationPoint { input_snmp: 3, ..INGRESS };
+        let mut selected = None;
+        assert_eq!(select_observation_point(&mut selected, INGRESS), ObservationMatch::Selected);
+        assert_eq!(select_observation_point(&mut selected, other_ingress), ObservationMatch::Other);
+        assert_eq!(selected, Some(INGRESS));
+    }
+
+    #[test]
+    fn without_direction_ingress_and_egress_look_the_same() {
+        let no_direction = ObservationPoint { direction: None, ..INGRESS };
+        let mut selected = Some(no_direction);
+        assert_eq!(select_observation_point(&mut selected, no_direction), ObservationMatch::Selected);
+    }
+
+    #[test]
+    fn egress_then_ingress_counts_the_packets_once() {
+        let flow = parse(vec![record(EGRESS, 1000, 10), record(INGRESS, 1000, 10), record(EGRESS, 500, 5), record(INGRESS, 500, 5)], true);
+        assert_eq!((flow.in_octets, flow.in_packets), (1500, 15));
+        assert_eq!(delta_octets(&flow), 1500);
+        assert_eq!(flow.fwd_observation, Some(INGRESS));
+    }
+
+    #[test]
+    fn ingress_then_egress_ignores_the_egress_copy() {
+        let flow = parse(vec![record(INGRESS, 1000, 10), record(EGRESS, 1000, 10)], true);
+        assert_eq!((flow.in_octets, flow.in_packets), (1000, 10));
+        assert_eq!(delta_octets(&flow), 1000);
+    }
+
+    #[test]
+    fn without_dedup_every_copy_is_counted() {
+        let flow = parse(vec![record(EGRESS, 1000, 10), record(INGRESS, 1000, 10)], false);
+        assert_eq!((flow.in_octets, flow.in_packets), (2000, 20));
+    }
+
+    #[test]
+    fn only_the_first_exporter_owns_a_conversation() {
+        let key = |sender: u8| FlowKey::new(Ipv4Addr::new(192, 168, 0, sender), 6, 0,
+            (Ipv4Addr::new(10, 0, 0, 1), 50000, 0), (Ipv4Addr::new(10, 0, 0, 2), 443, 0));
+        let mut table = DedupTable::new();
+        assert!(table.claim(&key(1)));
+        assert!(!table.claim(&key(2)));
+        //only the owner can give the conversation up
+        table.release(&key(2));
+        assert!(!table.claim(&key(2)));
+        table.release(&key(1));
+        assert!(table.claim(&key(2)));
+    }
+
+    #[test]
+    fn the_same_addresses_in_two_vrfs_are_owned_separately() {
+        let key = |sender: u8, vrf_id: u32, vlan: u16| FlowKey::new(Ipv4Addr::new(192, 168, 0, sender), 6, vrf_id,
+            (Ipv4Addr::new(10, 0, 0, 1), 50000, vlan), (Ipv4Addr::new(10, 0, 0, 2), 443, vlan));
+        let mut table = DedupTable::new();
+        assert!(table.claim(&key(1, 1, 10)));
+        assert!(table.claim(&key(2, 2, 10)));
+        //the vlan changes between hops, the vrf doesn't
+        assert!(!table.claim(&key(2, 1, 20)));
+        assert!(!table.claim(&key(1, 2, 30)));
+    }
+}
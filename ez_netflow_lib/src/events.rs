@@ -0,0 +1,168 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use chrono::prelude::*;
+
+use crate::templates::*;
+
+//implement the callbacks you need, the rest do nothing
+//the callbacks run on the dispatcher thread, never on the thread receiving netflow
+pub trait FlowSubscriber: Send {
+    fn on_template(&mut self, _sender_ip: Ipv4Addr, _template: &NetflowTemplate) {}
+    fn on_record(&mut self, _sender_ip: Ipv4Addr, _record: &NetflowTemplate) {}
+    fn on_flow_created(&mut self, _flow: &NetFlow) {}
+    fn on_flow_updated(&mut self, _flow: &NetFlow) {}
+    fn on_flow_expired(&mut self, _flow: &NetFlow) {}
+    fn on_exporter_added(&mut self, _sender_ip: Ipv4Addr) {}
+    //nothing was received for longer than the exporter's expected interval
+    fn on_exporter_silent(&mut self, _sender_ip: Ipv4Addr, _last_seen: DateTime<Local>) {}
+    fn on_exporter_resumed(&mut self, _sender_ip: Ipv4Addr) {}
+}
+
+#[derive(Clone)]
+pub enum FlowEvent {
+    Template(Ipv4Addr, NetflowTemplate),
+    Record(Ipv4Addr, NetflowTemplate),
+    FlowCreated(NetFlow),
+    FlowUpdated(NetFlow),
+    FlowExpired(NetFlow),
+    ExporterAdded(Ipv4Addr),
+    ExporterSilent(Ipv4Addr, DateTime<Local>),
+    ExporterResumed(Ipv4Addr),
+}
+
+impl FlowEvent {
+    //calls the subscriber's callback for the event
+    pub fn deliver(&self, subscriber: &mut dyn FlowSubscriber) {
+        match self {
+            FlowEvent::Template(sender_ip, template) => subscriber.on_template(*sender_ip, template),
+            FlowEvent::Record(sender_ip, record) => subscriber.on_record(*sender_ip, record),
+            FlowEvent::FlowCreated(flow) => subscriber.on_flow_created(flow),
+            FlowEvent::FlowUpdated(flow) => subscriber.on_flow_updated(flow),
+            FlowEvent::FlowExpired(flow) => subscriber.on_flow_expired(flow),
+            FlowEvent::ExporterAdded(sender_ip) => subscriber.on_exporter_added(*sender_ip),
+            FlowEvent::ExporterSilent(sender_ip, last_seen) => subscriber.on_exporter_silent(*sender_ip, *last_seen),
+            FlowEvent::ExporterResumed(sender_ip) => subscriber.on_exporter_resumed(*sender_ip),
+        }
+    }
+}
+
+//the server side of the queue, sending never blocks the receive loop
+#[derive(Clone)]
+pub struct FlowEvents {
+    tx: SyncSender<FlowEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl FlowEvents {
+    pub fn send(&self, event: FlowEvent) {
+        match self.tx.try_send(event) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            },
+            //the dispatcher only stops if a subscriber panicked
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+//the thread ends once every FlowEvents clone is dropped, after delivering what is still queued
+pub fn start_event_dispatcher(mut subscribers: Vec<Box<dyn FlowSubscriber>>, queue_size: usize) -> (FlowEvents, JoinHandle<()>) {
+    let (tx, rx) = sync_channel::<FlowEvent>(queue_size.max(1));
+    let dispatcher = thread::spawn(move || {
+        for event in rx {
+            for subscriber in subscribers.iter_mut() {
+                event.deliver(subscriber.as_mut());
+            }
+        }
+    });
+    let events = FlowEvents {
+        tx,
+        dropped: Arc::new(AtomicU64::new(0)),
+    };
+    (events, dispatcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::Mutex;
+
+    struct Recorder {
+        name: &'static str,
+        seen: Arc<Mutex<Vec<(&'static str, Ipv4Addr)>>>,
+    }
+
+    impl FlowSubscriber for Recorder {
+        fn on_exporter_added(&mut self, sender_ip: Ipv4Addr) {
+            self.seen.lock().unwrap().push((self.name, sender_ip));
+        }
+    }
+
+    //holds the dispatcher in its first callback until the test lets it go
+    struct Blocker {
+        started: Sender<()>,
+        release: Receiver<()>,
+    }
+
+    impl FlowSubscriber for Blocker {
+        fn on_exporter_added(&mut self, _sender_ip: Ipv4Addr) {
+            let _ = self.started.send(());
+            let _ = self.release.recv();
+        }
+    }
+
+    #[test]
+    fn every_subscriber_gets_every_event_in_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let subscribers: Vec<Box<dyn FlowSubscriber>> = vec![
+            Box::new(Recorder { name: "first", seen: seen.clone() }),
+            Box::new(Recorder { name: "second", seen: seen.clone() }),
+        ];
+        let (events, dispatcher) = start_event_dispatcher(subscribers, 16);
+        let exporters = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3)];
+        for sender_ip in exporters {
+            events.send(FlowEvent::ExporterAdded(sender_ip));
+        }
+        drop(events);
+        dispatcher.join().unwrap();
+
+        let expected: Vec<_> = exporters.iter().flat_map(|ip| [("first", *ip), ("second", *ip)]).collect();
+        assert_eq!(*seen.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn a_full_queue_drops_and_counts_without_blocking() {
+        let (started_tx, started_rx) = channel();
+        let (release_tx, release_rx) = channel();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let subscribers: Vec<Box<dyn FlowSubscriber>> = vec![
+            Box::new(Blocker { started: started_tx, release: release_rx }),
+            Box::new(Recorder { name: "recorder", seen: seen.clone() }),
+        ];
+        let (events, dispatcher) = start_event_dispatcher(subscribers, 1);
+
+        //the dispatcher is stuck on the first event, the second fills the queue and the rest are dropped
+        events.send(FlowEvent::ExporterAdded(Ipv4Addr::new(10, 0, 0, 1)));
+        started_rx.recv().unwrap();
+        for last_octet in 2..=4 {
+            events.send(FlowEvent::ExporterAdded(Ipv4Addr::new(10, 0, 0, last_octet)));
+        }
+        assert_eq!(events.dropped(), 2);
+
+        drop(release_tx);
+        drop(events);
+        dispatcher.join().unwrap();
+        let seen: Vec<Ipv4Addr> = seen.lock().unwrap().iter().map(|(_, ip)| *ip).collect();
+        assert_eq!(seen, vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]);
+    }
+}
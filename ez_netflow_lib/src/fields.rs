@@ -7,11 +7,86 @@ pub enum NetflowVersion {
     V9(u16)
 }
 
-#[derive(PartialEq, Clone, Serialize)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum TrafficType {
+    //never produced anymore, unicast traffic is classified by scope, see is_unicast
+    #[deprecated(note = "unicast traffic is classified as Private, Public, Loopback, LinkLocal, Documentation, Anycast or Unspecified")]
     Unicast,
     Multicast,
-    Broadcast
+    Broadcast,
+    //the last address of a subnet, from the exported mask or a local subnet in config.ini
+    DirectedBroadcast,
+    Loopback,
+    LinkLocal,
+    //192.0.2.0/24, 198.51.100.0/24 and 203.0.113.0/24 should never be seen on a real network
+    Documentation,
+    //192.88.99.0/24, the 6to4 relay anycast range
+    Anycast,
+    //rfc1918 on both ends
+    Private,
+    Public,
+    //0.0.0.0/8 on either end, usually an exporter that left the address out of the record
+    Unspecified,
+}
+
+//written by hand because the derive would use the deprecated variant
+impl Serialize for TrafficType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_variant("TrafficType", *self as u32, self.as_str())
+    }
+}
+
+impl TrafficType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            #[allow(deprecated)]
+            TrafficType::Unicast => "Unicast",
+            TrafficType::Multicast => "Multicast",
+            TrafficType::Broadcast => "Broadcast",
+            TrafficType::DirectedBroadcast => "DirectedBroadcast",
+            TrafficType::Loopback => "Loopback",
+            TrafficType::LinkLocal => "LinkLocal",
+            TrafficType::Documentation => "Documentation",
+            TrafficType::Anycast => "Anycast",
+            TrafficType::Private => "Private",
+            TrafficType::Public => "Public",
+            TrafficType::Unspecified => "Unspecified",
+        }
+    }
+
+    pub fn is_unicast(&self) -> bool {
+        !matches!(self, TrafficType::Multicast | TrafficType::Broadcast | TrafficType::DirectedBroadcast)
+    }
+}
+
+//tcp flag bits as exported in the TCP_FLAGS field
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+//state of a tcp conversation worked out from the flags of both directions
+#[derive(PartialEq, Copy, Clone, Debug, Serialize)]
+pub enum ConnState {
+    None,
+    Established,
+    HalfOpen,
+    Reset,
+    FinClosed,
+}
+
+impl ConnState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnState::None => "None",
+            ConnState::Established => "Established",
+            ConnState::HalfOpen => "HalfOpen",
+            ConnState::Reset => "Reset",
+            ConnState::FinClosed => "FinClosed",
+        }
+    }
 }
 
 pub enum PacketType {
@@ -100,6 +175,7 @@ pub enum FlowField {
    Direction,
    InDstMac,
    OutSrcMac,
+   IngressVRFID,
    //missing 5 last
    
 
@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+use log::warn;
+use chrono::prelude::*;
+
+use crate::events::FlowSubscriber;
+use crate::templates::{FlowKey, NetFlow};
+
+//leaves room for the ip and udp headers in a 1500 byte mtu
+const MAX_EXPORT_DATAGRAM: usize = 1400;
+//collectors that start after us only learn the template when it's sent again
+const TEMPLATE_RESEND_SEC: i64 = 60;
+const EXPORT_TEMPLATE_ID: u16 = 256;
+
+//the field ids and lengths of the export template, in the order the records are written
+//v9 only uses fields and lengths ez-netflow's own decoder knows, so the output can be collected by another ez-netflow
+const V9_FIELDS: [(u16, u16); 13] = [
+    (8, 4), (12, 4), (7, 2), (11, 2), (4, 1), (6, 1),
+    //IN_BYTES, IN_PKTS, FIRST_SWITCHED, LAST_SWITCHED
+    (1, 4), (2, 4), (22, 4), (21, 4),
+    (58, 2), (59, 2), (234, 4),
+];
+//ipfix has 64 bit counters and absolute times, flowStartMilliseconds and flowEndMilliseconds
+const IPFIX_FIELDS: [(u16, u16); 13] = [
+    (8, 4), (12, 4), (7, 2), (11, 2), (4, 1), (6, 1),
+    (1, 8), (2, 8), (152, 8), (153, 8),
+    (58, 2), (59, 2), (234, 4),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    NetflowV9,
+    Ipfix,
+}
+
+impl ExportFormat {
+    fn fields(&self) -> &'static [(u16, u16)] {
+        match self {
+            ExportFormat::NetflowV9 => &V9_FIELDS,
+            ExportFormat::Ipfix => &IPFIX_FIELDS,
+        }
+    }
+
+    fn header_size(&self) -> usize {
+        match self {
+            ExportFormat::NetflowV9 => 20,
+            ExportFormat::Ipfix => 16,
+        }
+    }
+
+    fn record_size(&self) -> usize {
+        self.fields().iter().map(|(_, len)| usize::from(*len)).sum()
+    }
+}
+
+//"v9" or "ipfix" from config.ini
+pub fn parse_export_format(value: &str) -> ExportFormat {
+    if value.contains("ipfix") {
+        ExportFormat::Ipfix
+    }
+    else {
+        ExportFormat::NetflowV9
+    }
+}
+
+//one direction of a flow, a flow is exported as a record for each direction that had traffic
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportRecord {
+    pub src_ip: Ipv4Addr,
+    pub dst_ip: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+    pub tcp_flags: u8,
+    pub octets: u64,
+    pub packets: u64,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>,
+    pub src_vlan: u16,
+    pub dst_vlan: u16,
+    pub vrf_id: u32,
+}
+
+#[derive(Copy, Clone)]
+struct ExportedCounters {
+    octets: u64,
+    packets: u64,
+    until: DateTime<Local>,
+}
+
+//what was already exported for a flow that's still open, by the source endpoint of each direction
+//the flow can swap its direction once it knows who started the conversation
+struct ExportedFlow {
+    flow: NetFlow,
+    counters: HashMap<(Ipv4Addr, u16, u16), ExportedCounters>,
+    last_export: DateTime<Local>,
+    last_update: DateTime<Local>,
+}
+
+impl ExportedFlow {
+    //the traffic since the last export, the counters of the flow only ever go up
+    fn take_records(&mut self) -> Vec<ExportRecord> {
+        let flow = &self.flow;
+        let mut directions = vec![
+            ((flow.src_ip, flow.src_port, flow.src_vlan), (flow.dst_ip, flow.dst_port, flow.dst_vlan),
+                flow.in_octets, flow.in_packets, flow.tcp_flags, flow.first_seen, flow.last_seen),
+        ];
+        if let (Some(rev_first_seen), Some(rev_last_seen)) = (flow.rev_first_seen, flow.rev_last_seen) {
+            directions.push(((flow.dst_ip, flow.dst_port, flow.dst_vlan), (flow.src_ip, flow.src_port, flow.src_vlan),
+                flow.rev_octets, flow.rev_packets, flow.rev_tcp_flags, rev_first_seen, rev_last_seen));
+        }
+        let mut records = Vec::new();
+        for (src, dst, octets, packets, tcp_flags, first_seen, last_seen) in directions {
+            let exported = self.counters.get(&src).copied()
+                .unwrap_or(ExportedCounters { octets: 0, packets: 0, until: first_seen });
+            if packets <= exported.packets {
+                continue;
+            }
+            records.push(ExportRecord {
+                src_ip: src.0,
+                dst_ip: dst.0,
+                src_port: src.1,
+                dst_port: dst.1,
+                protocol: flow.protocol,
+                tcp_flags,
+                octets: octets.saturating_sub(exported.octets),
+                packets: packets - exported.packets,
+                first_seen: exported.until.max(first_seen),
+                last_seen,
+                src_vlan: src.2,
+                dst_vlan: dst.2,
+                vrf_id: flow.vrf_id,
+            });
+            self.counters.insert(src, ExportedCounters { octets, packets, until: last_seen });
+        }
+        records
+    }
+}
+
+
+//re-exports the flows after they were merged, deduped and classified, as a FlowSubscriber
+//a long flow is exported every active_timeout_sec and once more when it expires, each time with the traffic since the last export
+pub struct FlowExporter {
+    socket: UdpSocket,
+    target: SocketAddr,
+    format: ExportFormat,
+    active_timeout_sec: i64,
+    inactive_timeout_sec: i64,
+    open_flows: HashMap<FlowKey, ExportedFlow>,
+    last_aging: DateTime<Local>,
+    //v9 counts datagrams, ipfix counts data records
+    sequence: u32,
+    //v9 times are relative to the exporter uptime, this is our boot
+    start_time: DateTime<Local>,
+    last_template: Option<DateTime<Local>>,
+}
+
+impl FlowExporter {
+    pub fn new(target: SocketAddr, format: ExportFormat, active_timeout_sec: i64, inactive_timeout_sec: i64) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        Ok(FlowExporter {
+            socket,
+            target,
+            format,
+            active_timeout_sec,
+            inactive_timeout_sec,
+            open_flows: HashMap::new(),
+            last_aging: Local::now(),
+            sequence: 0,
+            start_time: Local::now(),
+            last_template: None,
+        })
+    }
+
+    fn update(&mut self, flow: &NetFlow, expired: bool) {
+        let current_time = Local::now();
+        let key = flow.key();
+        //only what's needed to work out the next records is kept
+        let mut snapshot = flow.clone();
+        snapshot.deltas.clear();
+        snapshot.rate_buckets.clear();
+        let exported = self.open_flows.entry(key).or_insert_with(|| ExportedFlow {
+            flow: snapshot.clone(),
+            counters: HashMap::new(),
+            last_export: current_time,
+            last_update: current_time,
+        });
+        exported.flow = snapshot;
+        exported.last_update = current_time;
+        let due = expired || (current_time - exported.last_export).num_seconds() >= self.active_timeout_sec;
+        //with dedup on, the copy of a conversation reported by another exporter is left out
+        let records = if due && flow.dedup_primary {
+            exported.last_export = current_time;
+            exported.take_records()
+        }
+        else {
+            Vec::new()
+        };
+        if expired {
+            self.open_flows.remove(&key);
+        }
+        self.send(&records, current_time);
+        self.age_flows(current_time);
+    }
+
+    //the expired event of a flow never comes when its batch was rolled back, or the exporter is subscribed
+    //to the event queue and fell behind, so flows that stopped getting updates are exported and forgotten here too
+    //the decoders expire a flow after the inactive timeout, twice that leaves room for a late expired event
+    fn age_flows(&mut self, current_time: DateTime<Local>) {
+        if (current_time - self.last_aging).num_seconds() < 1 {
+            return;
+        }
+        self.last_aging = current_time;
+        let inactive_timeout_sec = 2 * self.inactive_timeout_sec.max(1);
+        let mut records = Vec::new();
+        self.open_flows.retain(|_, exported| {
+            if (current_time - exported.last_update).num_seconds() < inactive_timeout_sec {
+                return true;
+            }
+            if exported.flow.dedup_primary {
+                records.extend(exported.take_records());
+            }
+            false
+        });
+        self.send(&records, current_time);
+    }
+
+    fn send(&mut self, records: &[ExportRecord], current_time: DateTime<Local>) {
+        for datagram in self.encode(records, current_time) {
+            if let Err(e) = self.socket.send_to(&datagram, self.target) {
+                warn!("Unable to export flows to {}: {}", self.target, e);
+            }
+        }
+    }
+
+    //the template is sent first in the datagram, then as many records as fit
+    pub fn encode(&mut self, records: &[ExportRecord], current_time: DateTime<Local>) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        let record_size = self.format.record_size();
+        let mut remaining = records;
+        while !remaining.is_empty() {
+            let with_template = self.last_template
+                .is_none_or(|t| (current_time - t).num_seconds() >= TEMPLATE_RESEND_SEC);
+            let template = if with_template { self.template_set() } else { Vec::new() };
+            let room = MAX_EXPORT_DATAGRAM - self.format.header_size() - template.len() - 4;
+            let (chunk, rest) = remaining.split_at((room / record_size).min(remaining.len()));
+            remaining = rest;
+
+            let mut data = Vec::new();
+            for record in chunk {
+                self.write_record(record, &mut data);
+            }
+            //sets are padded to 4 bytes
+            data.resize(data.len().div_ceil(4) * 4, 0);
+
+            let mut body = template;
+            body.extend_from_slice(&EXPORT_TEMPLATE_ID.to_be_bytes());
+            body.extend_from_slice(&((data.len() + 4) as u16).to_be_bytes());
+            body.extend_from_slice(&data);
+
+            let record_count = chunk.len() + usize::from(with_template);
+            let mut datagram = self.header(record_count, chunk.len(), body.len(), current_time);
+            datagram.extend_from_slice(&body);
+            datagrams.push(datagram);
+            if with_template {
+                self.last_template = Some(current_time);
+            }
+        }
+        datagrams
+    }
+
+    fn header(&mut self, record_count: usize, data_records: usize, body_len: usize, current_time: DateTime<Local>) -> Vec<u8> {
+        let mut header = Vec::with_capacity(self.format.header_size());
+        //rounded up, a record from the last second can't be newer than the export
+        let unix_secs = ((current_time.timestamp_millis() + 999) / 1000) as u32;
+        match self.format {
+            ExportFormat::NetflowV9 => {
+                self.sequence = self.sequence.wrapping_add(1);
+                header.extend_from_slice(&9u16.to_be_bytes());
+                header.extend_from_slice(&(record_count as u16).to_be_bytes());
+                //the uptime has to match unix_secs exactly, the switched times are worked out from both
+                header.extend_from_slice(&self.uptime_ms(unix_secs as i64 * 1000).to_be_bytes());
+                header.extend_from_slice(&unix_secs.to_be_bytes());
+                header.extend_from_slice(&self.sequence.to_be_bytes());
+                //source id
+                header.extend_from_slice(&0u32.to_be_bytes());
+            },
+            ExportFormat::Ipfix => {
+                header.extend_from_slice(&10u16.to_be_bytes());
+                header.extend_from_slice(&((16 + body_len) as u16).to_be_bytes());
+                header.extend_from_slice(&unix_secs.to_be_bytes());
+                //the data records sent before this datagram
+                header.extend_from_slice(&self.sequence.to_be_bytes());
+                self.sequence = self.sequence.wrapping_add(data_records as u32);
+                //observation domain
+                header.extend_from_slice(&0u32.to_be_bytes());
+            },
+        }
+        header
+    }
+
+    fn template_set(&self) -> Vec<u8> {
+        let fields = self.format.fields();
+        let set_id: u16 = match self.format {
+            ExportFormat::NetflowV9 => 0,
+            ExportFormat::Ipfix => 2,
+        };
+        let mut set = Vec::new();
+        set.extend_from_slice(&set_id.to_be_bytes());
+        set.extend_from_slice(&((8 + 4 * fields.len()) as u16).to_be_bytes());
+        set.extend_from_slice(&EXPORT_TEMPLATE_ID.to_be_bytes());
+        set.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (id, len) in fields {
+            set.extend_from_slice(&id.to_be_bytes());
+            set.extend_from_slice(&len.to_be_bytes());
+        }
+        set
+    }
+
+    fn write_record(&self, record: &ExportRecord, data: &mut Vec<u8>) {
+        data.extend_from_slice(&record.src_ip.octets());
+        data.extend_from_slice(&record.dst_ip.octets());
+        data.extend_from_slice(&record.src_port.to_be_bytes());
+        data.extend_from_slice(&record.dst_port.to_be_bytes());
+        data.push(record.protocol);
+        data.push(record.tcp_flags);
+        match self.format {
+            ExportFormat::NetflowV9 => {
+                //the v9 template has 4 byte counters, what doesn't fit is cut off at the max
+                data.extend_from_slice(&u32::try_from(record.octets).unwrap_or(u32::MAX).to_be_bytes());
+                data.extend_from_slice(&u32::try_from(record.packets).unwrap_or(u32::MAX).to_be_bytes());
+                data.extend_from_slice(&self.uptime_ms(record.first_seen.timestamp_millis()).to_be_bytes());
+                data.extend_from_slice(&self.uptime_ms(record.last_seen.timestamp_millis()).to_be_bytes());
+            },
+            ExportFormat::Ipfix => {
+                data.extend_from_slice(&record.octets.to_be_bytes());
+                data.extend_from_slice(&record.packets.to_be_bytes());
+                data.extend_from_slice(&(record.first_seen.timestamp_millis() as u64).to_be_bytes());
+                data.extend_from_slice(&(record.last_seen.timestamp_millis() as u64).to_be_bytes());
+            },
+        }
+        data.extend_from_slice(&record.src_vlan.to_be_bytes());
+        data.extend_from_slice(&record.dst_vlan.to_be_bytes());
+        data.extend_from_slice(&record.vrf_id.to_be_bytes());
+    }
+
+    //wraps after 49 days like a real exporter's, collectors only look at the difference to the header
+    fn uptime_ms(&self, time_ms: i64) -> u32 {
+        (time_ms - self.start_time.timestamp_millis()) as u32
+    }
+}
+
+impl FlowSubscriber for FlowExporter {
+    fn on_flow_created(&mut self, flow: &NetFlow) {
+        self.update(flow, false);
+    }
+    fn on_flow_updated(&mut self, flow: &NetFlow) {
+        self.update(flow, false);
+    }
+    fn on_flow_expired(&mut self, flow: &NetFlow) {
+        self.update(flow, true);
+    }
+}
+
+//the flows still open when the server stops are exported with what they have so far
+impl Drop for FlowExporter {
+    fn drop(&mut self) {
+        let current_time = Local::now();
+        let mut records = Vec::new();
+        for exported in self.open_flows.values_mut() {
+            if exported.flow.dedup_primary {
+                records.extend(exported.take_records());
+            }
+        }
+        self.send(&records, current_time);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::mpsc::sync_channel;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fields::TrafficType;
+    use crate::pipeline::{Datagram, PipelineStats, WriteOp};
+    use crate::server::Decoder;
+    use crate::settings::parse_config_string;
+
+    //switched times only go down to the ms
+    fn time_ms(ago_ms: i64) -> DateTime<Local> {
+        let now_ms = Local::now().timestamp_millis() - ago_ms;
+        DateTime::from_timestamp_millis(now_ms).unwrap().with_timezone(&Local)
+    }
+
+    fn test_flow() -> NetFlow {
+        NetFlow {
+            sender_ip: Ipv4Addr::new(192, 168, 0, 1),
+            src_ip: Ipv4Addr::new(10, 1, 1, 1),
+            dst_ip: Ipv4Addr::new(10, 2, 2, 2),
+            src_port: 50000,
+            dst_port: 443,
+            protocol: 6,
+            src_vlan: 10,
+            dst_vlan: 20,
+            src_mask: 24,
+            dst_mask: 24,
+            vrf_id: 7,
+            in_octets: 1500,
+            in_packets: 10,
+            rev_octets: 9000,
+            rev_packets: 8,
+            tcp_flags: 0x12,
+            rev_tcp_flags: 0x12,
+            fwd_initial_syn: false,
+            rev_initial_syn: false,
+            first_seen: time_ms(30000),
+            last_seen: time_ms(5000),
+            rev_first_seen: Some(time_ms(29000)),
+            rev_last_seen: Some(time_ms(4000)),
+            fwd_observation: None,
+            rev_observation: None,
+            dedup_primary: true,
+            db_id: None,
+            traffic_type: TrafficType::Private,
+            deltas: Vec::new(),
+            rate_buckets: BTreeMap::new(),
+            peak_bps: 0,
+            peak_pps: 0,
+            created_time: Local::now(),
+            last_updated_time: Local::now(),
+            end_time: None,
+        }
+    }
+
+    //sends the flow events through a real socket, then decodes whatever arrived
+    fn round_trip(active_timeout_sec: i64, send_events: impl FnOnce(&mut FlowExporter)) -> Vec<NetFlow> {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let mut exporter = FlowExporter::new(collector.local_addr().unwrap(), ExportFormat::NetflowV9, active_timeout_sec, 300).unwrap();
+        send_events(&mut exporter);
+        drop(exporter);
+
+        let (writes_tx, writes_rx) = sync_channel(16);
+        let mut decoder = Decoder::new(parse_config_string(String::new()), None, None, writes_tx, Arc::new(PipelineStats::default()));
+        let mut receive_buffer = vec![0u8; 65535];
+        while let Ok((byte_count, source)) = collector.recv_from(&mut receive_buffer) {
+            decoder.decode_datagram(Datagram { source, bytes: receive_buffer[..byte_count].to_vec() });
+        }
+        decoder.flush_to_db();
+
+        let mut flows = Vec::new();
+        while let Ok(batch) = writes_rx.try_recv() {
+            for write in batch {
+                if let WriteOp::Flows { flows: written, .. } = write {
+                    flows.extend(written);
+                }
+            }
+        }
+        flows
+    }
+
+    fn assert_same_flow(decoded: &NetFlow, flow: &NetFlow) {
+        assert_eq!(decoded.key(), FlowKey { sender_ip: Ipv4Addr::LOCALHOST, ..flow.key() });
+        assert_eq!((decoded.src_ip, decoded.src_port, decoded.src_vlan), (flow.src_ip, flow.src_port, flow.src_vlan));
+        assert_eq!((decoded.in_octets, decoded.in_packets, decoded.tcp_flags), (flow.in_octets, flow.in_packets, flow.tcp_flags));
+        assert_eq!((decoded.rev_octets, decoded.rev_packets, decoded.rev_tcp_flags), (flow.rev_octets, flow.rev_packets, flow.rev_tcp_flags));
+        assert_eq!((decoded.first_seen, decoded.last_seen), (flow.first_seen, flow.last_seen));
+        assert_eq!((decoded.rev_first_seen, decoded.rev_last_seen), (flow.rev_first_seen, flow.rev_last_seen));
+    }
+
+    #[test]
+    fn v9_export_round_trips_through_the_decoder() {
+        let flow = test_flow();
+        let decoded = round_trip(60, |exporter| {
+            exporter.on_flow_created(&flow);
+            exporter.on_flow_expired(&flow);
+        });
+        assert_eq!(decoded.len(), 1);
+        assert_same_flow(&decoded[0], &flow);
+    }
+
+    #[test]
+    fn active_timeout_exports_add_up_to_the_flow() {
+        let mut flow = test_flow();
+        let first_part = flow.clone();
+        flow.in_octets += 500;
+        flow.in_packets += 4;
+        flow.rev_octets += 3000;
+        flow.rev_packets += 2;
+        flow.last_seen = time_ms(1000);
+        flow.rev_last_seen = Some(time_ms(500));
+        let decoded = round_trip(0, |exporter| {
+            exporter.on_flow_created(&first_part);
+            exporter.on_flow_updated(&flow);
+            exporter.on_flow_expired(&flow);
+        });
+        assert_eq!(decoded.len(), 1);
+        assert_same_flow(&decoded[0], &flow);
+    }
+
+    #[test]
+    fn ipfix_datagram_layout() {
+        let flow = test_flow();
+        let mut exporter = FlowExporter::new("127.0.0.1:9".parse().unwrap(), ExportFormat::Ipfix, 60, 300).unwrap();
+        let records = ExportedFlow { flow: flow.clone(), counters: HashMap::new(), last_export: Local::now(), last_update: Local::now() }.take_records();
+        let datagrams = exporter.encode(&records, Local::now());
+        assert_eq!(datagrams.len(), 1);
+        let datagram = &datagrams[0];
+        let read_u16 = |i: usize| u16::from_be_bytes([datagram[i], datagram[i + 1]]);
+        assert_eq!(read_u16(0), 10);
+        assert_eq!(usize::from(read_u16(2)), datagram.len());
+        //the template set, then the data set with both directions
+        assert_eq!((read_u16(16), read_u16(20), read_u16(22)), (2, EXPORT_TEMPLATE_ID, 13));
+        let data_start = 16 + usize::from(read_u16(18));
+        assert_eq!(read_u16(data_start), EXPORT_TEMPLATE_ID);
+        assert_eq!(usize::from(read_u16(data_start + 2)), 4 + 2 * ExportFormat::Ipfix.record_size());
+        let first_record = &datagram[data_start + 4..];
+        assert_eq!(&first_record[..4], &flow.src_ip.octets());
+        assert_eq!(u64::from_be_bytes(first_record[14..22].try_into().unwrap()), flow.in_octets);
+        assert_eq!(u64::from_be_bytes(first_record[30..38].try_into().unwrap()), flow.first_seen.timestamp_millis() as u64);
+    }
+
+    #[test]
+    fn flows_without_updates_are_aged_out() {
+        let mut exporter = FlowExporter::new("127.0.0.1:9".parse().unwrap(), ExportFormat::NetflowV9, 60, 10).unwrap();
+        let stale_flow = test_flow();
+        exporter.on_flow_created(&stale_flow);
+        //its expired event was lost
+        let long_ago = Local::now() - chrono::Duration::seconds(60);
+        exporter.open_flows.get_mut(&stale_flow.key()).unwrap().last_update = long_ago;
+        exporter.last_aging = long_ago;
+        let mut other_flow = test_flow();
+        other_flow.src_port += 1;
+        exporter.on_flow_created(&other_flow);
+        assert_eq!(exporter.open_flows.len(), 1);
+        assert!(exporter.open_flows.contains_key(&other_flow.key()));
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use serde::Serialize;
+use chrono::prelude::*;
+
+//interface counters are kept in 1 minute buckets
+pub const INTERFACE_BUCKET_SEC: i64 = 60;
+
+//the cli shows the average rate over the last 5 minutes
+pub const INTERFACE_RATE_WINDOW_SEC: i64 = 300;
+
+//snmp index and bucket start in unix seconds
+pub type InterfaceBucket = (u32, i64);
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct InterfaceCounters {
+    pub in_octets: u64,
+    pub in_pkts: u64,
+    pub out_octets: u64,
+    pub out_pkts: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InterfaceStats {
+    pub sender_ip: String,
+    pub snmp_index: u32,
+    pub name: String,
+    pub in_octets: i64,
+    pub in_pkts: i64,
+    pub out_octets: i64,
+    pub out_pkts: i64,
+    pub in_bps: i64,
+    pub out_bps: i64,
+}
+
+//options templates describe the extra data exporters send about themselves, like the interface table
+//only the field types and lengths are needed to find the interface name in the options data
+#[derive(Clone, Default, Debug)]
+pub struct OptionsTemplate {
+    pub id: u16,
+    pub scope_fields: Vec<(u16, u16)>,
+    pub option_fields: Vec<(u16, u16)>,
+}
+
+impl OptionsTemplate {
+    pub fn record_size(&self) -> usize {
+        self.scope_fields.iter()
+            .chain(self.option_fields.iter())
+            .map(|(_, len)| usize::from(*len))
+            .sum()
+    }
+}
+
+pub fn get_interface_bucket(snmp_index: u32, time: DateTime<Local>) -> InterfaceBucket {
+    let ts = time.timestamp();
+    (snmp_index, ts - ts.rem_euclid(INTERFACE_BUCKET_SEC))
+}
+
+//a record is counted as input on the interface it arrived on and as output on the interface it left from
+pub fn add_to_interface_counters(counters: &mut HashMap<InterfaceBucket, InterfaceCounters>, input_snmp: u32, output_snmp: u32, octets: u32, packets: u32, time: DateTime<Local>) {
+    if input_snmp != 0 {
+        let c = counters.entry(get_interface_bucket(input_snmp, time)).or_default();
+        c.in_octets += u64::from(octets);
+        c.in_pkts += u64::from(packets);
+    }
+    if output_snmp != 0 {
+        let c = counters.entry(get_interface_bucket(output_snmp, time)).or_default();
+        c.out_octets += u64::from(octets);
+        c.out_pkts += u64::from(packets);
+    }
+}
+
+//parses "interface_names: 10.0.0.1/1=Gi1; 10.0.0.1/2=Gi2" from config.ini
+pub fn parse_interface_names(value: &str) -> HashMap<(Ipv4Addr, u32), String> {
+    let mut names = HashMap::new();
+    for entry in value.split(';') {
+        let (sender_and_index, name) = match entry.split_once('=') {
+            Some(s) => s,
+            None => continue,
+        };
+        let (sender, index) = match sender_and_index.split_once('/') {
+            Some(s) => s,
+            None => continue,
+        };
+        if let (Ok(sender_ip), Ok(snmp_index)) = (sender.trim().parse::<Ipv4Addr>(), index.trim().parse::<u32>()) {
+            names.insert((sender_ip, snmp_index), name.trim().to_string());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_count_as_input_and_output_in_their_minute() {
+        let mut counters = HashMap::new();
+        let time = Local.timestamp_opt(1_700_000_050, 0).unwrap();
+        add_to_interface_counters(&mut counters, 1, 2, 1000, 10, time);
+        add_to_interface_counters(&mut counters, 2, 1, 500, 5, time + chrono::Duration::seconds(5));
+        //an unknown interface is exported as 0
+        add_to_interface_counters(&mut counters, 0, 1, 100, 1, time);
+
+        //1_700_000_050 is in the minute that started at 1_700_000_040
+        let bucket = |snmp_index| counters[&(snmp_index, 1_700_000_040)];
+        assert_eq!(counters.len(), 2);
+        assert_eq!((bucket(1).in_octets, bucket(1).in_pkts, bucket(1).out_octets, bucket(1).out_pkts), (1000, 10, 600, 6));
+        assert_eq!((bucket(2).in_octets, bucket(2).in_pkts, bucket(2).out_octets, bucket(2).out_pkts), (500, 5, 1000, 10));
+
+        add_to_interface_counters(&mut counters, 1, 0, 100, 1, time + chrono::Duration::seconds(60));
+        assert_eq!(counters[&(1, 1_700_000_100)].in_octets, 100);
+    }
+
+    #[test]
+    fn interface_names_from_the_config() {
+        let names = parse_interface_names(" 10.0.0.1/1=Gi1 ; 10.0.0.1/2 = Gi 0/2;10.0.0.2/1=eth0; 10.0.0.300/1=bad; 10.0.0.1/x=bad; no_index=bad;");
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[&(Ipv4Addr::new(10, 0, 0, 1), 1)], "Gi1");
+        assert_eq!(names[&(Ipv4Addr::new(10, 0, 0, 1), 2)], "Gi 0/2");
+        assert_eq!(names[&(Ipv4Addr::new(10, 0, 0, 2), 1)], "eth0");
+    }
+}
+
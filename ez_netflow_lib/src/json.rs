@@ -0,0 +1,194 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::query::*;
+use crate::settings::ServerSettings;
+
+//json output of the query module, the rows are read a page at a time and written before the next page
+//so a big result never has to fit in memory, wrap files and sockets in a BufWriter
+//the connection is only locked while a page is read, never while writing to a slow consumer
+//a db error ends the output early and is returned as an io error
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum JsonFormat {
+    //a single array, for tools that read the whole document
+    #[default]
+    Array,
+    //one object per line, can be read a line at a time
+    Lines,
+}
+
+//after the first failed write the rest of the rows are skipped and the error is returned by finish()
+pub struct JsonWriter<W: Write> {
+    writer: W,
+    format: JsonFormat,
+    count: usize,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(writer: W, format: JsonFormat) -> Self {
+        JsonWriter {
+            writer,
+            format,
+            count: 0,
+            error: None,
+        }
+    }
+
+    pub fn write<T: Serialize>(&mut self, row: &T) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_row(row) {
+            self.error = Some(e);
+        }
+    }
+
+    fn write_row<T: Serialize>(&mut self, row: &T) -> io::Result<()> {
+        if self.format == JsonFormat::Array {
+            self.writer.write_all(if self.count == 0 { b"[" } else { b"," })?;
+        }
+        serde_json::to_writer(&mut self.writer, row)?;
+        if self.format == JsonFormat::Lines {
+            self.writer.write_all(b"\n")?;
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    //closes the array and returns the number of rows written
+    pub fn finish(mut self) -> io::Result<usize> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if self.format == JsonFormat::Array {
+            self.writer.write_all(if self.count == 0 { b"[]" } else { b"]" })?;
+        }
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+//rows read per lock, the server writes between pages so a slow writer never holds up the db
+const PAGE_ROWS: usize = 1000;
+
+//pages through the rows with limit and offset, fetch reads one page from the locked connection
+//rows the server adds or reorders while the pages are read can be skipped or repeated
+fn write_pages<T: Serialize, W: Write>(db_conn: &Mutex<Connection>, limit: Option<usize>, offset: usize, json_writer: &mut JsonWriter<W>,
+    mut fetch: impl FnMut(&Connection, usize, usize) -> rusqlite::Result<Vec<T>>) -> io::Result<()> {
+    let mut read = 0;
+    loop {
+        let page_rows = limit.map_or(PAGE_ROWS, |limit| (limit - read).min(PAGE_ROWS));
+        if page_rows == 0 {
+            return Ok(());
+        }
+        let rows = fetch(&db_conn.lock().unwrap(), page_rows, offset + read).map_err(io::Error::other)?;
+        for row in &rows {
+            json_writer.write(row);
+        }
+        read += rows.len();
+        if rows.len() < page_rows || json_writer.error.is_some() {
+            return Ok(());
+        }
+    }
+}
+
+pub fn write_flows_json(db_conn: &Mutex<Connection>, query: &FlowQuery, format: JsonFormat, writer: impl Write) -> io::Result<usize> {
+    let mut json_writer = JsonWriter::new(writer, format);
+    write_pages(db_conn, query.limit, query.offset, &mut json_writer, |conn, limit, offset| {
+        query_flows(conn, &FlowQuery { limit: Some(limit), offset, ..query.clone() })
+    })?;
+    json_writer.finish()
+}
+
+pub fn write_deltas_json(db_conn: &Mutex<Connection>, query: &DeltaQuery, format: JsonFormat, writer: impl Write) -> io::Result<usize> {
+    let mut json_writer = JsonWriter::new(writer, format);
+    write_pages(db_conn, query.limit, query.offset, &mut json_writer, |conn, limit, offset| {
+        query_deltas(conn, &DeltaQuery { limit: Some(limit), offset, ..query.clone() })
+    })?;
+    json_writer.finish()
+}
+
+//there's a row per exporter, they are read under one lock
+pub fn write_senders_json(db_conn: &Mutex<Connection>, server_settings: &ServerSettings, current_time: &DateTime<Local>, format: JsonFormat, writer: impl Write) -> io::Result<usize> {
+    let senders = query_senders(&db_conn.lock().unwrap(), server_settings, current_time).map_err(io::Error::other)?;
+    let mut json_writer = JsonWriter::new(writer, format);
+    for sender in &senders {
+        json_writer.write(sender);
+    }
+    json_writer.finish()
+}
+
+//GroupBy::Host gives the hosts
+pub fn write_aggregates_json(db_conn: &Mutex<Connection>, query: &AggregateQuery, format: JsonFormat, writer: impl Write) -> io::Result<usize> {
+    let mut json_writer = JsonWriter::new(writer, format);
+    write_pages(db_conn, query.limit, query.offset, &mut json_writer, |conn, limit, offset| {
+        query_aggregates(conn, &AggregateQuery { limit: Some(limit), offset, ..query.clone() })
+    })?;
+    json_writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate_db;
+
+    //fails every write made while the db is locked
+    struct UnlockedCheck<'a> {
+        db_conn: &'a Mutex<Connection>,
+        output: Vec<u8>,
+    }
+
+    impl Write for UnlockedCheck<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.db_conn.try_lock().is_err() {
+                return Err(io::Error::other("written while the db was locked"));
+            }
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_db(flows: i64) -> Mutex<Connection> {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        db_conn.execute("INSERT INTO senders (ip) VALUES ('192.168.0.1')", []).unwrap();
+        for octets in 0..flows {
+            db_conn.execute("INSERT INTO flows (sender_ip, src_addr, dst_addr, protocol, src_port, dst_port, in_octets, in_pkts, traffic_type)
+                VALUES ('192.168.0.1', '10.0.0.1', '10.0.0.2', 6, 50000, 443, ?1, 1, 'Private')", [octets % 7]).unwrap();
+        }
+        Mutex::new(db_conn)
+    }
+
+    #[test]
+    fn flows_are_written_a_page_at_a_time_without_the_lock() {
+        let db_conn = test_db(2500);
+        let mut writer = UnlockedCheck { db_conn: &db_conn, output: Vec::new() };
+        let written = write_flows_json(&db_conn, &FlowQuery::default(), JsonFormat::Lines, &mut writer).unwrap();
+        assert_eq!(written, 2500);
+        //the ties in bytes are sorted by id, so no flow is repeated across pages
+        let mut ids: Vec<i64> = String::from_utf8(writer.output).unwrap().lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["id"].as_i64().unwrap())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 2500);
+    }
+
+    #[test]
+    fn limit_and_offset_span_the_pages() {
+        let db_conn = test_db(2500);
+        let query = FlowQuery { limit: Some(1200), offset: 1000, ..Default::default() };
+        assert_eq!(write_flows_json(&db_conn, &query, JsonFormat::Array, Vec::new()).unwrap(), 1200);
+        let query = FlowQuery { limit: None, offset: 2000, ..Default::default() };
+        assert_eq!(write_flows_json(&db_conn, &query, JsonFormat::Array, Vec::new()).unwrap(), 500);
+    }
+}
@@ -1,11 +1,3 @@
-use std::thread;
-use std::time::Duration;
-use std::sync::{Arc, Mutex, MutexGuard};
-
-use rusqlite::{params, Connection, Error, Result, Row, Rows, Statement};
-
-use tabled::tables::*;
-
 pub mod server;
 pub mod fields;
 pub mod templates;
@@ -14,11 +6,23 @@ pub mod utils;
 pub mod sql;
 pub mod settings;
 pub mod time;
+pub mod dedup;
+pub mod interfaces;
+pub mod events;
+pub mod rollups;
+pub mod migrations;
+pub mod retention;
+pub mod pipeline;
+pub mod sockets;
+pub mod access;
+pub mod replication;
+pub mod flow_export;
+pub mod query;
+pub mod json;
+#[cfg(feature = "tokio")]
+pub mod async_server;
 
-use server::NetflowServer;
 
-use crate::sql::*;
-use crate::settings::*;
 
 
 /////////////////////////////////////////////
@@ -28,18 +32,20 @@ use crate::settings::*;
 // let server_settings = ServerSettings::new("config.ini");
 //
 // //secure the db access for multi-thread use
-// let mut db_conn_cli: std::sync::Arc<Mutex<Connection>>  = Arc::new(Mutex::new(setup_db(&server_settings.conn_type)));
+// let mut db_conn_cli: std::sync::Arc<Mutex<Connection>>  = Arc::new(Mutex::new(setup_db(&server_settings).expect("Unable to set up the db")));
 // let db_conn_srv: std::sync::Arc<Mutex<Connection>>  = Arc::clone(&db_conn_cli);
 //
 // //println!("server settings conn type is {:#?}", server_settings.conn_type);
 // let srv_addr_and_port = String::from(&server_settings.address) + ":" + &server_settings.port.to_string();
-// let server_thread = thread::spawn(move || {
-//     let mut netflow_server = NetflowServer::new(srv_addr_and_port , db_conn_srv);
-//     netflow_server.run();
-// });
+// let server_settings_srv = server_settings.clone();
+// let netflow_server = NetflowServer::new(srv_addr_and_port , db_conn_srv, server_settings_srv);
+// let server_handle = netflow_server.spawn();
+//
+// //writes what's left to the db and stops the server threads
+// server_handle.shutdown();
 //
 /////////////////////////////////////////////
 //// 
-//// CHECK SQL RS FOR FUNCTIONS THAT PULL DATA FROM DB
+//// CHECK QUERY RS FOR TYPED READS OF THE DB AND SQL RS FOR THE TABLES BUILT ON THEM
 ////
 /////////////////////////////////////////////
\ No newline at end of file
@@ -0,0 +1,411 @@
+use std::fmt;
+
+use rusqlite::{Connection, Transaction};
+use log::info;
+
+//each migration takes the db from the version before it to its own version, the first one is version 1
+//never change a migration once it's released, add a new one instead
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    migration_1_base_schema,
+    migration_2_fix_delta_flow_id_type,
+    migration_3_sender_counters,
+    migration_4_epoch_columns,
+];
+
+//the schema version this build of ez_netflow writes
+pub const SCHEMA_VERSION: i32 = MIGRATIONS.len() as i32;
+
+#[derive(Debug)]
+pub enum DbSetupError {
+    //the db file or the snapshot couldn't be opened, the path is kept so the error says where it looked
+    Open { path: String, error: rusqlite::Error },
+    //the db was written by a newer ez_netflow, running old code against it could corrupt it
+    NewerVersion { found: i32, supported: i32 },
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for DbSetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbSetupError::Open { path, error } => write!(f, "Unable to open the db at {path}: {error}"),
+            DbSetupError::NewerVersion { found, supported } => write!(f,
+                "The db schema is version {found} but this version of ez_netflow only supports up to version {supported}, \
+                upgrade ez_netflow or use a different db file"),
+            DbSetupError::Sqlite(e) => write!(f, "Unable to set up the db: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DbSetupError {}
+
+impl From<rusqlite::Error> for DbSetupError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbSetupError::Sqlite(e)
+    }
+}
+
+pub fn get_schema_version(db_conn: &Connection) -> rusqlite::Result<i32> {
+    db_conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+//every pending migration runs in its own transaction together with the version bump,
+//so a failed migration leaves the db on the last good version
+pub fn migrate_db(db_conn: &mut Connection) -> Result<(), DbSetupError> {
+    let current_version = get_schema_version(db_conn)?;
+    if current_version > SCHEMA_VERSION {
+        return Err(DbSetupError::NewerVersion { found: current_version, supported: SCHEMA_VERSION });
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current_version as usize) {
+        let version = index as i32 + 1;
+        let tx = db_conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        info!("Migrated db schema to version {}", version);
+    }
+    Ok(())
+}
+
+pub fn add_column_if_missing(db_conn: &Connection, table: &str, column: &str, column_type: &str) -> rusqlite::Result<()> {
+    if !column_exists(db_conn, table, column)? {
+        db_conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {column_type}"), [])?;
+    }
+    Ok(())
+}
+
+fn column_exists(db_conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = db_conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(names.iter().any(|name| name == column))
+}
+
+//everything up to the first versioned release
+//dbs from before versioning also start at version 0, so the tables are only created if missing
+//and the columns added since the tables were first released are added if missing
+fn migration_1_base_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute("CREATE TABLE IF NOT EXISTS senders (
+        ip TEXT PRIMARY KEY
+        )",
+        [],
+        )?;
+
+    tx.execute("CREATE TABLE IF NOT EXISTS delta (
+        id INTEGER PRIMARY KEY,
+        flow_id INTEGER NOT NULL,
+        updated_time TEXT,
+        in_octets INTEGER,
+        in_pkts INTEGER,
+        bps INTEGER,
+        pps INTEGER,
+        FOREIGN KEY (flow_id) REFERENCES flows(id)
+        )",
+        [],
+        )?;
+
+    tx.execute("CREATE TABLE IF NOT EXISTS flows (
+        id INTEGER PRIMARY KEY,
+        sender_ip TEXT NOT NULL,
+        src_addr TEXT,
+        dst_addr TEXT,
+        protocol INTEGER,
+        src_port INTEGER,
+        dst_port INTEGER,
+        tcp_flags INTEGER,
+        input_snmp INTEGER,
+        output_snmp INTEGER,
+        in_octets INTEGER,
+        in_pkts INTEGER,
+        src_tos INTEGER,
+        src_mask INTEGER,
+        dst_mask INTEGER,
+        next_hop TEXT,
+        icmp TEXT,
+        traffic_type TEXT,
+        created_time TEXT,
+        flow_key TEXT,
+        end_time TEXT,
+        rev_octets INTEGER DEFAULT 0,
+        rev_pkts INTEGER DEFAULT 0,
+        first_seen TEXT,
+        last_seen TEXT,
+        rev_first_seen TEXT,
+        rev_last_seen TEXT,
+        dedup_primary INTEGER DEFAULT 1,
+        conn_state TEXT,
+        avg_bps INTEGER DEFAULT 0,
+        avg_pps INTEGER DEFAULT 0,
+        peak_bps INTEGER DEFAULT 0,
+        peak_pps INTEGER DEFAULT 0,
+        FOREIGN KEY (sender_ip) REFERENCES senders(ip)
+        )",
+        [],
+        )?;
+
+    for (column, column_type) in [
+        ("flow_key", "TEXT"),
+        ("end_time", "TEXT"),
+        ("rev_octets", "INTEGER DEFAULT 0"),
+        ("rev_pkts", "INTEGER DEFAULT 0"),
+        ("first_seen", "TEXT"),
+        ("last_seen", "TEXT"),
+        ("rev_first_seen", "TEXT"),
+        ("rev_last_seen", "TEXT"),
+        ("dedup_primary", "INTEGER DEFAULT 1"),
+        ("conn_state", "TEXT"),
+        ("avg_bps", "INTEGER DEFAULT 0"),
+        ("avg_pps", "INTEGER DEFAULT 0"),
+        ("peak_bps", "INTEGER DEFAULT 0"),
+        ("peak_pps", "INTEGER DEFAULT 0"),
+    ] {
+        add_column_if_missing(tx, "flows", column, column_type)?;
+    }
+
+    //names come from config.ini or from the options data the exporters send
+    tx.execute("CREATE TABLE IF NOT EXISTS interfaces (
+        sender_ip TEXT NOT NULL,
+        snmp_index INTEGER NOT NULL,
+        name TEXT,
+        description TEXT,
+        source TEXT,
+        PRIMARY KEY (sender_ip, snmp_index)
+        )",
+        [],
+        )?;
+
+    //per interface counters in 1 minute buckets
+    tx.execute("CREATE TABLE IF NOT EXISTS interface_stats (
+        sender_ip TEXT NOT NULL,
+        snmp_index INTEGER NOT NULL,
+        bucket_start INTEGER NOT NULL,
+        in_octets INTEGER,
+        in_pkts INTEGER,
+        out_octets INTEGER,
+        out_pkts INTEGER,
+        PRIMARY KEY (sender_ip, snmp_index, bucket_start)
+        )",
+        [],
+        )?;
+
+    //the port is the destination port, the flows are oriented so that's the service
+    for table in ["rollup_1m", "rollup_1h", "rollup_1d"] {
+        tx.execute(&format!("CREATE TABLE IF NOT EXISTS {table} (
+            bucket_start INTEGER NOT NULL,
+            sender_ip TEXT NOT NULL,
+            src_addr TEXT NOT NULL,
+            dst_addr TEXT NOT NULL,
+            protocol INTEGER NOT NULL,
+            dst_port INTEGER NOT NULL,
+            octets INTEGER,
+            pkts INTEGER,
+            PRIMARY KEY (bucket_start, sender_ip, src_addr, dst_addr, protocol, dst_port)
+            )"),
+            [],
+            )?;
+    }
+
+    //each level remembers up to where its source has been rolled up, so nothing is counted twice
+    tx.execute("CREATE TABLE IF NOT EXISTS rollup_state (
+        rollup_table TEXT PRIMARY KEY,
+        rolled_until INTEGER NOT NULL
+        )",
+        [],
+        )?;
+
+    //closed flows keep their key, so only the open flow for a key has to be unique
+    tx.execute("DROP INDEX IF EXISTS flows_flow_key", [])?;
+    tx.execute("CREATE UNIQUE INDEX IF NOT EXISTS flows_open_flow_key ON flows (flow_key) WHERE end_time IS NULL",
+        [],
+        )?;
+
+    Ok(())
+}
+
+//dbs from before versioning were created with "flow_id INTERGER", sqlite can't change a column type
+//so the table is copied into a new one
+fn migration_2_fix_delta_flow_id_type(tx: &Transaction) -> rusqlite::Result<()> {
+    let flow_id_type: String = tx.query_row(
+        "SELECT type FROM pragma_table_info('delta') WHERE name = 'flow_id'",
+        [],
+        |row| row.get(0),
+    )?;
+    if flow_id_type.eq_ignore_ascii_case("INTEGER") {
+        return Ok(());
+    }
+
+    tx.execute("CREATE TABLE delta_migrated (
+        id INTEGER PRIMARY KEY,
+        flow_id INTEGER NOT NULL,
+        updated_time TEXT,
+        in_octets INTEGER,
+        in_pkts INTEGER,
+        bps INTEGER,
+        pps INTEGER,
+        FOREIGN KEY (flow_id) REFERENCES flows(id)
+        )",
+        [],
+        )?;
+    tx.execute("INSERT INTO delta_migrated (id, flow_id, updated_time, in_octets, in_pkts, bps, pps)
+        SELECT id, flow_id, updated_time, in_octets, in_pkts, bps, pps FROM delta",
+        [],
+        )?;
+    tx.execute("DROP TABLE delta", [])?;
+    tx.execute("ALTER TABLE delta_migrated RENAME TO delta", [])?;
+    Ok(())
+}
+
+//per exporter ingest counters, the totals since the exporter was first seen
+fn migration_3_sender_counters(tx: &Transaction) -> rusqlite::Result<()> {
+    for column in ["packets", "bytes", "records_decoded", "records_skipped", "parse_errors", "template_refreshes"] {
+        add_column_if_missing(tx, "senders", column, "INTEGER DEFAULT 0")?;
+    }
+    add_column_if_missing(tx, "senders", "last_seen", "TEXT")?;
+    add_column_if_missing(tx, "senders", "last_template", "TEXT")?;
+    Ok(())
+}
+
+//the times are stored as text, filtering on strftime() of them can't use an index and reads the whole table
+//the unix time is kept next to the text for every time that is filtered on
+fn migration_4_epoch_columns(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "delta", "updated_epoch", "INTEGER")?;
+    tx.execute("UPDATE delta SET updated_epoch = CAST(strftime('%s', updated_time) AS INTEGER)", [])?;
+    tx.execute("CREATE INDEX IF NOT EXISTS delta_updated_epoch ON delta (updated_epoch)", [])?;
+
+    add_column_if_missing(tx, "flows", "last_seen_epoch", "INTEGER")?;
+    add_column_if_missing(tx, "flows", "end_epoch", "INTEGER")?;
+    tx.execute("UPDATE flows SET
+        last_seen_epoch = CAST(strftime('%s', COALESCE(last_seen, created_time)) AS INTEGER),
+        end_epoch = CAST(strftime('%s', end_time) AS INTEGER)",
+        [],
+        )?;
+    tx.execute("CREATE INDEX IF NOT EXISTS flows_last_seen_epoch ON flows (last_seen_epoch)", [])?;
+    tx.execute("CREATE INDEX IF NOT EXISTS flows_end_epoch ON flows (end_epoch)", [])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_columns_are_filled_in_and_indexed() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        db_conn.execute("INSERT INTO senders (ip) VALUES ('10.0.0.1')", []).unwrap();
+        db_conn.execute("INSERT INTO flows (id, sender_ip) VALUES (1, '10.0.0.1')", []).unwrap();
+        db_conn.execute("INSERT INTO delta (flow_id, updated_time, in_octets, in_pkts) VALUES (1, '2025-01-21T22:01:14Z', 100, 1)", []).unwrap();
+        //a db from before the epoch columns
+        db_conn.pragma_update(None, "user_version", 3).unwrap();
+        migrate_db(&mut db_conn).unwrap();
+
+        let updated_epoch: i64 = db_conn.query_row("SELECT updated_epoch FROM delta", [], |row| row.get(0)).unwrap();
+        assert_eq!(updated_epoch, 1737496874);
+        let plan: String = db_conn.query_row("EXPLAIN QUERY PLAN SELECT id FROM delta WHERE updated_epoch >= 0", [], |row| row.get(3)).unwrap();
+        assert!(plan.contains("delta_updated_epoch"), "{plan}");
+    }
+
+    #[test]
+    fn a_db_from_a_newer_version_is_refused() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        db_conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+
+        match migrate_db(&mut db_conn) {
+            Err(DbSetupError::NewerVersion { found, supported }) => {
+                assert_eq!(found, SCHEMA_VERSION + 1);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            other => panic!("expected NewerVersion, got {other:?}"),
+        }
+        //nothing was touched
+        assert_eq!(get_schema_version(&db_conn).unwrap(), SCHEMA_VERSION + 1);
+        let tables: i64 = db_conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'", [], |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn fixing_the_flow_id_type_keeps_the_delta_rows() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        db_conn.execute("CREATE TABLE senders (ip TEXT PRIMARY KEY)", []).unwrap();
+        db_conn.execute("CREATE TABLE flows (id INTEGER PRIMARY KEY, sender_ip TEXT NOT NULL, created_time TEXT)", []).unwrap();
+        db_conn.execute("INSERT INTO senders (ip) VALUES ('10.0.0.1')", []).unwrap();
+        db_conn.execute("INSERT INTO flows (id, sender_ip) VALUES (7, '10.0.0.1')", []).unwrap();
+        db_conn.execute("CREATE TABLE delta (
+            id INTEGER PRIMARY KEY,
+            flow_id INTERGER NOT NULL,
+            updated_time TEXT,
+            in_octets INTEGER,
+            in_pkts INTEGER,
+            bps INTEGER,
+            pps INTEGER
+            )", []).unwrap();
+        db_conn.execute("INSERT INTO delta (id, flow_id, updated_time, in_octets, in_pkts, bps, pps)
+            VALUES (1, 7, '2025-01-21T22:01:14Z', 100, 1, 800, 1), (2, 7, '2025-01-21T22:01:15Z', 200, 2, 1600, 2)", []).unwrap();
+
+        migrate_db(&mut db_conn).unwrap();
+
+        let flow_id_type: String = db_conn.query_row("SELECT type FROM pragma_table_info('delta') WHERE name = 'flow_id'", [], |row| row.get(0)).unwrap();
+        assert_eq!(flow_id_type, "INTEGER");
+        let mut stmt = db_conn.prepare("SELECT id, flow_id, in_octets, in_pkts, bps, pps FROM delta ORDER BY id").unwrap();
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(i64, i64, i64, i64, i64, i64)>>>()
+            .unwrap();
+        assert_eq!(rows, vec![(1, 7, 100, 1, 800, 1), (2, 7, 200, 2, 1600, 2)]);
+    }
+
+    #[test]
+    fn a_db_from_before_versioning_is_upgraded_with_its_data() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        //the tables as the first releases created them
+        db_conn.execute("CREATE TABLE senders (ip TEXT PRIMARY KEY)", []).unwrap();
+        db_conn.execute("CREATE TABLE flows (
+            id INTEGER PRIMARY KEY,
+            sender_ip TEXT NOT NULL,
+            src_addr TEXT,
+            dst_addr TEXT,
+            protocol INTEGER,
+            src_port INTEGER,
+            dst_port INTEGER,
+            in_octets INTEGER,
+            in_pkts INTEGER,
+            created_time TEXT
+            )", []).unwrap();
+        db_conn.execute("CREATE TABLE delta (
+            id INTEGER PRIMARY KEY,
+            flow_id INTERGER NOT NULL,
+            updated_time TEXT,
+            in_octets INTEGER,
+            in_pkts INTEGER,
+            bps INTEGER,
+            pps INTEGER
+            )", []).unwrap();
+        db_conn.execute("INSERT INTO senders (ip) VALUES ('10.0.0.1')", []).unwrap();
+        db_conn.execute("INSERT INTO flows (id, sender_ip, src_addr, dst_addr, protocol, src_port, dst_port, in_octets, in_pkts, created_time)
+            VALUES (1, '10.0.0.1', '10.0.0.2', '10.0.0.3', 6, 50000, 443, 1500, 10, '2025-01-21T22:01:14Z')", []).unwrap();
+        db_conn.execute("INSERT INTO delta (flow_id, updated_time, in_octets, in_pkts) VALUES (1, '2025-01-21T22:01:14Z', 1500, 10)", []).unwrap();
+        assert_eq!(get_schema_version(&db_conn).unwrap(), 0);
+
+        migrate_db(&mut db_conn).unwrap();
+
+        assert_eq!(get_schema_version(&db_conn).unwrap(), SCHEMA_VERSION);
+        let (in_octets, rev_octets, dedup_primary, last_seen_epoch, end_epoch): (i64, i64, i64, i64, Option<i64>) = db_conn.query_row(
+            "SELECT in_octets, rev_octets, dedup_primary, last_seen_epoch, end_epoch FROM flows WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)),
+        ).unwrap();
+        assert_eq!((in_octets, rev_octets, dedup_primary, last_seen_epoch, end_epoch), (1500, 0, 1, 1737496874, None));
+        let (packets, updated_epoch): (i64, i64) = db_conn.query_row(
+            "SELECT senders.packets, delta.updated_epoch FROM delta JOIN flows ON flows.id = delta.flow_id JOIN senders ON senders.ip = flows.sender_ip",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!((packets, updated_epoch), (0, 1737496874));
+
+        //running it again is a no-op
+        migrate_db(&mut db_conn).unwrap();
+        assert_eq!(get_schema_version(&db_conn).unwrap(), SCHEMA_VERSION);
+    }
+}
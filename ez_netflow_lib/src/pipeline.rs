@@ -0,0 +1,653 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SendError, SyncSender, TrySendError};
+use std::sync::mpsc::{channel, sync_channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rusqlite::Connection;
+use log::{error, info, warn};
+use chrono::prelude::*;
+
+use crate::access::ExporterAccess;
+use crate::dedup::*;
+use crate::events::*;
+use crate::flow_export::FlowExporter;
+use crate::interfaces::*;
+use crate::rollups::start_rollup_thread;
+use crate::senders::SenderCounters;
+use crate::server::Decoder;
+use crate::settings::ServerSettings;
+use crate::replication::Replicator;
+use crate::sockets::KernelDrops;
+use crate::sql::*;
+use crate::templates::*;
+use crate::utils::*;
+
+//the server runs in three stages connected by bounded queues:
+//one receiver per socket reads datagrams, one decoder per shard parses the datagrams of its exporters,
+//and the writer is the only stage that touches the db
+//only the receivers ever drop anything, the decoders wait for the writer so no parsed record is lost
+//unless the db refuses a transaction, then the records in it are rolled back and counted
+
+//shared by all stages, read it at any time through NetflowServer::pipeline_stats
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    received: AtomicU64,
+    //from an exporter that isn't allowed
+    rejected: AtomicU64,
+    //the decoder queue for the exporter was full
+    dropped: AtomicU64,
+    //the decoder for the exporter is gone and couldn't be restarted
+    undeliverable: AtomicU64,
+    //decoders that panicked and were replaced
+    decoder_restarts: AtomicU64,
+    //too short to be netflow
+    malformed: AtomicU64,
+    //bigger than max_datagram_size
+    truncated: AtomicU64,
+    //dropped by the kernel before the receiver got to them, only known on linux
+    kernel_dropped: AtomicU64,
+    //copies sent to the replication targets, and the ones that couldn't be sent
+    replicated: AtomicU64,
+    replication_errors: AtomicU64,
+    decode_queue_depth: AtomicUsize,
+    write_queue_depth: AtomicUsize,
+    written_batches: AtomicU64,
+    //batches the db refused, or that couldn't reach the writer, their records are lost
+    failed_batches: AtomicU64,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PipelineCounters {
+    pub received: u64,
+    pub rejected: u64,
+    pub dropped: u64,
+    pub undeliverable: u64,
+    pub decoder_restarts: u64,
+    pub malformed: u64,
+    pub truncated: u64,
+    pub kernel_dropped: u64,
+    pub replicated: u64,
+    pub replication_errors: u64,
+    pub decode_queue_depth: usize,
+    pub write_queue_depth: usize,
+    pub written_batches: u64,
+    pub failed_batches: u64,
+}
+
+impl PipelineStats {
+    pub fn counters(&self) -> PipelineCounters {
+        PipelineCounters {
+            received: self.received.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            undeliverable: self.undeliverable.load(Ordering::Relaxed),
+            decoder_restarts: self.decoder_restarts.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+            truncated: self.truncated.load(Ordering::Relaxed),
+            kernel_dropped: self.kernel_dropped.load(Ordering::Relaxed),
+            replicated: self.replicated.load(Ordering::Relaxed),
+            replication_errors: self.replication_errors.load(Ordering::Relaxed),
+            decode_queue_depth: self.decode_queue_depth.load(Ordering::Relaxed),
+            write_queue_depth: self.write_queue_depth.load(Ordering::Relaxed),
+            written_batches: self.written_batches.load(Ordering::Relaxed),
+            failed_batches: self.failed_batches.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn datagram_decoded(&self) {
+        self.decode_queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Datagram {
+    pub source: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+//everything a decoder wants written, applied in order by the writer
+pub enum WriteOp {
+    Sender(Ipv4Addr),
+    SenderCounters { sender_ip: Ipv4Addr, counters: SenderCounters, last_seen: DateTime<Local>, last_template: Option<DateTime<Local>> },
+    InterfaceName { sender_ip: Ipv4Addr, snmp_index: u32, name: String, description: String },
+    Flows { sender_ip: Ipv4Addr, flows: Vec<NetFlow>, interface_counters: Vec<(InterfaceBucket, InterfaceCounters)> },
+    CloseFlows { flows: Vec<NetFlow>, end_time: DateTime<Local> },
+}
+
+//everything behind the receiver, shared by the sync and the async server
+pub(crate) struct Pipeline {
+    pub(crate) decoders: Vec<SyncSender<Datagram>>,
+    pub(crate) access: Arc<ExporterAccess>,
+    pub(crate) replicator: Arc<Replicator>,
+    pub(crate) events: Option<FlowEvents>,
+    decoder_threads: Vec<JoinHandle<()>>,
+    writer_thread: JoinHandle<()>,
+    dispatcher_thread: Option<JoinHandle<()>>,
+    rollups_stop: Sender<()>,
+    rollup_thread: JoinHandle<()>,
+    db_conn: Arc<Mutex<Connection>>,
+    settings: ServerSettings,
+}
+
+pub(crate) fn start_pipeline(db_conn: &Arc<Mutex<Connection>>, settings: &ServerSettings, subscribers: Vec<Box<dyn FlowSubscriber>>, stats: &Arc<PipelineStats>) -> Pipeline {
+    let mut db_conn = Arc::clone(db_conn);
+    close_open_flows_in_db(&mut db_conn, &Local::now());
+    update_interface_names_from_config(&db_conn.lock().unwrap(), settings);
+    let access = Arc::new(ExporterAccess::new(settings, &get_sender_ips_from_db(&db_conn.lock().unwrap())));
+    let replicator = Arc::new(Replicator::new(settings));
+
+    //the exporter is fed by the writer itself rather than through the event queue, so it never misses a flow
+    let mut writer_subscribers: Vec<Box<dyn FlowSubscriber>> = Vec::new();
+    if let Some(target) = settings.flow_export_target {
+        match FlowExporter::new(target, settings.flow_export_format, settings.flow_export_active_timeout_sec, settings.inactive_timeout_sec) {
+            Ok(flow_exporter) => {
+                info!("Exporting flows as {:?} to {}", settings.flow_export_format, target);
+                writer_subscribers.push(Box::new(flow_exporter));
+            },
+            Err(e) => error!("Unable to bind the flow export socket, flows are not exported to {}: {e}", target),
+        }
+    }
+
+    let (rollups_stop, rollups_stop_rx) = channel::<()>();
+    let rollup_thread = start_rollup_thread(Arc::clone(&db_conn), settings.clone(), rollups_stop_rx);
+    let (events, dispatcher_thread) = if subscribers.is_empty() {
+        (None, None)
+    }
+    else {
+        let (events, dispatcher_thread) = start_event_dispatcher(subscribers, settings.event_queue_size);
+        (Some(events), Some(dispatcher_thread))
+    };
+
+    let (writes_tx, writes_rx) = sync_channel::<Vec<WriteOp>>(settings.write_queue_size.max(1));
+    let db_conn_writer = Arc::clone(&db_conn);
+    let stats_writer = Arc::clone(stats);
+    let events_writer = events.clone();
+    let writer_thread = thread::spawn(move || {
+        run_writer(db_conn_writer, writes_rx, stats_writer, events_writer, writer_subscribers);
+    });
+
+    //shared by all decoders so a conversation reported by several exporters is only counted once
+    let dedup_table = if settings.dedup_flows { Some(Arc::new(Mutex::new(DedupTable::new()))) } else { None };
+    let mut decoders: Vec<SyncSender<Datagram>> = Vec::new();
+    let mut decoder_threads = Vec::new();
+    for shard in 0..settings.decoder_threads.max(1) {
+        let (datagrams_tx, datagrams_rx) = sync_channel::<Datagram>(settings.decode_queue_size.max(1));
+        let (settings, dedup_table, events, writes_tx, stats) = (settings.clone(), dedup_table.clone(), events.clone(), writes_tx.clone(), Arc::clone(stats));
+        decoder_threads.push(thread::spawn(move || {
+            run_decoder_shard(shard, &datagrams_rx, &stats, || {
+                Decoder::new(settings.clone(), dedup_table.clone(), events.clone(), writes_tx.clone(), Arc::clone(&stats))
+            });
+        }));
+        decoders.push(datagrams_tx);
+    }
+
+    Pipeline {
+        decoders,
+        access,
+        replicator,
+        events,
+        decoder_threads,
+        writer_thread,
+        dispatcher_thread,
+        rollups_stop,
+        rollup_thread,
+        db_conn,
+        settings: settings.clone(),
+    }
+}
+
+impl Pipeline {
+    //each stage finishes what it was given before the next one is stopped, so nothing received is lost
+    //the server has to drop its own FlowEvents first, the dispatcher waits for all of them
+    pub(crate) fn stop(self) {
+        let Pipeline { decoders, access: _, replicator: _, events, decoder_threads, writer_thread, dispatcher_thread, rollups_stop, rollup_thread, mut db_conn, settings } = self;
+        //the decoders write their pending records when their queue is closed
+        drop(decoders);
+        join_stage(decoder_threads, "decoder");
+        //and the writer stops once the last decoder is gone
+        join_stage(vec![writer_thread], "db writer");
+        drop(rollups_stop);
+        join_stage(vec![rollup_thread], "rollup");
+        drop(events);
+        join_stage(dispatcher_thread.into_iter().collect(), "event dispatcher");
+
+        //closed now rather than at the next start, so they get the right end time
+        close_open_flows_in_db(&mut db_conn, &Local::now());
+        let db_conn_unlocked = db_conn.lock().unwrap();
+        if let Err(e) = snapshot_db(&db_conn_unlocked, &settings) {
+            error!("Unable to write the in memory db snapshot: {e}");
+        }
+    }
+}
+
+//a decoder that keeps panicking is given up on after this many restarts in a row
+const MAX_DECODER_RESTARTS: u32 = 5;
+//the wait before the first restart, doubled for every restart in a row
+const DECODER_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+//a decoder that ran this long before panicking starts the count over
+const DECODER_RESTART_RESET: Duration = Duration::from_secs(60);
+
+//a decoder that panics is replaced by a new one on the same queue, so one bad datagram doesn't stop the shard
+//the new decoder starts without the templates and open flows of the shard's exporters
+//once the shard is given up on its queue is closed, and its datagrams are counted as undeliverable
+fn run_decoder_shard(shard: usize, datagrams: &Receiver<Datagram>, stats: &PipelineStats, new_decoder: impl Fn() -> Decoder) {
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let mut decoder = new_decoder();
+        match panic::catch_unwind(AssertUnwindSafe(|| decoder.run(datagrams))) {
+            Ok(()) => return,
+            Err(_) => {
+                if started.elapsed() >= DECODER_RESTART_RESET {
+                    restarts = 0;
+                }
+                if restarts == MAX_DECODER_RESTARTS {
+                    error!("The decoder for shard {shard} panicked {} times in a row and was stopped, its exporters' datagrams are dropped until the server is restarted",
+                        MAX_DECODER_RESTARTS + 1);
+                    return;
+                }
+                let backoff = DECODER_RESTART_BACKOFF * 2u32.pow(restarts);
+                restarts += 1;
+                stats.decoder_restarts.fetch_add(1, Ordering::Relaxed);
+                error!("The decoder for shard {shard} panicked and is restarted in {} ms, its exporters' flows are recorded again once they resend their templates",
+                    backoff.as_millis());
+                thread::sleep(backoff);
+            },
+        }
+    }
+}
+
+fn join_stage(threads: Vec<JoinHandle<()>>, stage: &str) {
+    for thread in threads {
+        if thread.join().is_err() {
+            error!("The {stage} thread panicked");
+        }
+    }
+}
+
+pub fn update_interface_names_from_config(db_conn: &Connection, settings: &ServerSettings) {
+    for ((sender_ip, snmp_index), name) in &settings.interface_names {
+        let ip_as_str = convert_ipv4_to_string(*sender_ip);
+        if let Err(e) = update_interface_name_in_db(db_conn, &ip_as_str, *snmp_index, name, "", "config") {
+            error!("Unable to store the name of interface {} of {}: {e}", snmp_index, ip_as_str);
+        }
+    }
+}
+
+//warns about dropped datagrams and events, at most once a second
+pub(crate) struct DropWarnings {
+    last_dropped_datagrams: u64,
+    last_undeliverable_datagrams: u64,
+    last_rejected_datagrams: u64,
+    last_truncated_datagrams: u64,
+    last_replication_errors: u64,
+    last_kernel_dropped: u64,
+    last_dropped_events: u64,
+    last_check: Instant,
+    kernel_drops: KernelDrops,
+}
+
+impl DropWarnings {
+    pub(crate) fn new(kernel_drops: KernelDrops) -> Self {
+        DropWarnings {
+            last_dropped_datagrams: 0,
+            last_undeliverable_datagrams: 0,
+            last_rejected_datagrams: 0,
+            last_truncated_datagrams: 0,
+            last_replication_errors: 0,
+            last_kernel_dropped: 0,
+            last_dropped_events: 0,
+            last_check: Instant::now(),
+            kernel_drops,
+        }
+    }
+
+    pub(crate) fn check(&mut self, stats: &PipelineStats, events: Option<&FlowEvents>) {
+        if self.last_check.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.last_check = Instant::now();
+        if let Some(kernel_dropped) = self.kernel_drops.read() {
+            stats.kernel_dropped.store(kernel_dropped, Ordering::Relaxed);
+        }
+        let counters = stats.counters();
+        if counters.kernel_dropped > self.last_kernel_dropped {
+            warn!("The socket receive buffer overflowed, the kernel dropped {} datagrams so far, raise socket_receive_buffer_bytes or receive_sockets",
+                counters.kernel_dropped);
+            self.last_kernel_dropped = counters.kernel_dropped;
+        }
+        if counters.rejected > self.last_rejected_datagrams {
+            warn!("{} datagrams so far came from exporters that aren't allowed and were dropped", counters.rejected);
+            self.last_rejected_datagrams = counters.rejected;
+        }
+        if counters.truncated > self.last_truncated_datagrams {
+            warn!("{} datagrams so far were bigger than max_datagram_size and were dropped", counters.truncated);
+            self.last_truncated_datagrams = counters.truncated;
+        }
+        if counters.replication_errors > self.last_replication_errors {
+            warn!("{} datagrams so far couldn't be replicated, {} were", counters.replication_errors, counters.replicated);
+            self.last_replication_errors = counters.replication_errors;
+        }
+        if counters.dropped > self.last_dropped_datagrams {
+            warn!("The decoders are falling behind, {} datagrams dropped so far, {} waiting to be decoded and {} batches waiting to be written",
+                counters.dropped, counters.decode_queue_depth, counters.write_queue_depth);
+            self.last_dropped_datagrams = counters.dropped;
+        }
+        if counters.undeliverable > self.last_undeliverable_datagrams {
+            error!("A decoder thread stopped, {} datagrams so far couldn't be decoded, restart the server", counters.undeliverable);
+            self.last_undeliverable_datagrams = counters.undeliverable;
+        }
+        let dropped_events = events.map_or(0, |e| e.dropped());
+        if dropped_events > self.last_dropped_events {
+            warn!("Flow subscribers are falling behind, {} events dropped so far", dropped_events);
+            self.last_dropped_events = dropped_events;
+        }
+    }
+}
+
+//every datagram from an exporter goes to the same decoder, so its templates and flows are only ever in one place
+pub fn get_decoder_shard(sender_ip: Ipv4Addr, shards: usize) -> usize {
+    u32::from(sender_ip) as usize % shards.max(1)
+}
+
+//never blocks, a full queue means the decoder is behind and the datagram is counted as dropped
+//a closed queue means the decoder is gone, that's counted apart so it isn't mistaken for load
+pub fn dispatch_datagram(receive_buffer: &[u8], byte_count: usize, source: SocketAddr, decoders: &[SyncSender<Datagram>], access: &ExporterAccess, replicator: &Replicator, stats: &PipelineStats) {
+    stats.received.fetch_add(1, Ordering::Relaxed);
+    let sender_ip = convert_socket_to_ipv4(source);
+    if !access.is_allowed(sender_ip) {
+        stats.rejected.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    //the os cuts a datagram down to the buffer without saying so, the buffer is one byte bigger than max_datagram_size
+    //so a full buffer means the datagram was bigger
+    if byte_count >= receive_buffer.len() {
+        stats.truncated.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    //copied as received, the downstream collectors decide for themselves what they can parse
+    if !replicator.is_empty() {
+        let replication = replicator.forward(&receive_buffer[..byte_count], source);
+        stats.replicated.fetch_add(replication.replicated, Ordering::Relaxed);
+        stats.replication_errors.fetch_add(replication.errors, Ordering::Relaxed);
+    }
+    if check_packet_size(byte_count).is_err() {
+        stats.malformed.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let datagram = Datagram { source, bytes: receive_buffer[..byte_count].to_vec() };
+    let shard = get_decoder_shard(sender_ip, decoders.len());
+    //counted before sending so the decoder can never take it below zero
+    stats.decode_queue_depth.fetch_add(1, Ordering::Relaxed);
+    match decoders[shard].try_send(datagram) {
+        Ok(()) => {},
+        Err(TrySendError::Full(_)) => {
+            stats.decode_queue_depth.fetch_sub(1, Ordering::Relaxed);
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+        },
+        Err(TrySendError::Disconnected(_)) => {
+            stats.decode_queue_depth.fetch_sub(1, Ordering::Relaxed);
+            stats.undeliverable.fetch_add(1, Ordering::Relaxed);
+        },
+    }
+}
+
+//blocks while the writer is behind, the batch is handed back if the writer is gone
+pub fn send_write_batch(writes: &SyncSender<Vec<WriteOp>>, batch: Vec<WriteOp>, stats: &PipelineStats) -> Result<(), SendError<Vec<WriteOp>>> {
+    stats.write_queue_depth.fetch_add(1, Ordering::Relaxed);
+    writes.send(batch).inspect_err(|_| {
+        stats.write_queue_depth.fetch_sub(1, Ordering::Relaxed);
+        stats.failed_batches.fetch_add(1, Ordering::Relaxed);
+    })
+}
+
+//what a transaction changed outside the db, only kept once it commits
+#[derive(Default)]
+struct WriteEffects {
+    //the row id every touched flow had before, put back if the transaction is rolled back
+    previous_ids: Vec<(FlowKey, Option<i64>)>,
+    events: Vec<FlowEvent>,
+}
+
+//whatever is waiting when the writer gets to it goes into one transaction
+//a transaction the db refuses is rolled back and counted, the writer carries on with the next one
+//the subscribers passed here get every flow event of a committed transaction, on the writer thread, so they have to be quick
+pub fn run_writer(db_conn: Arc<Mutex<Connection>>, writes: Receiver<Vec<WriteOp>>, stats: Arc<PipelineStats>, events: Option<FlowEvents>, mut subscribers: Vec<Box<dyn FlowSubscriber>>) {
+    //the flow rows that are still open, so a flow is updated and closed by id
+    let mut open_flows: HashMap<FlowKey, i64> = HashMap::new();
+    while let Ok(batch) = writes.recv() {
+        let mut batches = vec![batch];
+        while let Ok(batch) = writes.try_recv() {
+            batches.push(batch);
+        }
+        let batch_count = batches.len();
+
+        let mut effects = WriteEffects::default();
+        let with_events = events.is_some() || !subscribers.is_empty();
+        let result = write_batches(&mut db_conn.lock().unwrap(), batches, &mut open_flows, &mut effects, with_events);
+        stats.write_queue_depth.fetch_sub(batch_count, Ordering::Relaxed);
+        match result {
+            Ok(()) => {
+                stats.written_batches.fetch_add(batch_count as u64, Ordering::Relaxed);
+                for event in effects.events {
+                    for subscriber in subscribers.iter_mut() {
+                        event.deliver(subscriber.as_mut());
+                    }
+                    if let Some(events) = &events {
+                        events.send(event);
+                    }
+                }
+            },
+            Err(e) => {
+                for (key, flow_id) in effects.previous_ids.into_iter().rev() {
+                    match flow_id {
+                        Some(flow_id) => open_flows.insert(key, flow_id),
+                        None => open_flows.remove(&key),
+                    };
+                }
+                stats.failed_batches.fetch_add(batch_count as u64, Ordering::Relaxed);
+                error!("Unable to write {} batches to the db, their records were rolled back and are lost: {e}", batch_count);
+            },
+        }
+    }
+}
+
+//the transaction is rolled back when it's dropped without being committed
+fn write_batches(db_conn: &mut Connection, batches: Vec<Vec<WriteOp>>, open_flows: &mut HashMap<FlowKey, i64>, effects: &mut WriteEffects, with_events: bool) -> rusqlite::Result<()> {
+    let tx = db_conn.transaction()?;
+    for op in batches.into_iter().flatten() {
+        apply_write_op(&tx, op, open_flows, effects, with_events)?;
+    }
+    tx.commit()
+}
+
+fn apply_write_op(db_conn: &Connection, op: WriteOp, open_flows: &mut HashMap<FlowKey, i64>, effects: &mut WriteEffects, with_events: bool) -> rusqlite::Result<()> {
+    match op {
+        WriteOp::Sender(sender_ip) => {
+            update_senders_in_db(db_conn, &convert_ipv4_to_string(sender_ip))?;
+        },
+        WriteOp::SenderCounters { sender_ip, counters, last_seen, last_template } => {
+            update_sender_counters_in_db(db_conn, &convert_ipv4_to_string(sender_ip), &counters, &last_seen, last_template.as_ref())?;
+        },
+        WriteOp::InterfaceName { sender_ip, snmp_index, name, description } => {
+            update_interface_name_in_db(db_conn, &convert_ipv4_to_string(sender_ip), snmp_index, &name, &description, "options")?;
+        },
+        WriteOp::Flows { sender_ip, flows, interface_counters } => {
+            update_interface_counters_in_db(db_conn, &sender_ip.to_string(), interface_counters.into_iter())?;
+            let current_time = Local::now();
+            for mut flow in flows {
+                let key = flow.key();
+                //flows left open by a previous run are closed at startup, so a flow without a row id is new
+                flow.db_id = open_flows.get(&key).copied();
+                let is_new = flow.db_id.is_none();
+                let flow_id = upsert_flow_in_db(db_conn, &flow, &current_time)?;
+                effects.previous_ids.push((key, open_flows.insert(key, flow_id)));
+                flow.db_id = Some(flow_id);
+                if with_events {
+                    effects.events.push(if is_new { FlowEvent::FlowCreated(flow) } else { FlowEvent::FlowUpdated(flow) });
+                }
+            }
+        },
+        WriteOp::CloseFlows { flows, end_time } => {
+            for mut flow in flows {
+                let key = flow.key();
+                flow.db_id = open_flows.remove(&key);
+                effects.previous_ids.push((key, flow.db_id));
+                close_flow_in_db(db_conn, &flow, &end_time)?;
+                if with_events {
+                    effects.events.push(FlowEvent::FlowExpired(flow));
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate_db;
+    use crate::settings::parse_config_string;
+    use crate::sockets::get_receive_buffer_size;
+
+    fn sender_ips(db_conn: &Mutex<Connection>) -> Vec<Ipv4Addr> {
+        get_sender_ips_from_db(&db_conn.lock().unwrap())
+    }
+
+    //the batch a decoder sends for a template and one record of 10.0.0.1:50000 -> 10.0.0.2:443
+    fn decoded_flow_batch() -> Vec<WriteOp> {
+        let mut datagram = vec![0, 9, 0, 2, 0, 0, 0x27, 0x10, 0x65, 0x00, 0x00, 0x00, 0, 0, 0, 1, 0, 0, 0, 0];
+        datagram.extend_from_slice(&[0, 0, 0, 36, 1, 0, 0, 7, 0, 8, 0, 4, 0, 12, 0, 4, 0, 4, 0, 1, 0, 7, 0, 2, 0, 11, 0, 2, 0, 1, 0, 4, 0, 2, 0, 4]);
+        datagram.extend_from_slice(&[1, 0, 0, 28, 10, 0, 0, 1, 10, 0, 0, 2, 6, 0xc3, 0x50, 0x01, 0xbb, 0, 0, 0x05, 0xdc, 0, 0, 0, 10, 0, 0, 0]);
+        let (writes_tx, writes_rx) = sync_channel(1);
+        let mut decoder = Decoder::new(parse_config_string(String::new()), None, None, writes_tx, Arc::new(PipelineStats::default()));
+        decoder.decode_datagram(Datagram { source: "192.168.0.1:2055".parse().unwrap(), bytes: datagram });
+        decoder.flush_to_db();
+        writes_rx.try_recv().unwrap()
+    }
+
+    struct CreatedFlows(Arc<Mutex<Vec<FlowKey>>>);
+
+    impl FlowSubscriber for CreatedFlows {
+        fn on_flow_created(&mut self, flow: &NetFlow) {
+            self.0.lock().unwrap().push(flow.key());
+        }
+    }
+
+    #[test]
+    fn datagram_for_a_stopped_decoder_is_not_counted_as_dropped() {
+        let settings = parse_config_string(String::new());
+        let (access, replicator, stats) = (ExporterAccess::new(&settings, &[]), Replicator::new(&settings), PipelineStats::default());
+        let (datagrams_tx, datagrams_rx) = sync_channel::<Datagram>(1);
+        drop(datagrams_rx);
+        let receive_buffer = [0u8; 64];
+        dispatch_datagram(&receive_buffer, 24, "127.0.0.1:2055".parse().unwrap(), &[datagrams_tx], &access, &replicator, &stats);
+        let counters = stats.counters();
+        assert_eq!(counters.dropped, 0);
+        assert_eq!(counters.undeliverable, 1);
+        assert_eq!(counters.decode_queue_depth, 0);
+    }
+
+    #[test]
+    fn datagram_of_max_datagram_size_is_not_truncated() {
+        let settings = ServerSettings { max_datagram_size: 64, ..parse_config_string(String::new()) };
+        let (access, replicator, stats) = (ExporterAccess::new(&settings, &[]), Replicator::new(&settings), PipelineStats::default());
+        let (datagrams_tx, _datagrams_rx) = sync_channel::<Datagram>(4);
+        let decoders = [datagrams_tx];
+        let receive_buffer = vec![0u8; get_receive_buffer_size(&settings)];
+        let source = "127.0.0.1:2055".parse().unwrap();
+        dispatch_datagram(&receive_buffer, 64, source, &decoders, &access, &replicator, &stats);
+        assert_eq!(stats.counters().truncated, 0);
+        dispatch_datagram(&receive_buffer, 65, source, &decoders, &access, &replicator, &stats);
+        assert_eq!(stats.counters().truncated, 1);
+    }
+
+    #[test]
+    fn failed_batch_is_rolled_back_and_the_writer_carries_on() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        //every interface name written from now on fails
+        db_conn.execute("DROP TABLE interfaces", []).unwrap();
+        let db_conn = Arc::new(Mutex::new(db_conn));
+        let stats = Arc::new(PipelineStats::default());
+        let (writes_tx, writes_rx) = sync_channel::<Vec<WriteOp>>(4);
+        let writer = {
+            let (db_conn, stats) = (Arc::clone(&db_conn), Arc::clone(&stats));
+            thread::spawn(move || run_writer(db_conn, writes_rx, stats, None, Vec::new()))
+        };
+
+        let (first, second) = (Ipv4Addr::new(192, 168, 0, 1), Ipv4Addr::new(192, 168, 0, 2));
+        let failing = vec![
+            WriteOp::Sender(first),
+            WriteOp::InterfaceName { sender_ip: first, snmp_index: 1, name: "eth0".to_string(), description: String::new() },
+        ];
+        send_write_batch(&writes_tx, failing, &stats).unwrap();
+        let started = Instant::now();
+        while stats.counters().failed_batches == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(sender_ips(&db_conn).is_empty());
+
+        send_write_batch(&writes_tx, vec![WriteOp::Sender(second)], &stats).unwrap();
+        drop(writes_tx);
+        writer.join().unwrap();
+
+        let counters = stats.counters();
+        assert_eq!((counters.written_batches, counters.failed_batches, counters.write_queue_depth), (1, 1, 0));
+        assert_eq!(sender_ips(&db_conn), vec![second]);
+    }
+
+    #[test]
+    fn batch_for_a_stopped_writer_is_handed_back() {
+        let stats = PipelineStats::default();
+        let (writes_tx, writes_rx) = sync_channel::<Vec<WriteOp>>(1);
+        drop(writes_rx);
+        let batch = send_write_batch(&writes_tx, vec![WriteOp::Sender(Ipv4Addr::new(192, 168, 0, 1))], &stats).unwrap_err().0;
+        assert_eq!(batch.len(), 1);
+        let counters = stats.counters();
+        assert_eq!((counters.failed_batches, counters.write_queue_depth), (1, 0));
+    }
+
+    #[test]
+    fn writer_subscribers_only_get_the_flows_that_were_committed() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        db_conn.execute("DROP TABLE interfaces", []).unwrap();
+        let db_conn = Arc::new(Mutex::new(db_conn));
+        let stats = Arc::new(PipelineStats::default());
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let (writes_tx, writes_rx) = sync_channel::<Vec<WriteOp>>(4);
+        let writer = {
+            let (db_conn, stats, subscriber) = (Arc::clone(&db_conn), Arc::clone(&stats), CreatedFlows(Arc::clone(&created)));
+            thread::spawn(move || run_writer(db_conn, writes_rx, stats, None, vec![Box::new(subscriber)]))
+        };
+
+        let sender_ip = Ipv4Addr::new(192, 168, 0, 1);
+        let mut failing = decoded_flow_batch();
+        failing.push(WriteOp::InterfaceName { sender_ip, snmp_index: 1, name: "eth0".to_string(), description: String::new() });
+        send_write_batch(&writes_tx, failing, &stats).unwrap();
+        let started = Instant::now();
+        while stats.counters().failed_batches == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(created.lock().unwrap().is_empty());
+
+        //the rolled back flow is new again when its records are written the next time
+        let batch = decoded_flow_batch();
+        send_write_batch(&writes_tx, batch, &stats).unwrap();
+        drop(writes_tx);
+        writer.join().unwrap();
+
+        let created = created.lock().unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].sender_ip, sender_ip);
+        let flow_count: i64 = db_conn.lock().unwrap().query_row("SELECT COUNT(*) FROM flows", [], |row| row.get(0)).unwrap();
+        assert_eq!(flow_count, 1);
+    }
+}
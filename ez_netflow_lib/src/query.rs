@@ -0,0 +1,796 @@
+use std::net::Ipv4Addr;
+
+use rusqlite::{Connection, Row, params_from_iter};
+use rusqlite::types::Value;
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::fields::ConnState;
+use crate::interfaces::*;
+use crate::senders::SenderStats;
+use crate::settings::*;
+use crate::templates::*;
+use crate::utils::*;
+
+//typed reads of the db for library users, the cli tables and the json output are built on these
+//every query takes a locked connection, so several can be run under one lock
+//the db errors are returned, a locked or corrupt db shouldn't take the caller down
+
+
+//all the set conditions have to match
+#[derive(Clone, Debug, Default)]
+pub struct FlowFilter {
+    pub sender_ip: Option<Ipv4Addr>,
+    pub src_addr: Option<Ipv4Addr>,
+    pub dst_addr: Option<Ipv4Addr>,
+    //either end of the flow
+    pub host: Option<Ipv4Addr>,
+    pub protocol: Option<u8>,
+    //either port
+    pub port: Option<u16>,
+    //empty matches every state
+    pub conn_states: Vec<ConnState>,
+    pub unicast_only: bool,
+    //leaves out the copies from the exporters that don't own the conversation, see dedup_flows
+    pub primary_only: bool,
+    pub open_only: bool,
+    //flows that saw traffic at or after this time
+    pub since: Option<DateTime<Local>>,
+}
+
+impl FlowFilter {
+    //the same flows the cli shows
+    pub fn from_settings(server_settings: &ServerSettings) -> Self {
+        FlowFilter {
+            conn_states: server_settings.conn_states.clone(),
+            unicast_only: server_settings.unicast_only,
+            primary_only: server_settings.dedup_flows,
+            ..Default::default()
+        }
+    }
+
+    //the values are bound in order to the ? in the conditions, only fixed strings go into the statement
+    fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(sender_ip) = self.sender_ip {
+            conditions.push("sender_ip = ?".to_string());
+            values.push(Value::Text(sender_ip.to_string()));
+        }
+        if let Some(src_addr) = self.src_addr {
+            conditions.push("src_addr = ?".to_string());
+            values.push(Value::Text(src_addr.to_string()));
+        }
+        if let Some(dst_addr) = self.dst_addr {
+            conditions.push("dst_addr = ?".to_string());
+            values.push(Value::Text(dst_addr.to_string()));
+        }
+        if let Some(host) = self.host {
+            conditions.push("(src_addr = ? OR dst_addr = ?)".to_string());
+            values.push(Value::Text(host.to_string()));
+            values.push(Value::Text(host.to_string()));
+        }
+        if let Some(protocol) = self.protocol {
+            conditions.push("protocol = ?".to_string());
+            values.push(Value::Integer(protocol.into()));
+        }
+        if let Some(port) = self.port {
+            conditions.push("(src_port = ? OR dst_port = ?)".to_string());
+            values.push(Value::Integer(port.into()));
+            values.push(Value::Integer(port.into()));
+        }
+        if let Some(since) = self.since {
+            conditions.push("last_seen_epoch >= ?".to_string());
+            values.push(Value::Integer(since.timestamp()));
+        }
+        if !self.conn_states.is_empty() {
+            let states: Vec<String> = self.conn_states.iter()
+                .map(|state| format!("\'{}\'", state.as_str()))
+                .collect();
+            conditions.push(format!("conn_state IN ({})", states.join(", ")));
+        }
+        if self.unicast_only {
+            //dbs from before the finer traffic types have Unicast rows, so filter out the non-unicast types instead
+            conditions.push("traffic_type NOT IN (\'Broadcast\', \'DirectedBroadcast\', \'Multicast\')".to_string());
+        }
+        if self.primary_only {
+            conditions.push("dedup_primary = 1".to_string());
+        }
+        if self.open_only {
+            conditions.push("end_time IS NULL".to_string());
+        }
+        if conditions.is_empty() {
+            (String::new(), values)
+        }
+        else {
+            (format!("WHERE {} ", conditions.join(" AND ")), values)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FlowSort {
+    //both directions, biggest first
+    #[default]
+    Bytes,
+    Packets,
+    //most recently created first
+    Newest,
+    //in the order they were written
+    None,
+}
+
+impl FlowSort {
+    //ties are broken by id so every page of a paged read sees the same order
+    fn to_sql(self) -> &'static str {
+        match self {
+            FlowSort::Bytes => "ORDER BY (in_octets + rev_octets) DESC, id ",
+            FlowSort::Packets => "ORDER BY (in_pkts + rev_pkts) DESC, id ",
+            FlowSort::Newest => "ORDER BY id DESC ",
+            FlowSort::None => "ORDER BY id ",
+        }
+    }
+}
+
+impl From<&SortBy> for FlowSort {
+    fn from(sort_by: &SortBy) -> Self {
+        match sort_by {
+            SortBy::Bytes => FlowSort::Bytes,
+            SortBy::Pkts => FlowSort::Packets,
+            SortBy::None => FlowSort::None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FlowQuery {
+    pub filter: FlowFilter,
+    pub sort: FlowSort,
+    //None returns every match
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl FlowQuery {
+    //the flows the cli table shows, flows_to_display: all is capped at 1000 like before
+    pub fn from_settings(server_settings: &ServerSettings) -> Self {
+        let limit = match server_settings.flow_limit {
+            FlowsToShow::Limit { flows } => flows,
+            FlowsToShow::NoLimit => 1000,
+        };
+        FlowQuery {
+            filter: FlowFilter::from_settings(server_settings),
+            sort: FlowSort::from(&server_settings.sort_by),
+            limit: Some(limit as usize),
+            offset: 0,
+        }
+    }
+}
+
+//sqlite takes -1 as no limit
+fn limit_to_sql(limit: Option<usize>, offset: usize) -> String {
+    let limit = limit.map_or(-1, |l| l as i64);
+    format!("LIMIT {limit} OFFSET {offset}")
+}
+
+//the times are stored as rfc3339
+fn parse_db_time(time: Option<String>) -> Option<DateTime<Local>> {
+    time.and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+        .map(|t| t.with_timezone(&Local))
+}
+
+
+//a row of the flows table, fwd is the side that started the conversation
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowRow {
+    pub id: i64,
+    pub sender_ip: String,
+    pub src_addr: String,
+    pub dst_addr: String,
+    pub protocol: i64,
+    pub src_port: i64,
+    pub dst_port: i64,
+    //the icmp type and code are exported in the ports
+    pub icmp_type: String,
+    pub fwd_octets: i64,
+    pub fwd_pkts: i64,
+    pub rev_octets: i64,
+    pub rev_pkts: i64,
+    pub avg_bps: i64,
+    pub avg_pps: i64,
+    pub peak_bps: i64,
+    pub peak_pps: i64,
+    //both directions
+    pub tcp_flags: Option<u8>,
+    pub conn_state: Option<String>,
+    pub traffic_type: String,
+    pub input_snmp: Option<i64>,
+    pub output_snmp: Option<i64>,
+    pub dedup_primary: bool,
+    pub first_seen: Option<DateTime<Local>>,
+    pub last_seen: Option<DateTime<Local>>,
+    pub rev_first_seen: Option<DateTime<Local>>,
+    pub rev_last_seen: Option<DateTime<Local>>,
+    pub created_time: Option<DateTime<Local>>,
+    //None while the flow is open
+    pub end_time: Option<DateTime<Local>>,
+}
+
+const FLOW_COLUMNS: &str = "id, sender_ip, src_addr, dst_addr, protocol, src_port, dst_port, in_octets, in_pkts, rev_octets, rev_pkts,
+    avg_bps, avg_pps, peak_bps, peak_pps, tcp_flags, conn_state, traffic_type, input_snmp, output_snmp, dedup_primary,
+    first_seen, last_seen, rev_first_seen, rev_last_seen, created_time, end_time";
+
+impl FlowRow {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let protocol: i64 = row.get("protocol")?;
+        let src_port: i64 = row.get("src_port")?;
+        let dst_port: i64 = row.get("dst_port")?;
+        let (icmp_type, _, _) = handle_icmp_code(protocol as i32, src_port as i32, dst_port as i32);
+        Ok(FlowRow {
+            id: row.get("id")?,
+            sender_ip: row.get("sender_ip")?,
+            src_addr: row.get("src_addr")?,
+            dst_addr: row.get("dst_addr")?,
+            protocol,
+            src_port,
+            dst_port,
+            icmp_type,
+            fwd_octets: row.get("in_octets")?,
+            fwd_pkts: row.get("in_pkts")?,
+            rev_octets: row.get("rev_octets")?,
+            rev_pkts: row.get("rev_pkts")?,
+            avg_bps: row.get("avg_bps")?,
+            avg_pps: row.get("avg_pps")?,
+            peak_bps: row.get("peak_bps")?,
+            peak_pps: row.get("peak_pps")?,
+            tcp_flags: row.get("tcp_flags")?,
+            conn_state: row.get("conn_state")?,
+            traffic_type: row.get("traffic_type")?,
+            input_snmp: row.get("input_snmp")?,
+            output_snmp: row.get("output_snmp")?,
+            dedup_primary: row.get::<_, Option<bool>>("dedup_primary")?.unwrap_or(true),
+            first_seen: parse_db_time(row.get("first_seen")?),
+            last_seen: parse_db_time(row.get("last_seen")?),
+            rev_first_seen: parse_db_time(row.get("rev_first_seen")?),
+            rev_last_seen: parse_db_time(row.get("rev_last_seen")?),
+            created_time: parse_db_time(row.get("created_time")?),
+            end_time: parse_db_time(row.get("end_time")?),
+        })
+    }
+}
+
+//hands the rows over one at a time, so a big result never has to fit in memory
+pub fn for_each_flow(conn: &Connection, query: &FlowQuery, mut f: impl FnMut(FlowRow)) -> rusqlite::Result<()> {
+    let (filter_statement, values) = query.filter.to_sql();
+    let statement = format!("SELECT {FLOW_COLUMNS} FROM flows {filter_statement}{}{}",
+        query.sort.to_sql(), limit_to_sql(query.limit, query.offset));
+    let mut stmt = conn.prepare(&statement)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+    while let Some(row) = rows.next()? {
+        f(FlowRow::from_row(row)?);
+    }
+    Ok(())
+}
+
+pub fn query_flows(conn: &Connection, query: &FlowQuery) -> rusqlite::Result<Vec<FlowRow>> {
+    let mut flows = Vec::new();
+    for_each_flow(conn, query, |flow| flows.push(flow))?;
+    Ok(flows)
+}
+
+impl From<&FlowRow> for NetFlowJson {
+    fn from(flow: &FlowRow) -> Self {
+        NetFlowJson {
+            src_ip: flow.src_addr.parse().unwrap_or(Ipv4Addr::UNSPECIFIED),
+            dst_ip: flow.dst_addr.parse().unwrap_or(Ipv4Addr::UNSPECIFIED),
+            src_port: u16::try_from(flow.src_port).unwrap_or_default(),
+            dst_port: u16::try_from(flow.dst_port).unwrap_or_default(),
+            protocol: u8::try_from(flow.protocol).unwrap_or_default(),
+            in_octets: flow.fwd_octets,
+            in_packets: flow.fwd_pkts,
+            traffic_type: flow.traffic_type.clone(),
+            icmp: flow.icmp_type.clone(),
+            created_time: flow.created_time.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_default(),
+        }
+    }
+}
+
+
+//the record counts of a flow, newest first
+#[derive(Clone, Debug, Default)]
+pub struct DeltaQuery {
+    pub flow_id: Option<i64>,
+    pub since: Option<DateTime<Local>>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeltaRow {
+    pub id: i64,
+    pub flow_id: i64,
+    pub updated_time: Option<DateTime<Local>>,
+    pub in_octets: i64,
+    pub in_pkts: i64,
+    pub bps: i64,
+    pub pps: i64,
+}
+
+pub fn for_each_delta(conn: &Connection, query: &DeltaQuery, mut f: impl FnMut(DeltaRow)) -> rusqlite::Result<()> {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut values: Vec<Value> = Vec::new();
+    if let Some(flow_id) = query.flow_id {
+        conditions.push("flow_id = ?");
+        values.push(Value::Integer(flow_id));
+    }
+    if let Some(since) = query.since {
+        conditions.push("updated_epoch >= ?");
+        values.push(Value::Integer(since.timestamp()));
+    }
+    let filter_statement = if conditions.is_empty() { String::new() } else { format!("WHERE {} ", conditions.join(" AND ")) };
+    let statement = format!("SELECT id, flow_id, updated_time, in_octets, in_pkts, bps, pps FROM delta {filter_statement}ORDER BY id DESC {}",
+        limit_to_sql(query.limit, query.offset));
+    let mut stmt = conn.prepare(&statement)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+    while let Some(row) = rows.next()? {
+        f(DeltaRow {
+            id: row.get(0)?,
+            flow_id: row.get(1)?,
+            updated_time: parse_db_time(row.get(2)?),
+            in_octets: row.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+            in_pkts: row.get::<_, Option<i64>>(4)?.unwrap_or_default(),
+            bps: row.get::<_, Option<i64>>(5)?.unwrap_or_default(),
+            pps: row.get::<_, Option<i64>>(6)?.unwrap_or_default(),
+        });
+    }
+    Ok(())
+}
+
+pub fn query_deltas(conn: &Connection, query: &DeltaQuery) -> rusqlite::Result<Vec<DeltaRow>> {
+    let mut deltas = Vec::new();
+    for_each_delta(conn, query, |delta| deltas.push(delta))?;
+    Ok(deltas)
+}
+
+
+//an exporter is silent once it has been quiet for longer than its expected interval
+pub fn for_each_sender(conn: &Connection, server_settings: &ServerSettings, current_time: &DateTime<Local>, mut f: impl FnMut(SenderStats)) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT ip, packets, bytes, records_decoded, records_skipped, parse_errors, template_refreshes, last_seen, last_template
+            FROM senders
+            ORDER BY ip",
+        )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let sender_ip: String = row.get(0)?;
+        let last_seen = parse_db_time(row.get(7)?);
+        let expected_interval_sec = match sender_ip.parse() {
+            Ok(ip) => server_settings.get_expected_interval_sec(ip),
+            Err(_) => server_settings.exporter_expected_interval_sec,
+        };
+        let silent = match last_seen {
+            Some(last_seen) => expected_interval_sec > 0 && (*current_time - last_seen).num_seconds() > expected_interval_sec,
+            None => false,
+        };
+        f(SenderStats {
+            sender_ip,
+            packets: row.get(1)?,
+            bytes: row.get(2)?,
+            records_decoded: row.get(3)?,
+            records_skipped: row.get(4)?,
+            parse_errors: row.get(5)?,
+            template_refreshes: row.get(6)?,
+            last_seen,
+            last_template: parse_db_time(row.get(8)?),
+            silent,
+        });
+    }
+    Ok(())
+}
+
+pub fn query_senders(conn: &Connection, server_settings: &ServerSettings, current_time: &DateTime<Local>) -> rusqlite::Result<Vec<SenderStats>> {
+    let mut senders = Vec::new();
+    for_each_sender(conn, server_settings, current_time, |sender| senders.push(sender))?;
+    Ok(senders)
+}
+
+
+//the interface counters in 1 minute buckets, newest first
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceQuery {
+    pub sender_ip: Option<Ipv4Addr>,
+    pub snmp_index: Option<u32>,
+    //buckets that start at or after since and before until
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InterfaceRow {
+    pub sender_ip: String,
+    pub snmp_index: u32,
+    //from the config or the exporter's options data, empty when neither named it
+    pub name: String,
+    //unix seconds
+    pub bucket_start: i64,
+    pub in_octets: i64,
+    pub in_pkts: i64,
+    pub out_octets: i64,
+    pub out_pkts: i64,
+    //averaged over the bucket
+    pub in_bps: i64,
+    pub out_bps: i64,
+}
+
+pub fn for_each_interface_bucket(conn: &Connection, query: &InterfaceQuery, mut f: impl FnMut(InterfaceRow)) -> rusqlite::Result<()> {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut values: Vec<Value> = Vec::new();
+    if let Some(sender_ip) = query.sender_ip {
+        conditions.push("s.sender_ip = ?");
+        values.push(Value::Text(sender_ip.to_string()));
+    }
+    if let Some(snmp_index) = query.snmp_index {
+        conditions.push("s.snmp_index = ?");
+        values.push(Value::Integer(snmp_index.into()));
+    }
+    if let Some(since) = query.since {
+        conditions.push("s.bucket_start >= ?");
+        values.push(Value::Integer(since.timestamp()));
+    }
+    if let Some(until) = query.until {
+        conditions.push("s.bucket_start < ?");
+        values.push(Value::Integer(until.timestamp()));
+    }
+    let filter_statement = if conditions.is_empty() { String::new() } else { format!("WHERE {} ", conditions.join(" AND ")) };
+    let statement = format!(
+        "SELECT s.sender_ip, s.snmp_index, COALESCE(i.name, ''), s.bucket_start, s.in_octets, s.in_pkts, s.out_octets, s.out_pkts
+            FROM interface_stats s
+            LEFT JOIN interfaces i ON i.sender_ip = s.sender_ip AND i.snmp_index = s.snmp_index
+            {filter_statement}ORDER BY s.bucket_start DESC, s.sender_ip, s.snmp_index {}",
+        limit_to_sql(query.limit, query.offset));
+    let mut stmt = conn.prepare(&statement)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+    while let Some(row) = rows.next()? {
+        let in_octets: i64 = row.get::<_, Option<i64>>(4)?.unwrap_or_default();
+        let out_octets: i64 = row.get::<_, Option<i64>>(6)?.unwrap_or_default();
+        f(InterfaceRow {
+            sender_ip: row.get(0)?,
+            snmp_index: row.get(1)?,
+            name: row.get(2)?,
+            bucket_start: row.get(3)?,
+            in_octets,
+            in_pkts: row.get::<_, Option<i64>>(5)?.unwrap_or_default(),
+            out_octets,
+            out_pkts: row.get::<_, Option<i64>>(7)?.unwrap_or_default(),
+            in_bps: in_octets * 8 / INTERFACE_BUCKET_SEC,
+            out_bps: out_octets * 8 / INTERFACE_BUCKET_SEC,
+        });
+    }
+    Ok(())
+}
+
+pub fn query_interface_buckets(conn: &Connection, query: &InterfaceQuery) -> rusqlite::Result<Vec<InterfaceRow>> {
+    let mut buckets = Vec::new();
+    for_each_interface_bucket(conn, query, |bucket| buckets.push(bucket))?;
+    Ok(buckets)
+}
+
+//totals and average rate per interface over the last few minutes, busiest first
+pub fn query_interface_stats(conn: &Connection, current_time: &DateTime<Local>) -> rusqlite::Result<Vec<InterfaceStats>> {
+    let query = InterfaceQuery {
+        since: Some(*current_time - chrono::Duration::seconds(INTERFACE_RATE_WINDOW_SEC)),
+        ..Default::default()
+    };
+    let buckets = query_interface_buckets(conn, &query)?;
+
+    let mut all_stats: Vec<InterfaceStats> = Vec::new();
+    for bucket in buckets {
+        let index = match all_stats.iter().position(|s| s.sender_ip == bucket.sender_ip && s.snmp_index == bucket.snmp_index) {
+            Some(index) => index,
+            None => {
+                all_stats.push(InterfaceStats {
+                    sender_ip: bucket.sender_ip,
+                    snmp_index: bucket.snmp_index,
+                    name: bucket.name,
+                    in_octets: 0,
+                    in_pkts: 0,
+                    out_octets: 0,
+                    out_pkts: 0,
+                    in_bps: 0,
+                    out_bps: 0,
+                });
+                all_stats.len() - 1
+            },
+        };
+        let s = &mut all_stats[index];
+        s.in_octets += bucket.in_octets;
+        s.in_pkts += bucket.in_pkts;
+        s.out_octets += bucket.out_octets;
+        s.out_pkts += bucket.out_pkts;
+    }
+    for s in &mut all_stats {
+        s.in_bps = s.in_octets * 8 / INTERFACE_RATE_WINDOW_SEC;
+        s.out_bps = s.out_octets * 8 / INTERFACE_RATE_WINDOW_SEC;
+    }
+    all_stats.sort_by(|a, b| (b.in_octets + b.out_octets).cmp(&(a.in_octets + a.out_octets))
+        .then_with(|| (&a.sender_ip, a.snmp_index).cmp(&(&b.sender_ip, b.snmp_index))));
+    Ok(all_stats)
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GroupBy {
+    Sender,
+    SrcAddr,
+    DstAddr,
+    Protocol,
+    //the flows are oriented so the destination port is the service
+    DstPort,
+    TrafficType,
+    ConnState,
+    //either end of the flow, so a flow counts for both of its hosts
+    Host,
+}
+
+impl GroupBy {
+    fn column(&self) -> &'static str {
+        match self {
+            GroupBy::Sender => "sender_ip",
+            GroupBy::SrcAddr => "src_addr",
+            GroupBy::DstAddr => "dst_addr",
+            GroupBy::Protocol => "protocol",
+            GroupBy::DstPort => "dst_port",
+            GroupBy::TrafficType => "traffic_type",
+            GroupBy::ConnState => "conn_state",
+            GroupBy::Host => "host",
+        }
+    }
+}
+
+//totals of the matching flows for each value of group_by, sorted by bytes or packets
+#[derive(Clone, Debug)]
+pub struct AggregateQuery {
+    pub group_by: GroupBy,
+    pub filter: FlowFilter,
+    //FlowSort::Newest sorts by the number of flows
+    pub sort: FlowSort,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AggregateRow {
+    //the value of the group_by column as text
+    pub key: String,
+    pub flows: i64,
+    pub octets: i64,
+    pub pkts: i64,
+}
+
+pub fn for_each_aggregate(conn: &Connection, query: &AggregateQuery, mut f: impl FnMut(AggregateRow)) -> rusqlite::Result<()> {
+    let (filter_statement, mut values) = query.filter.to_sql();
+    let column = query.group_by.column();
+    let source = if query.group_by == GroupBy::Host {
+        values.extend(values.clone());
+        format!("(SELECT src_addr AS host, in_octets, rev_octets, in_pkts, rev_pkts FROM flows {filter_statement}
+            UNION ALL SELECT dst_addr AS host, in_octets, rev_octets, in_pkts, rev_pkts FROM flows {filter_statement}) ")
+    }
+    else {
+        format!("flows {filter_statement}")
+    };
+    //ties are broken by the key, like for the flows
+    let order_statement = match query.sort {
+        FlowSort::Bytes => format!("ORDER BY octets DESC, {column} "),
+        FlowSort::Packets => format!("ORDER BY pkts DESC, {column} "),
+        FlowSort::Newest => format!("ORDER BY flows DESC, {column} "),
+        FlowSort::None => format!("ORDER BY {column} "),
+    };
+    let statement = format!(
+        "SELECT CAST(COALESCE({column}, '') AS TEXT), COUNT(*) AS flows,
+            SUM(in_octets + rev_octets) AS octets, SUM(in_pkts + rev_pkts) AS pkts
+            FROM {source}GROUP BY {column} {order_statement}{}",
+        limit_to_sql(query.limit, query.offset));
+    let mut stmt = conn.prepare(&statement)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+    while let Some(row) = rows.next()? {
+        f(AggregateRow {
+            key: row.get(0)?,
+            flows: row.get(1)?,
+            octets: row.get::<_, Option<i64>>(2)?.unwrap_or_default(),
+            pkts: row.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+        });
+    }
+    Ok(())
+}
+
+pub fn query_aggregates(conn: &Connection, query: &AggregateQuery) -> rusqlite::Result<Vec<AggregateRow>> {
+    let mut aggregates = Vec::new();
+    for_each_aggregate(conn, query, |aggregate| aggregates.push(aggregate))?;
+    Ok(aggregates)
+}
+
+//the sources sending the most bytes
+pub fn query_top_sources_by_bytes(conn: &Connection, filter: &FlowFilter, limit: usize) -> rusqlite::Result<Vec<NetflowBytesJson>> {
+    let query = AggregateQuery { group_by: GroupBy::SrcAddr, filter: filter.clone(), sort: FlowSort::Bytes, limit: Some(limit), offset: 0 };
+    Ok(query_aggregates(conn, &query)?.into_iter()
+        .map(|a| NetflowBytesJson { flow_src_ip: a.key, flow_bytes: a.octets })
+        .collect())
+}
+
+pub fn query_top_sources_by_packets(conn: &Connection, filter: &FlowFilter, limit: usize) -> rusqlite::Result<Vec<NetflowPacketsJson>> {
+    let query = AggregateQuery { group_by: GroupBy::SrcAddr, filter: filter.clone(), sort: FlowSort::Packets, limit: Some(limit), offset: 0 };
+    Ok(query_aggregates(conn, &query)?.into_iter()
+        .map(|a| NetflowPacketsJson { flow_src_ip: a.key, flow_packets: a.pkts })
+        .collect())
+}
+
+//bytes for each port and protocol pair, busiest first
+pub fn query_ports_and_protocols(conn: &Connection, filter: &FlowFilter, limit: usize) -> rusqlite::Result<Vec<NetflowPortsAndProtocolsJson>> {
+    let (filter_statement, values) = filter.to_sql();
+    let statement = format!(
+        "SELECT src_port, dst_port, protocol, SUM(in_octets + rev_octets) AS octets
+            FROM flows {filter_statement}GROUP BY src_port, dst_port, protocol ORDER BY octets DESC {}",
+        limit_to_sql(Some(limit), 0));
+    let mut stmt = conn.prepare(&statement)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+    let mut ports_and_protocols = Vec::new();
+    while let Some(row) = rows.next()? {
+        ports_and_protocols.push(NetflowPortsAndProtocolsJson {
+            flow_src_port: row.get(0)?,
+            flow_dst_port: row.get(1)?,
+            flow_protocol: row.get(2)?,
+            flow_bytes: row.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+        });
+    }
+    Ok(ports_and_protocols)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::migrations::migrate_db;
+    use crate::senders::SenderCounters;
+    use crate::sql::{update_sender_counters_in_db, update_senders_in_db};
+
+    #[test]
+    fn filter_values_are_in_placeholder_order() {
+        let since = Local.timestamp_opt(1737496874, 0).unwrap();
+        let filter = FlowFilter {
+            sender_ip: Some(Ipv4Addr::new(192, 168, 0, 1)),
+            host: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            protocol: Some(6),
+            port: Some(443),
+            since: Some(since),
+            ..Default::default()
+        };
+        let (statement, values) = filter.to_sql();
+        assert_eq!(statement, "WHERE sender_ip = ? AND (src_addr = ? OR dst_addr = ?) AND protocol = ? \
+            AND (src_port = ? OR dst_port = ?) AND last_seen_epoch >= ? ");
+        assert_eq!(values, vec![
+            Value::Text("192.168.0.1".to_string()),
+            Value::Text("10.0.0.1".to_string()),
+            Value::Text("10.0.0.1".to_string()),
+            Value::Integer(6),
+            Value::Integer(443),
+            Value::Integer(443),
+            Value::Integer(1737496874),
+        ]);
+    }
+
+    #[test]
+    fn hosts_are_filtered_in_both_halves_of_the_union() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut conn).unwrap();
+        conn.execute("INSERT INTO senders (ip) VALUES ('192.168.0.1'), ('192.168.0.2')", []).unwrap();
+        for (sender_ip, src_addr, dst_addr, dst_port, octets) in [
+            ("192.168.0.1", "10.0.0.1", "10.0.0.2", 443, 100),
+            ("192.168.0.1", "10.0.0.2", "10.0.0.3", 443, 50),
+            ("192.168.0.1", "10.0.0.1", "10.0.0.3", 80, 10),
+            ("192.168.0.2", "10.0.0.1", "10.0.0.2", 443, 1000),
+        ] {
+            conn.execute("INSERT INTO flows (sender_ip, src_addr, dst_addr, protocol, src_port, dst_port, in_octets, in_pkts)
+                VALUES (?1, ?2, ?3, 6, 50000, ?4, ?5, 1)", rusqlite::params![sender_ip, src_addr, dst_addr, dst_port, octets]).unwrap();
+        }
+        let query = AggregateQuery {
+            group_by: GroupBy::Host,
+            filter: FlowFilter { sender_ip: Some(Ipv4Addr::new(192, 168, 0, 1)), port: Some(443), ..Default::default() },
+            sort: FlowSort::Bytes,
+            limit: None,
+            offset: 0,
+        };
+        let hosts: Vec<(String, i64)> = query_aggregates(&conn, &query).unwrap().into_iter()
+            .map(|a| (a.key, a.octets))
+            .collect();
+        assert_eq!(hosts, vec![("10.0.0.2".to_string(), 150), ("10.0.0.1".to_string(), 100), ("10.0.0.3".to_string(), 50)]);
+    }
+
+    #[test]
+    fn interface_buckets_are_filtered_by_sender_index_and_time() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut conn).unwrap();
+        conn.execute("INSERT INTO senders (ip) VALUES ('192.168.0.1'), ('192.168.0.2')", []).unwrap();
+        conn.execute("INSERT INTO interfaces (sender_ip, snmp_index, name, description, source) VALUES ('192.168.0.1', 1, 'Gi1', '', 'config')", []).unwrap();
+        for (sender_ip, snmp_index, bucket_start, in_octets) in [
+            ("192.168.0.1", 1, 1_700_000_000, 600),
+            ("192.168.0.1", 1, 1_700_000_060, 1200),
+            ("192.168.0.1", 1, 1_700_000_120, 1800),
+            ("192.168.0.1", 2, 1_700_000_060, 60),
+            ("192.168.0.2", 1, 1_700_000_060, 6000),
+        ] {
+            conn.execute("INSERT INTO interface_stats (sender_ip, snmp_index, bucket_start, in_octets, in_pkts, out_octets, out_pkts)
+                VALUES (?1, ?2, ?3, ?4, 1, 0, 0)", rusqlite::params![sender_ip, snmp_index, bucket_start, in_octets]).unwrap();
+        }
+        let query = InterfaceQuery {
+            sender_ip: Some(Ipv4Addr::new(192, 168, 0, 1)),
+            snmp_index: Some(1),
+            since: Local.timestamp_opt(1_700_000_060, 0).single(),
+            until: Local.timestamp_opt(1_700_000_120, 0).single(),
+            ..Default::default()
+        };
+        let buckets = query_interface_buckets(&conn, &query).unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!((buckets[0].name.as_str(), buckets[0].bucket_start, buckets[0].in_octets, buckets[0].in_bps), ("Gi1", 1_700_000_060, 1200, 160));
+
+        let query = InterfaceQuery { sender_ip: Some(Ipv4Addr::new(192, 168, 0, 1)), ..Default::default() };
+        let buckets: Vec<(u32, i64)> = query_interface_buckets(&conn, &query).unwrap().into_iter()
+            .map(|b| (b.snmp_index, b.bucket_start))
+            .collect();
+        assert_eq!(buckets, vec![(1, 1_700_000_120), (1, 1_700_000_060), (2, 1_700_000_060), (1, 1_700_000_000)]);
+    }
+
+    #[test]
+    fn interface_stats_add_up_the_last_minutes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut conn).unwrap();
+        conn.execute("INSERT INTO senders (ip) VALUES ('192.168.0.1')", []).unwrap();
+        let current_time = Local.timestamp_opt(1_700_000_400, 0).unwrap();
+        for (snmp_index, bucket_start, in_octets, out_octets) in [
+            //older than the window
+            (1, 1_700_000_040, 1_000_000, 0),
+            (1, 1_700_000_160, 3000, 300),
+            (1, 1_700_000_340, 3000, 0),
+            (2, 1_700_000_340, 9000, 0),
+        ] {
+            conn.execute("INSERT INTO interface_stats (sender_ip, snmp_index, bucket_start, in_octets, in_pkts, out_octets, out_pkts)
+                VALUES ('192.168.0.1', ?1, ?2, ?3, 1, ?4, 1)", rusqlite::params![snmp_index, bucket_start, in_octets, out_octets]).unwrap();
+        }
+        let stats: Vec<(u32, i64, i64, i64, i64)> = query_interface_stats(&conn, &current_time).unwrap().into_iter()
+            .map(|s| (s.snmp_index, s.in_octets, s.in_pkts, s.out_octets, s.in_bps))
+            .collect();
+        assert_eq!(stats, vec![(2, 9000, 1, 0, 240), (1, 6000, 2, 300, 160)]);
+    }
+
+    #[test]
+    fn senders_are_silent_after_their_expected_interval() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        let now = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        let counters = SenderCounters { packets: 2, bytes: 300, records_decoded: 5, records_skipped: 1, parse_errors: 0, template_refreshes: 1 };
+        //quiet for 120 sec
+        for sender_ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            update_sender_counters_in_db(&db_conn, sender_ip, &counters, &(now - chrono::Duration::seconds(130)), None).unwrap();
+            update_sender_counters_in_db(&db_conn, sender_ip, &counters, &(now - chrono::Duration::seconds(120)), Some(&now)).unwrap();
+        }
+        //never sent anything yet
+        update_senders_in_db(&db_conn, "10.0.0.4").unwrap();
+        let server_settings = ServerSettings {
+            exporter_expected_interval_sec: 60,
+            exporter_expected_intervals: HashMap::from([(Ipv4Addr::new(10, 0, 0, 2), 300), (Ipv4Addr::new(10, 0, 0, 3), 0)]),
+            ..parse_config_string(String::new())
+        };
+
+        let senders = query_senders(&db_conn, &server_settings, &now).unwrap();
+        let silent: Vec<(&str, bool)> = senders.iter().map(|s| (s.sender_ip.as_str(), s.silent)).collect();
+        assert_eq!(silent, vec![("10.0.0.1", true), ("10.0.0.2", false), ("10.0.0.3", false), ("10.0.0.4", false)]);
+        //the counters of every write are added up
+        let sender = &senders[0];
+        assert_eq!((sender.packets, sender.bytes, sender.records_decoded, sender.records_skipped, sender.template_refreshes), (4, 600, 10, 2, 2));
+        assert_eq!(sender.last_seen, Some(now - chrono::Duration::seconds(120)));
+        assert_eq!(sender.last_template, Some(now));
+        assert_eq!((senders[3].packets, senders[3].last_seen), (0, None));
+    }
+}
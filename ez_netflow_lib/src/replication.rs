@@ -0,0 +1,265 @@
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+use log::{error, info, warn};
+use socket2::{SockAddr, Socket};
+
+use crate::settings::ServerSettings;
+use crate::utils::*;
+
+//ip and udp headers in front of a spoofed datagram
+const SPOOFED_HEADER_SIZE: usize = 28;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicationTarget {
+    pub address: SocketAddrV4,
+    //only datagrams from these exporters are forwarded, empty forwards all of them
+    pub exporters: Vec<Ipv4Subnet>,
+    //keep the exporter as the source address, needs a raw socket
+    pub spoof_source: bool,
+}
+
+impl ReplicationTarget {
+    pub fn wants(&self, sender_ip: Ipv4Addr) -> bool {
+        self.exporters.is_empty() || self.exporters.iter().any(|subnet| subnet.contains(sender_ip))
+    }
+}
+
+//"10.0.0.5:2055; 10.0.0.6:9995 exporters=10.0.0.1|10.1.0.0/16 spoof" from config.ini
+pub fn parse_replication_targets(value: &str) -> Vec<ReplicationTarget> {
+    let mut targets = Vec::new();
+    for entry in value.split(';') {
+        let mut words = entry.split_whitespace();
+        let Some(first_word) = words.next() else {
+            continue;
+        };
+        let Ok(address) = first_word.parse::<SocketAddrV4>() else {
+            error!("Skipping replication target \"{}\" in config.ini, it doesn't start with an ipv4 address and port like 10.0.0.5:2055", entry.trim());
+            continue;
+        };
+        let mut target = ReplicationTarget { address, exporters: Vec::new(), spoof_source: false };
+        for word in words {
+            if word == "spoof" {
+                target.spoof_source = true;
+            }
+            else if let Some(exporters) = word.strip_prefix("exporters=") {
+                target.exporters = parse_subnets(&exporters.replace('|', ";"));
+                //same as allowed_exporters, an empty list would forward every exporter
+                if target.exporters.is_empty() && !exporters.is_empty() {
+                    panic!("None of the exporters for replication target {} in config.ini are valid ipv4 addresses or subnets: {}", address, exporters);
+                }
+            }
+            else {
+                warn!("Ignoring \"{}\" for replication target {} in config.ini, only exporters= and spoof are known", word, address);
+            }
+        }
+        targets.push(target);
+    }
+    targets
+}
+
+
+//forwards datagrams unchanged to the downstream collectors, from the receive stage
+//both sockets are non blocking, a send that would wait is counted as an error instead of holding up the receiver
+pub struct Replicator {
+    targets: Vec<ReplicationTarget>,
+    socket: Option<UdpSocket>,
+    raw_socket: Option<Socket>,
+}
+
+//for one datagram, added to the pipeline stats by the receiver
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReplicationCounters {
+    pub replicated: u64,
+    pub errors: u64,
+}
+
+impl Replicator {
+    //targets that can't get a raw socket are sent from our own address instead
+    pub fn new(server_settings: &ServerSettings) -> Self {
+        let mut targets = server_settings.replication_targets.clone();
+        let raw_socket = if targets.iter().any(|t| t.spoof_source) {
+            match bind_raw_socket() {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    error!("Unable to open a raw socket to replicate with the exporter address, replicating from our own address: {}", e);
+                    for target in targets.iter_mut() {
+                        target.spoof_source = false;
+                    }
+                    None
+                },
+            }
+        }
+        else {
+            None
+        };
+        let socket = if targets.iter().any(|t| !t.spoof_source) {
+            let socket = UdpSocket::bind("0.0.0.0:0").expect("Unable to bind the replication socket");
+            socket.set_nonblocking(true).expect("Unable to make the replication socket non blocking");
+            Some(socket)
+        }
+        else {
+            None
+        };
+        for target in &targets {
+            info!("Replicating datagrams to {}{}", target.address, if target.spoof_source { " with the exporter address" } else { "" });
+        }
+        Replicator {
+            targets,
+            socket,
+            raw_socket,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    pub fn forward(&self, datagram: &[u8], source: SocketAddr) -> ReplicationCounters {
+        let mut counters = ReplicationCounters::default();
+        let SocketAddr::V4(source) = source else {
+            return counters;
+        };
+        for target in self.targets.iter().filter(|t| t.wants(*source.ip())) {
+            let result = match (&self.raw_socket, &self.socket) {
+                (Some(raw_socket), _) if target.spoof_source => {
+                    build_spoofed_datagram(datagram, source, target.address)
+                        .and_then(|packet| raw_socket.send_to(&packet, &SockAddr::from(target.address)))
+                },
+                (_, Some(socket)) => socket.send_to(datagram, target.address),
+                _ => continue,
+            };
+            match result {
+                Ok(_) => counters.replicated += 1,
+                Err(_) => counters.errors += 1,
+            }
+        }
+        counters
+    }
+}
+
+//the kernel only lets us pick the source address when we write the ip header ourselves
+#[cfg(target_os = "linux")]
+fn bind_raw_socket() -> std::io::Result<Socket> {
+    use socket2::{Domain, Protocol, Type};
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP))?;
+    socket.set_header_included_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_raw_socket() -> std::io::Result<Socket> {
+    Err(Error::new(ErrorKind::Unsupported, "keeping the exporter address is only supported on linux"))
+}
+
+//linux fills in the ip checksum and the id, the udp checksum is optional on ipv4 and left at 0
+fn build_spoofed_datagram(payload: &[u8], source: SocketAddrV4, target: SocketAddrV4) -> std::io::Result<Vec<u8>> {
+    let total_len = u16::try_from(SPOOFED_HEADER_SIZE + payload.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "datagram too big to replicate with the exporter address"))?;
+    let udp_len = total_len - 20;
+    let mut packet = Vec::with_capacity(usize::from(total_len));
+    //version 4, 5 word header, no tos
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    //id, fragment offset, ttl 64, udp, checksum
+    packet.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0]);
+    packet.extend_from_slice(&source.ip().octets());
+    packet.extend_from_slice(&target.ip().octets());
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&target.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::settings::parse_config_string;
+
+    #[test]
+    fn targets_are_parsed_with_their_options() {
+        let targets = parse_replication_targets("10.0.0.5:2055; 10.0.0.6:9995 exporters=10.0.0.1|10.1.0.0/16 spoof");
+        assert_eq!(targets, vec![
+            ReplicationTarget { address: "10.0.0.5:2055".parse().unwrap(), exporters: Vec::new(), spoof_source: false },
+            ReplicationTarget { address: "10.0.0.6:9995".parse().unwrap(), exporters: parse_subnets("10.0.0.1;10.1.0.0/16"), spoof_source: true },
+        ]);
+        assert!(targets[1].wants(Ipv4Addr::new(10, 1, 2, 3)));
+        assert!(!targets[1].wants(Ipv4Addr::new(10, 0, 0, 2)));
+    }
+
+    #[test]
+    fn bad_targets_and_unknown_options_are_skipped() {
+        let targets = parse_replication_targets(" ; collector:2055; 10.0.0.5; 10.0.0.6:9995 spof");
+        assert_eq!(targets, vec![
+            ReplicationTarget { address: "10.0.0.6:9995".parse().unwrap(), exporters: Vec::new(), spoof_source: false },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "None of the exporters for replication target")]
+    fn target_without_a_valid_exporter_panics() {
+        parse_replication_targets("10.0.0.5:2055 exporters=nope");
+    }
+
+    #[test]
+    fn spoofed_datagram_has_the_right_headers() {
+        let payload = [1u8, 2, 3, 4, 5];
+        let source: SocketAddrV4 = "10.0.0.1:50000".parse().unwrap();
+        let target: SocketAddrV4 = "10.0.0.5:2055".parse().unwrap();
+        let packet = build_spoofed_datagram(&payload, source, target).unwrap();
+
+        assert_eq!(packet.len(), SPOOFED_HEADER_SIZE + payload.len());
+        //ip header
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 33);
+        assert_eq!(packet[8], 64);
+        assert_eq!(packet[9], 17);
+        //left for the kernel to fill in
+        assert_eq!(&packet[10..12], &[0, 0]);
+        assert_eq!(&packet[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&packet[16..20], &[10, 0, 0, 5]);
+        //udp header
+        assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), 50000);
+        assert_eq!(u16::from_be_bytes([packet[22], packet[23]]), 2055);
+        assert_eq!(u16::from_be_bytes([packet[24], packet[25]]), 13);
+        //no udp checksum
+        assert_eq!(&packet[26..28], &[0, 0]);
+        assert_eq!(&packet[28..], &payload);
+    }
+
+    #[test]
+    fn datagram_too_big_for_an_ip_packet_is_refused() {
+        let source: SocketAddrV4 = "10.0.0.1:50000".parse().unwrap();
+        let target: SocketAddrV4 = "10.0.0.5:2055".parse().unwrap();
+        let payload = vec![0u8; usize::from(u16::MAX) - SPOOFED_HEADER_SIZE];
+        assert!(build_spoofed_datagram(&payload, source, target).is_ok());
+        let payload = vec![0u8; usize::from(u16::MAX) - SPOOFED_HEADER_SIZE + 1];
+        assert_eq!(build_spoofed_datagram(&payload, source, target).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn only_the_wanted_exporters_are_forwarded() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let settings = ServerSettings {
+            replication_targets: parse_replication_targets(&format!("{} exporters=10.0.0.1", collector.local_addr().unwrap())),
+            ..parse_config_string(String::new())
+        };
+        let replicator = Replicator::new(&settings);
+        let mut buffer = [0u8; 16];
+
+        let counters = replicator.forward(&[9, 9], "10.0.0.2:2055".parse().unwrap());
+        assert_eq!((counters.replicated, counters.errors), (0, 0));
+        assert!(collector.recv_from(&mut buffer).is_err());
+
+        let counters = replicator.forward(&[1, 2], "10.0.0.1:2055".parse().unwrap());
+        assert_eq!((counters.replicated, counters.errors), (1, 0));
+        let (byte_count, _) = collector.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..byte_count], &[1, 2]);
+    }
+}
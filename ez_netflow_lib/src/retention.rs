@@ -0,0 +1,285 @@
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{Connection, params};
+use chrono::prelude::*;
+use log::{error, info};
+
+use crate::rollups::{get_rolled_until, RollupLevel};
+use crate::settings::*;
+
+//only flows that are closed and have no deltas left can go, the deltas reference them
+const FLOW_PURGE_CONDITION: &str = "end_time IS NOT NULL
+    AND NOT EXISTS (SELECT 1 FROM delta WHERE delta.flow_id = flows.id)";
+
+#[derive(Clone, Debug, Default)]
+pub struct PurgeStats {
+    //rows deleted per table because they were past their retention
+    pub expired: Vec<(&'static str, usize)>,
+    //rows deleted per table to get the db back under max_db_size_mb
+    pub evicted: Vec<(&'static str, usize)>,
+    pub vacuumed_pages: i64,
+}
+
+impl PurgeStats {
+    fn add(list: &mut Vec<(&'static str, usize)>, table: &'static str, rows: usize) {
+        if rows == 0 {
+            return;
+        }
+        match list.iter_mut().find(|(t, _)| *t == table) {
+            Some((_, total)) => *total += rows,
+            None => list.push((table, rows)),
+        }
+    }
+
+    pub fn log(&self) {
+        for (table, rows) in &self.expired {
+            info!("Purged {} expired rows from {}", rows, table);
+        }
+        for (table, rows) in &self.evicted {
+            info!("Purged {} rows from {} to stay under the max db size", rows, table);
+        }
+        if self.vacuumed_pages > 0 {
+            info!("Incremental vacuum freed {} pages", self.vacuumed_pages);
+        }
+    }
+}
+
+//incremental vacuum only works when auto_vacuum was set before the tables were created,
+//an existing db has to be rebuilt once with VACUUM to switch it over, only done when rebuild is set
+pub fn enable_incremental_vacuum(db_conn: &Connection, rebuild: bool) -> rusqlite::Result<()> {
+    let auto_vacuum: i64 = db_conn.pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+    if auto_vacuum != 2 {
+        //a new db has no pages yet, the pragma is enough
+        let page_count: i64 = db_conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+        db_conn.pragma_update(None, "auto_vacuum", "incremental")?;
+        if page_count > 0 && rebuild {
+            info!("Switching the db to incremental vacuum, this can take a while on a large db");
+            db_conn.execute_batch("VACUUM")?;
+        }
+        else if page_count > 0 {
+            info!("The db doesn't use incremental vacuum, purged space stays in the file until it's started once with vacuum_db_at_startup: true");
+        }
+    }
+    Ok(())
+}
+
+//the lock is taken per batch, so the server only ever waits for one batch
+pub fn run_retention(db_conn: &Mutex<Connection>, server_settings: &ServerSettings, current_time: &DateTime<Local>) -> PurgeStats {
+    let now = current_time.timestamp();
+    let batch_size = server_settings.retention_batch_size.max(1);
+    let mut stats = PurgeStats::default();
+
+    //raw deltas are only removed once they are in rollup_1m
+    let rolled_until = get_rolled_until(&db_conn.lock().unwrap(), RollupLevel::Minute);
+    let delta_cutoff = (now - server_settings.delta_retention_hours * 3600).min(rolled_until);
+    let deleted = delete_in_batches(db_conn, "delta",
+        "updated_epoch < ?1", delta_cutoff, batch_size);
+    PurgeStats::add(&mut stats.expired, "delta", deleted);
+
+    let deleted = delete_in_batches(db_conn, "flows",
+        &format!("{FLOW_PURGE_CONDITION} AND end_epoch < ?1"),
+        now - server_settings.flow_retention_days * 86400, batch_size);
+    PurgeStats::add(&mut stats.expired, "flows", deleted);
+
+    let deleted = delete_in_batches(db_conn, "interface_stats", "bucket_start < ?1",
+        now - server_settings.interface_stats_retention_days * 86400, batch_size);
+    PurgeStats::add(&mut stats.expired, "interface_stats", deleted);
+
+    for level in [RollupLevel::Minute, RollupLevel::Hour, RollupLevel::Day] {
+        let deleted = delete_in_batches(db_conn, level.table(), "bucket_start < ?1",
+            now - level.retention_sec(server_settings), batch_size);
+        PurgeStats::add(&mut stats.expired, level.table(), deleted);
+    }
+
+    if server_settings.max_db_size_mb > 0 {
+        evict_to_max_size(db_conn, server_settings.max_db_size_mb * 1024 * 1024, batch_size, &mut stats);
+    }
+
+    //an exporter that comes back is added again with its next datagram, its counters start over
+    let deleted = delete_in_batches(db_conn, "senders",
+        "NOT EXISTS (SELECT 1 FROM flows WHERE flows.sender_ip = senders.ip)
+            AND (last_seen IS NULL OR CAST(strftime('%s', last_seen) AS INTEGER) < ?1)",
+        now - server_settings.flow_retention_days * 86400, batch_size);
+    PurgeStats::add(&mut stats.expired, "senders", deleted);
+
+    stats.vacuumed_pages = incremental_vacuum(&db_conn.lock().unwrap());
+    stats
+}
+
+//a table max_db_size_mb can evict from, by the time of its rows
+struct EvictionCandidate {
+    table: &'static str,
+    time_column: &'static str,
+    condition: &'static str,
+    //rows from here on aren't kept in a coarser level yet, evicting them loses them for good
+    floor: i64,
+    //how far it goes once everything up to the floors is gone
+    limit: i64,
+}
+
+//the oldest rows go first whatever table they are in, as long as they are kept in a coarser level too
+//only when that isn't enough are the rows kept nowhere else evicted, and that's logged as an error
+fn evict_to_max_size(db_conn: &Mutex<Connection>, max_bytes: i64, batch_size: i64, stats: &mut PurgeStats) {
+    let candidates = {
+        let db_conn_unlocked = db_conn.lock().unwrap();
+        let minute_rolled_until = get_rolled_until(&db_conn_unlocked, RollupLevel::Minute);
+        let hour_rolled_until = get_rolled_until(&db_conn_unlocked, RollupLevel::Hour);
+        let day_rolled_until = get_rolled_until(&db_conn_unlocked, RollupLevel::Day);
+        [
+            //deltas that aren't in rollup_1m yet are never evicted, they are rolled up by the next run
+            EvictionCandidate { table: "delta", time_column: "updated_epoch", condition: "TRUE", floor: minute_rolled_until, limit: minute_rolled_until },
+            //the traffic of a flow without deltas is in the rollups
+            EvictionCandidate { table: "flows", time_column: "end_epoch", condition: FLOW_PURGE_CONDITION, floor: i64::MAX, limit: i64::MAX },
+            EvictionCandidate { table: RollupLevel::Minute.table(), time_column: "bucket_start", condition: "TRUE", floor: hour_rolled_until, limit: i64::MAX },
+            EvictionCandidate { table: RollupLevel::Hour.table(), time_column: "bucket_start", condition: "TRUE", floor: day_rolled_until, limit: i64::MAX },
+            EvictionCandidate { table: RollupLevel::Day.table(), time_column: "bucket_start", condition: "TRUE", floor: i64::MIN, limit: i64::MAX },
+            EvictionCandidate { table: "interface_stats", time_column: "bucket_start", condition: "TRUE", floor: i64::MIN, limit: i64::MAX },
+        ]
+    };
+    if evict_oldest(db_conn, &candidates, false, max_bytes, batch_size, stats) {
+        return;
+    }
+    error!("The db is still over max_db_size_mb after evicting everything that is also kept in a coarser level, \
+        evicting the oldest rollup_1d, interface_stats and not yet rolled up rollup_1m and rollup_1h rows, \
+        raise max_db_size_mb or lower the retention");
+    if !evict_oldest(db_conn, &candidates, true, max_bytes, batch_size, stats) {
+        error!("The db is still over max_db_size_mb with nothing left to evict but the open flows and the deltas that aren't rolled up yet");
+    }
+}
+
+//true once the db is under max_bytes
+//a batch only goes as far as the oldest row of the next table, so the tables are evicted in step
+fn evict_oldest(db_conn: &Mutex<Connection>, candidates: &[EvictionCandidate], past_floor: bool, max_bytes: i64, batch_size: i64, stats: &mut PurgeStats) -> bool {
+    loop {
+        let db_conn_unlocked = db_conn.lock().unwrap();
+        if get_used_db_bytes(&db_conn_unlocked) <= max_bytes {
+            return true;
+        }
+        let mut oldest: Vec<(i64, &EvictionCandidate)> = candidates.iter()
+            .filter_map(|candidate| {
+                let cutoff = if past_floor { candidate.limit } else { candidate.floor };
+                get_oldest_time(&db_conn_unlocked, candidate, cutoff).map(|time| (time, candidate))
+            })
+            .collect();
+        oldest.sort_by_key(|(time, _)| *time);
+        let Some((_, candidate)) = oldest.first() else {
+            return false;
+        };
+        let mut cutoff = if past_floor { candidate.limit } else { candidate.floor };
+        if let Some((next_time, _)) = oldest.get(1) {
+            cutoff = cutoff.min(next_time.saturating_add(1));
+        }
+        let deleted = db_conn_unlocked.execute(
+            &format!("DELETE FROM {table} WHERE rowid IN
+                (SELECT rowid FROM {table} WHERE {condition} AND {time_column} < ?1 ORDER BY {time_column} LIMIT ?2)",
+                table = candidate.table, condition = candidate.condition, time_column = candidate.time_column),
+            params![cutoff, batch_size],
+            ).expect("Unable to delete a batch of rows in evict_oldest");
+        drop(db_conn_unlocked);
+        PurgeStats::add(&mut stats.evicted, candidate.table, deleted);
+    }
+}
+
+fn get_oldest_time(db_conn: &Connection, candidate: &EvictionCandidate, cutoff: i64) -> Option<i64> {
+    db_conn.query_row(
+        &format!("SELECT MIN({time_column}) FROM {table} WHERE {condition} AND {time_column} < ?1",
+            table = candidate.table, condition = candidate.condition, time_column = candidate.time_column),
+        [cutoff],
+        |row| row.get(0),
+        ).expect("Unable to read the oldest row in get_oldest_time")
+}
+
+fn delete_in_batches(db_conn: &Mutex<Connection>, table: &str, condition: &str, cutoff: i64, batch_size: i64) -> usize {
+    let mut total = 0;
+    loop {
+        let db_conn_unlocked: MutexGuard<Connection> = db_conn.lock().unwrap();
+        let deleted = delete_batch(&db_conn_unlocked, table, condition, cutoff, batch_size);
+        total += deleted;
+        if (deleted as i64) < batch_size {
+            return total;
+        }
+    }
+}
+
+//rowids go up as rows are inserted, so the lowest ones are the oldest
+fn delete_batch(db_conn: &Connection, table: &str, condition: &str, cutoff: i64, batch_size: i64) -> usize {
+    db_conn.execute(
+        &format!("DELETE FROM {table} WHERE rowid IN
+            (SELECT rowid FROM {table} WHERE {condition} ORDER BY rowid LIMIT ?2)"),
+        params![cutoff, batch_size],
+        ).expect("Unable to delete a batch of rows in delete_batch")
+}
+
+//freed pages stay in the file until they are vacuumed, so they don't count towards the size
+pub fn get_used_db_bytes(db_conn: &Connection) -> i64 {
+    let page_count: i64 = db_conn.pragma_query_value(None, "page_count", |row| row.get(0))
+        .expect("Unable to read page_count");
+    let freelist_count: i64 = db_conn.pragma_query_value(None, "freelist_count", |row| row.get(0))
+        .expect("Unable to read freelist_count");
+    let page_size: i64 = db_conn.pragma_query_value(None, "page_size", |row| row.get(0))
+        .expect("Unable to read page_size");
+    (page_count - freelist_count) * page_size
+}
+
+fn incremental_vacuum(db_conn: &Connection) -> i64 {
+    let freelist_count: i64 = db_conn.pragma_query_value(None, "freelist_count", |row| row.get(0))
+        .expect("Unable to read freelist_count");
+    if freelist_count == 0 {
+        return 0;
+    }
+    let mut stmt = db_conn.prepare("PRAGMA incremental_vacuum")
+        .expect("Unable to prepare incremental_vacuum");
+    let mut rows = stmt.query([]).expect("Unable to run incremental_vacuum");
+    //every step frees one page, so it has to run to the end
+    while rows.next().expect("Unable to run incremental_vacuum").is_some() {}
+    let remaining: i64 = db_conn.pragma_query_value(None, "freelist_count", |row| row.get(0))
+        .expect("Unable to read freelist_count");
+    freelist_count - remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate_db;
+
+    fn count_rows(db_conn: &Mutex<Connection>, table: &str) -> i64 {
+        db_conn.lock().unwrap().query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| row.get(0)).unwrap()
+    }
+
+    fn test_db() -> Mutex<Connection> {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        for (table, bucket_start) in [("rollup_1m", 60), ("rollup_1m", 7200), ("rollup_1d", 0)] {
+            db_conn.execute(&format!("INSERT INTO {table} (bucket_start, sender_ip, src_addr, dst_addr, protocol, dst_port, octets, pkts)
+                VALUES (?1, '10.0.0.1', '10.1.1.1', '10.2.2.2', 6, 443, 100, 1)"), [bucket_start]).unwrap();
+        }
+        db_conn.execute("INSERT INTO interface_stats (sender_ip, snmp_index, bucket_start) VALUES ('10.0.0.1', 1, 0)", []).unwrap();
+        //rollup_1h has the first hour of rollup_1m
+        db_conn.execute("INSERT INTO rollup_state (rollup_table, rolled_until) VALUES ('rollup_1h', 3600)", []).unwrap();
+        Mutex::new(db_conn)
+    }
+
+    #[test]
+    fn eviction_stops_at_the_floors() {
+        let db_conn = test_db();
+        let candidates = [
+            EvictionCandidate { table: RollupLevel::Minute.table(), time_column: "bucket_start", condition: "TRUE", floor: 3600, limit: i64::MAX },
+            EvictionCandidate { table: RollupLevel::Day.table(), time_column: "bucket_start", condition: "TRUE", floor: i64::MIN, limit: i64::MAX },
+            EvictionCandidate { table: "interface_stats", time_column: "bucket_start", condition: "TRUE", floor: i64::MIN, limit: i64::MAX },
+        ];
+        let mut stats = PurgeStats::default();
+        assert!(!evict_oldest(&db_conn, &candidates, false, 0, 10, &mut stats));
+        assert_eq!(stats.evicted, vec![("rollup_1m", 1)]);
+        assert_eq!((count_rows(&db_conn, "rollup_1m"), count_rows(&db_conn, "rollup_1d"), count_rows(&db_conn, "interface_stats")), (1, 1, 1));
+    }
+
+    #[test]
+    fn eviction_goes_past_the_floors_last() {
+        let db_conn = test_db();
+        let mut stats = PurgeStats::default();
+        evict_to_max_size(&db_conn, 0, 10, &mut stats);
+        //the rolled up rollup_1m row goes first, then everything else
+        assert_eq!(stats.evicted, vec![("rollup_1m", 2), ("rollup_1d", 1), ("interface_stats", 1)]);
+        assert_eq!(count_rows(&db_conn, "rollup_1m"), 0);
+    }
+}
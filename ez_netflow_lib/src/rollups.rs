@@ -0,0 +1,300 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use chrono::prelude::*;
+use log::error;
+
+use crate::retention::run_retention;
+use crate::settings::*;
+
+//how often the background thread rolls up new data and applies the retention, see retention.rs
+pub const ROLLUP_INTERVAL_SEC: u64 = 60;
+
+//a window is answered from the coarsest level that still gives at least this many buckets
+const MIN_BUCKETS_PER_WINDOW: i64 = 12;
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize)]
+pub enum RollupLevel {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl RollupLevel {
+    pub fn table(&self) -> &'static str {
+        match self {
+            RollupLevel::Minute => "rollup_1m",
+            RollupLevel::Hour => "rollup_1h",
+            RollupLevel::Day => "rollup_1d",
+        }
+    }
+
+    pub fn bucket_sec(&self) -> i64 {
+        match self {
+            RollupLevel::Minute => 60,
+            RollupLevel::Hour => 3600,
+            RollupLevel::Day => 86400,
+        }
+    }
+
+    pub fn retention_sec(&self, server_settings: &ServerSettings) -> i64 {
+        match self {
+            RollupLevel::Minute => server_settings.rollup_1m_retention_hours * 3600,
+            RollupLevel::Hour => server_settings.rollup_1h_retention_days * 86400,
+            RollupLevel::Day => server_settings.rollup_1d_retention_days * 86400,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RollupRow {
+    pub bucket_start: i64,
+    pub sender_ip: String,
+    pub src_addr: String,
+    pub dst_addr: String,
+    pub protocol: i64,
+    pub dst_port: i64,
+    pub octets: i64,
+    pub pkts: i64,
+}
+
+//runs until stop is dropped, which also cuts the wait between runs short
+pub fn start_rollup_thread(db_conn: Arc<Mutex<Connection>>, server_settings: ServerSettings, stop: Receiver<()>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(Duration::from_secs(ROLLUP_INTERVAL_SEC)) {
+            {
+                let db_conn_unlocked: MutexGuard<Connection> = db_conn.lock().unwrap();
+                //nothing was committed, the same buckets are tried again on the next run
+                if let Err(e) = run_rollups(&db_conn_unlocked, &Local::now()) {
+                    error!("Unable to roll up the deltas: {}", e);
+                }
+            }
+            //after the rollups, so the deltas that were just rolled up can go
+            run_retention(&db_conn, &server_settings, &Local::now()).log();
+        }
+    })
+}
+
+//only complete buckets are rolled up, the current one is still filling
+//the levels and their rolled_until are written in one transaction, so a failed run can't count anything twice
+pub fn run_rollups(db_conn: &Connection, current_time: &DateTime<Local>) -> rusqlite::Result<()> {
+    let tx = db_conn.unchecked_transaction()?;
+    let now = current_time.timestamp();
+
+    //a record is timestamped before the db lock is taken, so give the last minute time to be written
+    let settled = now - RollupLevel::Minute.bucket_sec();
+    let minute_until = settled - settled.rem_euclid(RollupLevel::Minute.bucket_sec());
+    let minute_from = get_rolled_until(&tx, RollupLevel::Minute);
+    tx.execute(
+        "INSERT INTO rollup_1m (bucket_start, sender_ip, src_addr, dst_addr, protocol, dst_port, octets, pkts)
+            SELECT (d.updated_epoch / 60) * 60 AS bucket,
+                f.sender_ip, f.src_addr, f.dst_addr, f.protocol, f.dst_port, SUM(d.in_octets), SUM(d.in_pkts)
+            FROM delta d JOIN flows f ON f.id = d.flow_id
+            WHERE f.dedup_primary = 1
+                AND d.updated_epoch >= ?1
+                AND d.updated_epoch < ?2
+            GROUP BY bucket, f.sender_ip, f.src_addr, f.dst_addr, f.protocol, f.dst_port
+            ON CONFLICT DO UPDATE SET
+                octets = octets + excluded.octets,
+                pkts = pkts + excluded.pkts",
+        params![minute_from, minute_until],
+        )?;
+    set_rolled_until(&tx, RollupLevel::Minute, minute_until.max(minute_from))?;
+
+    roll_up_level(&tx, RollupLevel::Minute, RollupLevel::Hour)?;
+    roll_up_level(&tx, RollupLevel::Hour, RollupLevel::Day)?;
+    tx.commit()
+}
+
+//a target bucket is only rolled up once its source has been rolled up to the end of it
+fn roll_up_level(db_conn: &Connection, source: RollupLevel, target: RollupLevel) -> rusqlite::Result<()> {
+    let bucket_sec = target.bucket_sec();
+    let rolled_from = get_rolled_until(db_conn, target);
+    let source_until = get_rolled_until(db_conn, source);
+    let rolled_until = source_until - source_until.rem_euclid(bucket_sec);
+    db_conn.execute(&format!(
+        "INSERT INTO {target} (bucket_start, sender_ip, src_addr, dst_addr, protocol, dst_port, octets, pkts)
+            SELECT (bucket_start / {bucket_sec}) * {bucket_sec} AS bucket,
+                sender_ip, src_addr, dst_addr, protocol, dst_port, SUM(octets), SUM(pkts)
+            FROM {source}
+            WHERE bucket_start >= ?1 AND bucket_start < ?2
+            GROUP BY bucket, sender_ip, src_addr, dst_addr, protocol, dst_port
+            ON CONFLICT DO UPDATE SET
+                octets = octets + excluded.octets,
+                pkts = pkts + excluded.pkts",
+        target = target.table(), source = source.table()),
+        params![rolled_from, rolled_until],
+        )?;
+    set_rolled_until(db_conn, target, rolled_until.max(rolled_from))
+}
+
+pub(crate) fn get_rolled_until(db_conn: &Connection, level: RollupLevel) -> i64 {
+    db_conn.query_row(
+        "SELECT rolled_until FROM rollup_state WHERE rollup_table = ?1",
+        params![level.table()],
+        |row| row.get(0),
+    ).unwrap_or(0)
+}
+
+fn set_rolled_until(db_conn: &Connection, level: RollupLevel, rolled_until: i64) -> rusqlite::Result<()> {
+    db_conn.execute(
+        "INSERT INTO rollup_state (rollup_table, rolled_until) VALUES (?1, ?2)
+            ON CONFLICT (rollup_table) DO UPDATE SET rolled_until = excluded.rolled_until",
+        params![level.table(), rolled_until],
+        )?;
+    Ok(())
+}
+
+//the coarsest level that still splits the window into enough buckets and still has data for its start
+pub fn choose_rollup_level(server_settings: &ServerSettings, window_start: i64, window_end: i64, current_time: &DateTime<Local>) -> RollupLevel {
+    let window_sec = window_end - window_start;
+    let now = current_time.timestamp();
+    [RollupLevel::Day, RollupLevel::Hour]
+        .into_iter()
+        .find(|level| {
+            window_sec >= level.bucket_sec() * MIN_BUCKETS_PER_WINDOW
+                && window_start >= now - level.retention_sec(server_settings)
+        })
+        .unwrap_or(RollupLevel::Minute)
+}
+
+//top talkers between two unix times, summed over the buckets of the chosen level
+pub fn get_traffic_in_window(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings, window_start: i64, window_end: i64, limit: i64) -> (RollupLevel, Vec<RollupRow>) {
+    let level = choose_rollup_level(server_settings, window_start, window_end, &Local::now());
+    let mut all_rows: Vec<RollupRow> = Vec::new();
+
+    let conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
+    let mut stmt: rusqlite::Statement = conn.prepare(&format!(
+        "SELECT MIN(bucket_start), sender_ip, src_addr, dst_addr, protocol, dst_port, SUM(octets), SUM(pkts)
+            FROM {}
+            WHERE bucket_start >= ?1 AND bucket_start < ?2
+            GROUP BY sender_ip, src_addr, dst_addr, protocol, dst_port
+            ORDER BY SUM(octets) DESC LIMIT ?3",
+        level.table()))
+        .expect("Unable to prepare query");
+
+    //include the bucket the window starts in
+    let bucket_start = window_start - window_start.rem_euclid(level.bucket_sec());
+    let mut rows = stmt.query(params![bucket_start, window_end, limit])
+        .expect("Unable to query rows");
+
+    while let Some(row) = rows.next().expect("no more rows") {
+        all_rows.push(RollupRow {
+            bucket_start: row.get(0).expect("Unable to open column 0"),
+            sender_ip: row.get(1).expect("Unable to open column 1"),
+            src_addr: row.get(2).expect("Unable to open column 2"),
+            dst_addr: row.get(3).expect("Unable to open column 3"),
+            protocol: row.get(4).expect("Unable to open column 4"),
+            dst_port: row.get(5).expect("Unable to open column 5"),
+            octets: row.get(6).expect("Unable to open column 6"),
+            pkts: row.get(7).expect("Unable to open column 7"),
+        });
+    }
+
+    (level, all_rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate_db;
+
+    //the start of a day in utc, so every level's buckets line up with it
+    const DAY: i64 = 1_699_920_000;
+
+    fn at(timestamp: i64) -> DateTime<Local> {
+        Local.timestamp_opt(timestamp, 0).unwrap()
+    }
+
+    fn db_with_deltas(deltas: &[(i64, i64)]) -> Connection {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        migrate_db(&mut db_conn).unwrap();
+        db_conn.execute("INSERT INTO senders (ip) VALUES ('10.0.0.1')", []).unwrap();
+        db_conn.execute("INSERT INTO flows (id, sender_ip, src_addr, dst_addr, protocol, dst_port)
+            VALUES (1, '10.0.0.1', '10.0.0.2', '10.0.0.3', 6, 443)", []).unwrap();
+        for (updated_epoch, octets) in deltas {
+            db_conn.execute("INSERT INTO delta (flow_id, updated_epoch, in_octets, in_pkts) VALUES (1, ?1, ?2, 1)",
+                params![updated_epoch, octets]).unwrap();
+        }
+        db_conn
+    }
+
+    fn buckets(db_conn: &Connection, level: RollupLevel) -> Vec<(i64, i64)> {
+        let mut stmt = db_conn.prepare(&format!("SELECT bucket_start, octets FROM {} ORDER BY bucket_start", level.table())).unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(i64, i64)>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn only_complete_buckets_are_rolled_up_and_only_once() {
+        let db_conn = db_with_deltas(&[(DAY + 10, 100), (DAY + 70, 200), (DAY + 3605, 400)]);
+
+        //the first minute ended less than a minute ago, it may still be written to
+        run_rollups(&db_conn, &at(DAY + 100)).unwrap();
+        assert_eq!(buckets(&db_conn, RollupLevel::Minute), vec![]);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Minute), DAY);
+
+        run_rollups(&db_conn, &at(DAY + 125)).unwrap();
+        assert_eq!(buckets(&db_conn, RollupLevel::Minute), vec![(DAY, 100)]);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Minute), DAY + 60);
+        //the hour isn't complete in rollup_1m yet
+        assert_eq!(buckets(&db_conn, RollupLevel::Hour), vec![]);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Hour), DAY);
+
+        //running again in the same minute adds nothing
+        run_rollups(&db_conn, &at(DAY + 125)).unwrap();
+        assert_eq!(buckets(&db_conn, RollupLevel::Minute), vec![(DAY, 100)]);
+    }
+
+    #[test]
+    fn each_level_is_rolled_up_from_the_one_below() {
+        let db_conn = db_with_deltas(&[(DAY + 10, 100), (DAY + 70, 200), (DAY + 3605, 400)]);
+
+        run_rollups(&db_conn, &at(DAY + 7260)).unwrap();
+
+        assert_eq!(buckets(&db_conn, RollupLevel::Minute), vec![(DAY, 100), (DAY + 60, 200), (DAY + 3600, 400)]);
+        assert_eq!(buckets(&db_conn, RollupLevel::Hour), vec![(DAY, 300), (DAY + 3600, 400)]);
+        assert_eq!(buckets(&db_conn, RollupLevel::Day), vec![]);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Minute), DAY + 7200);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Hour), DAY + 7200);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Day), DAY);
+
+        //once the day is over in rollup_1h it is rolled up too
+        run_rollups(&db_conn, &at(DAY + 86400 + 120)).unwrap();
+        assert_eq!(buckets(&db_conn, RollupLevel::Day), vec![(DAY, 700)]);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Day), DAY + 86400);
+    }
+
+    #[test]
+    fn a_failed_run_leaves_nothing_behind() {
+        let db_conn = db_with_deltas(&[(DAY + 10, 100)]);
+        db_conn.execute("DROP TABLE rollup_1d", []).unwrap();
+
+        assert!(run_rollups(&db_conn, &at(DAY + 86400 + 120)).is_err());
+        assert_eq!(buckets(&db_conn, RollupLevel::Minute), vec![]);
+        assert_eq!(get_rolled_until(&db_conn, RollupLevel::Minute), 0);
+    }
+
+    #[test]
+    fn the_coarsest_level_with_enough_buckets_and_data_is_chosen() {
+        let server_settings = parse_config_string(String::new());
+        let now = at(DAY);
+        let hour = 3600;
+        let day = 86400;
+
+        assert_eq!(choose_rollup_level(&server_settings, DAY - hour, DAY, &now), RollupLevel::Minute);
+        assert_eq!(choose_rollup_level(&server_settings, DAY - 11 * hour, DAY, &now), RollupLevel::Minute);
+        assert_eq!(choose_rollup_level(&server_settings, DAY - 12 * hour, DAY, &now), RollupLevel::Hour);
+        assert_eq!(choose_rollup_level(&server_settings, DAY - 5 * day, DAY, &now), RollupLevel::Hour);
+        assert_eq!(choose_rollup_level(&server_settings, DAY - 12 * day, DAY, &now), RollupLevel::Day);
+        //rollup_1h only keeps 90 days, a shorter window further back can't be answered from it
+        assert_eq!(choose_rollup_level(&server_settings, DAY - 100 * day, DAY - 95 * day, &now), RollupLevel::Minute);
+        assert_eq!(choose_rollup_level(&server_settings, DAY - 100 * day, DAY - 80 * day, &now), RollupLevel::Day);
+    }
+}
@@ -1,12 +1,14 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::Ipv4Addr;
-use std::sync::{Arc, Mutex, MutexGuard};
 
-use rusqlite::Connection;
 use chrono::prelude::*;
+use serde::Serialize;
 
+use crate::dedup::*;
+use crate::interfaces::*;
+use crate::pipeline::WriteOp;
 use crate::templates::*;
 use crate::fields::*;
-use crate::sql::*;
 use crate::utils::*;
 
 
@@ -16,30 +18,101 @@ pub struct NetflowSender {
     pub ip_addr: Ipv4Addr,
     pub active_template: NetflowTemplate,
     pub flow_packets: Vec<NetflowTemplate>,
-    pub flow_stats:  Vec<NetFlow>,
+    pub flow_stats: HashMap<FlowKey, NetFlow>,
+    //flows that changed since the last db write
+    pub dirty_flows: HashSet<FlowKey>,
+    //interface traffic that hasn't been written to the db yet
+    pub interface_counters: HashMap<InterfaceBucket, InterfaceCounters>,
+    pub options_template: Option<OptionsTemplate>,
+    //counted since the last db write, the totals are in the senders table
+    pub counters: SenderCounters,
+    pub last_seen: DateTime<Local>,
+    //when the last template was received, None until the first one
+    pub last_template: Option<DateTime<Local>>,
+    //set once the exporter has been quiet for longer than its expected interval
+    pub silent: bool,
+}
+
+//ingest counters for one exporter
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SenderCounters {
+    pub packets: u64,
+    pub bytes: u64,
+    pub records_decoded: u64,
+    //records the datagram header announced that weren't decoded, the template was missing or didn't match
+    pub records_skipped: u64,
+    //datagrams, flowsets or templates that were cut short or that the template didn't fit
+    pub parse_errors: u64,
+    //templates received after the first one, exporters resend them on a fixed interval
+    pub template_refreshes: u64,
+}
+
+impl SenderCounters {
+    pub fn is_empty(&self) -> bool {
+        *self == SenderCounters::default()
+    }
+}
+
+//an exporter as stored in the senders table, with its totals
+#[derive(Clone, Debug, Serialize)]
+pub struct SenderStats {
+    pub sender_ip: String,
+    pub packets: i64,
+    pub bytes: i64,
+    pub records_decoded: i64,
+    pub records_skipped: i64,
+    pub parse_errors: i64,
+    pub template_refreshes: i64,
+    pub last_seen: Option<DateTime<Local>>,
+    pub last_template: Option<DateTime<Local>>,
+    //quiet for longer than its expected interval
+    pub silent: bool,
+}
+
+//"10.0.0.1=300; 10.0.0.2=30" from config.ini, in seconds
+pub fn parse_exporter_intervals(value: &str) -> HashMap<Ipv4Addr, i64> {
+    let mut intervals = HashMap::new();
+    for entry in value.split(';') {
+        let (sender, interval) = match entry.split_once('=') {
+            Some(s) => s,
+            None => continue,
+        };
+        if let (Ok(sender_ip), Ok(interval_sec)) = (sender.trim().parse::<Ipv4Addr>(), interval.trim().parse::<i64>()) {
+            intervals.insert(sender_ip, interval_sec);
+        }
+    }
+    intervals
 }
 
 
 impl NetflowSender {
-    // pub fn new(new_sender_ip: Ipv4Addr, template: NetflowTemplate) -> Self {
-    //     NetflowSender {
-    //         ip_addr: new_sender_ip,
-    //         active_template: template,
-    //         flow_packets: Vec::new(),
-    //         flow_stats: Vec::new(),
-    //     }
-    // }
+    //the active template stays empty until the exporter sends one
+    pub fn new(new_sender_ip: Ipv4Addr) -> Self {
+        NetflowSender {
+            ip_addr: new_sender_ip,
+            active_template: NetflowTemplate::default(),
+            flow_packets: Vec::new(),
+            flow_stats: HashMap::new(),
+            dirty_flows: HashSet::new(),
+            interface_counters: HashMap::new(),
+            options_template: None,
+            counters: SenderCounters::default(),
+            last_seen: Local::now(),
+            last_template: None,
+            silent: false,
+        }
+    }
 
 
     pub fn report_flow_stats(&self) {
           //look for existing flow and update
-          for flow in &self.flow_stats {
+          for flow in self.flow_stats.values() {
             println!("Start flow data...");
             println!("Src IP is {} and Dst IP is {}", flow.src_ip, flow.dst_ip );
             println!("Src Port is {} and Dst Port is {}", flow.src_ip, flow.dst_ip);
             println!("Protocol is {}", flow.protocol);
-            println!("Bytes/octets are {}", flow.in_octets );
-            println!("Packets are {}", flow.in_packets);
+            println!("Bytes/octets are {} forward and {} reverse", flow.in_octets, flow.rev_octets);
+            println!("Packets are {} forward and {} reverse", flow.in_packets, flow.rev_packets);
             println!("End flow data");
 
         }
@@ -47,8 +120,13 @@ impl NetflowSender {
   
 
 
-    pub fn parse_packet_to_flow(&mut self) {
-        while let Some(pkt) = self.flow_packets.pop() {
+    //dedup_table is only passed in when dedup is enabled
+    //returns the number of records parsed, the server uses it to decide when to write to the db
+    pub fn parse_packet_to_flow(&mut self, mut dedup_table: Option<&mut DedupTable>, local_subnets: &[Ipv4Subnet]) -> usize {
+        //records are handled in the order they arrived so first-seen times stay meaningful
+        let received_packets: Vec<NetflowTemplate> = self.flow_packets.drain(..).collect();
+        let record_count = received_packets.len();
+        for pkt in received_packets {
                     //println!("parsing packet to flow");
 
                     //get tuple
@@ -68,6 +146,26 @@ impl NetflowSender {
                         _ => 0,
                     };
 
+                    let flags: u8 = match pkt.tcp_flags {
+                        Some(U8Field::Value(v)) => { v },
+                        _ => 0,
+                    };
+
+                    let observation = ObservationPoint {
+                        input_snmp: match pkt.input_snmp {
+                            Some(U32Field::Value(v)) => { v },
+                            _ => 0,
+                        },
+                        output_snmp: match pkt.output_snmp {
+                            Some(U32Field::Value(v)) => { v },
+                            _ => 0,
+                        },
+                        direction: match pkt.direction {
+                            Some(U8Field::Value(v)) => { Some(v) },
+                            _ => None,
+                        },
+                    };
+
                     // let s_and_d_ip: (Ipv4Addr, Ipv4Addr) = (
                     //     match pkt.src_addr {
                     //         Some(Ipv4Field::Value(v)) => { v },
@@ -99,6 +197,23 @@ impl NetflowSender {
                         _ => 0,
                     };
 
+                    let src_vlan: u16 = match pkt.src_vlan {
+                        Some(U16Field::Value(v)) => { v },
+                        _ => 0,
+                    };
+
+                    let dst_vlan: u16 = match pkt.dst_vlan {
+                        Some(U16Field::Value(v)) => { v },
+                        _ => 0,
+                    };
+
+                    let vrf_id: u32 = match pkt.ingress_vrf_id {
+                        Some(U32Field::Value(v)) => { v },
+                        _ => 0,
+                    };
+
+                    let key = FlowKey::new(self.ip_addr, proto, vrf_id, (src_ip, src_port, src_vlan), (dst_ip, dst_port, dst_vlan));
+
                     // let s_and_d_port: (u16, u16) = (
                     //     match pkt.src_port {
                     //         Some(U16Field::Value(v)) => { v },
@@ -110,12 +225,22 @@ impl NetflowSender {
                     //     }
                     // );
 
+                    let src_mask: u8 = match pkt.src_mask {
+                        Some(U8Field::Value(v)) => { v },
+                        _ => 0,
+                    };
+
+                    let dst_mask: u8 = match pkt.dst_mask {
+                        Some(U8Field::Value(v)) => { v },
+                        _ => 0,
+                    };
+
                     let cast: TrafficType = match pkt.in_dst_mac {
                         Some(U64Field::Value(v)) => { 
                             let field_array: [u8; 8] = v.to_be_bytes();
                             //let field_array: [u8; 6] = field_array_64[..6];
-                            let pkt_cast = handle_traffic_type_in_flow(src_ip, dst_ip);
-                            if pkt_cast == TrafficType::Unicast && field_array[0] == 0xFF && field_array[1] == 0xFF
+                            let pkt_cast = handle_traffic_type_in_flow(src_ip, dst_ip, dst_mask, local_subnets);
+                            if pkt_cast.is_unicast() && field_array[0] == 0xFF && field_array[1] == 0xFF
                             && field_array[2] == 0xFF && field_array[3] == 0xFF && field_array[4] == 0xFF && field_array[5] == 0xFF {
                                 TrafficType::Broadcast
                             }
@@ -124,92 +249,184 @@ impl NetflowSender {
                             }
                         },
                         _ =>  {
-                            handle_traffic_type_in_flow(src_ip, dst_ip)
+                            handle_traffic_type_in_flow(src_ip, dst_ip, dst_mask, local_subnets)
                         },
                     };
 
                     let current_time = Local::now();
-                    let mut updated_flow = false;
+                    let (first_switched, last_switched) = pkt.switched_times(current_time);
+                    let mut counted = true;
                     //look for existing flow and update
-                    for flow in &mut self.flow_stats {
-                        if is_flow_match(flow.src_ip, flow.dst_ip, src_ip, dst_ip,
-                             flow.src_port, flow.dst_port, src_port, dst_port) {
+                    match self.flow_stats.get_mut(&key) {
+                        Some(flow) => {
                                 //println!("updating existing flow");
-                                //first update the delta vec for the flow so we can have the correct value when we update db later
-                                //this separation is required to have both gui and cli displays
-                                
-                                let new_delta = NetFlowDelta {
-                                  updated_time: current_time,
-                                  in_octets: oct as i64,
-                                  in_pkts: pk as i64,
-                                  ..Default::default()
-                                };
-                                
-                                flow.deltas.push(new_delta);
-                                //add the delta here so we can display it in cli if required
-                                flow.update_throughput();
-                                flow.in_octets += oct;
-                                flow.in_packets += pk;
-                                updated_flow = true;
-                                flow.needs_db_update = true;
-                                break;
-                        }
+                                let is_forward = flow.is_forward(src_ip, src_port);
+                                flow.last_updated_time = current_time;
+                                //the observation points are always tracked so the flow knows its interfaces
+                                //with dedup on, a record from another observation point is a copy of packets we already counted
+                                let selected = flow.select_observation(is_forward, observation);
+                                counted = dedup_table.is_none() || selected != ObservationMatch::Other;
+                                if dedup_table.is_some() && selected == ObservationMatch::Replaced {
+                                    //the egress copies of these packets are already in the totals, take them back out
+                                    //a negative delta keeps the rollups in line with the flow
+                                    let (old_octets, old_packets) = flow.reset_direction(is_forward);
+                                    flow.deltas.push(NetFlowDelta {
+                                        updated_time: current_time,
+                                        in_octets: -(old_octets as i64),
+                                        in_pkts: -(old_packets as i64),
+                                        ..Default::default()
+                                    });
+                                }
+                                if counted {
+                                    //first update the delta vec for the flow so we can have the correct value when we update db later
+                                    //this separation is required to have both gui and cli displays
+                                    
+                                    let new_delta = NetFlowDelta {
+                                      updated_time: current_time,
+                                      in_octets: oct as i64,
+                                      in_pkts: pk as i64,
+                                      ..Default::default()
+                                    };
+                                    
+                                    flow.deltas.push(new_delta);
+                                    //add the delta here so we can display it in cli if required
+                                    flow.update_throughput(oct, pk, first_switched, last_switched);
+                                    if is_forward {
+                                        flow.add_forward_record(oct, pk, flags, first_switched, last_switched);
+                                    }
+                                    else {
+                                        flow.add_reverse_record(oct, pk, flags, first_switched, last_switched);
+                                    }
+                                    flow.orient_by_initiator();
+                                }
+                        },
+                        //no flow exists, create new
+                        None => {
+                            let mut new_flow = NetFlow {
+                                sender_ip: self.ip_addr,
+                                src_ip,
+                                dst_ip,
+                                src_port,
+                                dst_port,
+                                protocol: proto,
+                                src_vlan,
+                                dst_vlan,
+                                src_mask,
+                                dst_mask,
+                                vrf_id,
+                                //Need to handle optional variants
+                                in_octets: u64::from(oct),
+                                in_packets: u64::from(pk),
+                                rev_octets: 0,
+                                rev_packets: 0,
+                                tcp_flags: flags,
+                                rev_tcp_flags: 0,
+                                fwd_initial_syn: is_initial_syn(flags),
+                                rev_initial_syn: false,
+                                first_seen: first_switched,
+                                last_seen: last_switched,
+                                rev_first_seen: None,
+                                rev_last_seen: None,
+                                fwd_observation: Some(observation),
+                                rev_observation: None,
+                                dedup_primary: true,
+                                db_id: None,
+                                traffic_type: cast,
+                                created_time: current_time,
+                                last_updated_time: current_time,
+                                end_time: None,
+                                //the first record is a delta too so the delta table holds every record
+                                deltas: vec![NetFlowDelta {
+                                    updated_time: current_time,
+                                    in_octets: oct as i64,
+                                    in_pkts: pk as i64,
+                                    ..Default::default()
+                                }],
+                                rate_buckets: BTreeMap::new(),
+                                peak_bps: 0,
+                                peak_pps: 0,
+                            };
+                            new_flow.update_throughput(oct, pk, first_switched, last_switched);
+                            self.flow_stats.insert(key, new_flow);
+                        },
                     }
-
-                    //no flow exists, create new
-                    if !updated_flow {
-                        //println!("flow_stats is empty, creating new flow");
-                        let new_flow = NetFlow {
-                            src_ip,
-                            dst_ip,
-                            src_port,
-                            dst_port,
-                            protocol: proto,
-                            //Need to handle optional variants
-                            in_octets: oct,
-                            in_packets: pk,
-                            in_db: false,
-                            needs_db_update: true,
-                            traffic_type: cast,
-                            created_time: current_time,
-                            //delta starts empty if the flow is new, it grows when flow is updated in for loop above
-                            deltas: Vec::new(),
-                        };
-                        self.flow_stats.push(new_flow)
+                    if counted {
+                        add_to_interface_counters(&mut self.interface_counters, observation.input_snmp, observation.output_snmp, oct, pk, last_switched);
                     }
+                    //ownership is checked on every record so a flow can take over once the owner's flow closes
+                    if let Some(table) = dedup_table.as_deref_mut() {
+                        let is_primary = table.claim(&key);
+                        if let Some(flow) = self.flow_stats.get_mut(&key) {
+                            flow.dedup_primary = is_primary;
+                        }
+                    }
+                    self.dirty_flows.insert(key);
 
-                
                 // None => {
                 //     //println!("Can't parse empty packet in parse_stats_on_packet, skipping");
                 //     return;
                 // }
             
         }
+        record_count
     }
 
  
 
-    pub fn prepare_and_update_flow_in_db(&mut self, db_conn: &mut Arc<Mutex<Connection>>) {
-        let sender_ip: String = String::from(&self.ip_addr.to_string());
-        let mut db_conn_unlocked: MutexGuard<Connection> = db_conn.lock().unwrap();
-        for flow in &mut self.flow_stats {
-            // needs_db_update is required because this func and parse_packet_to_flow share overlap
-            // upstream to this call we are iterating over senders, which would dup flow stats and create dup updated_times in db
-            if flow.needs_db_update {
-                flow.in_db = check_if_flow_exists_in_db(&mut db_conn_unlocked, flow);
-                let current_time = Local::now();
-                if !flow.in_db {
-                    create_flow_in_db(&mut db_conn_unlocked, flow, &sender_ip, &current_time);
-                    flow.in_db = true;
-                    flow.needs_db_update = false;
-                }
-                else {
-                    update_flow_in_db(&mut db_conn_unlocked, flow, &sender_ip, &current_time);
-                    flow.needs_db_update = false;
-                }
+    pub fn has_pending_writes(&self) -> bool {
+        !self.dirty_flows.is_empty() || !self.interface_counters.is_empty() || !self.counters.is_empty()
+    }
+
+    //only the flows in dirty_flows are written, so the cost follows the number of records received
+    //the flows are copied with their deltas for the writer, then the deltas are cleared here
+    pub fn take_pending_writes(&mut self) -> Vec<WriteOp> {
+        let mut writes = Vec::new();
+        if !self.counters.is_empty() {
+            writes.push(WriteOp::SenderCounters {
+                sender_ip: self.ip_addr,
+                counters: std::mem::take(&mut self.counters),
+                last_seen: self.last_seen,
+                last_template: self.last_template,
+            });
+        }
+        if self.dirty_flows.is_empty() && self.interface_counters.is_empty() {
+            return writes;
+        }
+        let interface_counters = self.interface_counters.drain().collect();
+        let mut flows = Vec::with_capacity(self.dirty_flows.len());
+        for key in self.dirty_flows.drain() {
+            if let Some(flow) = self.flow_stats.get_mut(&key) {
+                flows.push(flow.clone());
+                flow.clear_deltas();
             }
-            
         }
+        writes.push(WriteOp::Flows { sender_ip: self.ip_addr, flows, interface_counters });
+        writes
+    }
+
+    pub fn has_expired_flows(&self, current_time: DateTime<Local>, active_timeout_sec: i64, inactive_timeout_sec: i64) -> bool {
+        self.flow_stats.values()
+            .any(|flow| flow.is_expired(current_time, active_timeout_sec, inactive_timeout_sec))
+    }
+
+    //removes the flows that hit the active or inactive timeout and returns them so the writer can close them
+    //their last records have to be taken with take_pending_writes first
+    pub fn take_expired_flows(&mut self, current_time: DateTime<Local>, active_timeout_sec: i64, inactive_timeout_sec: i64, mut dedup_table: Option<&mut DedupTable>) -> Vec<NetFlow> {
+        let mut expired_flows = Vec::new();
+        self.flow_stats.retain(|key, flow| {
+            if flow.is_expired(current_time, active_timeout_sec, inactive_timeout_sec) {
+                flow.end_time = Some(current_time);
+                if let Some(table) = dedup_table.as_deref_mut() {
+                    table.release(key);
+                }
+                expired_flows.push(flow.clone());
+                false
+            }
+            else {
+                true
+            }
+        });
+        expired_flows
     }
 }
 
@@ -1,110 +1,379 @@
-use std::net::{UdpSocket, SocketAddr};
+use std::net::UdpSocket;
 use std::net::Ipv4Addr;
 use std::convert::TryInto;
 use std::io::{Error,ErrorKind};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use rusqlite::Connection;
+use log::{error, info, warn};
+use chrono::prelude::*;
 
+use crate::access::ExporterAccess;
+use crate::dedup::*;
+use crate::events::*;
 use crate::fields::*;
+use crate::interfaces::*;
+use crate::pipeline::*;
+use crate::replication::Replicator;
 use crate::senders::*;
 use crate::settings::ServerSettings;
+use crate::sockets::*;
 use crate::templates::*;
 use crate::utils::*;
-use crate::sql::*;
 
 
+//the receive stage, see pipeline.rs for how the stages fit together
 pub struct NetflowServer {
-    pub initial_template_received: bool,
-    pub socket: UdpSocket,
-    pub receive_buffer: [u8; 2500],
-    byte_count: usize,
-    // //may need to move these around as a tuple for multiple netflow senders
-    // pub byte_count: usize,
-    // pub source_address: SocketAddr,
-    pub senders: Vec<NetflowSender>,
-    pub db_conn: Arc<Mutex<Connection>>
+    //more than one with receive_sockets, all bound to the same port
+    pub sockets: Vec<UdpSocket>,
+    pub db_conn: Arc<Mutex<Connection>>,
+    pub settings: ServerSettings,
+    //registered before run, then moved to the dispatcher thread
+    subscribers: Vec<Box<dyn FlowSubscriber>>,
+    events: Option<FlowEvents>,
+    stats: Arc<PipelineStats>,
+    drop_warnings: DropWarnings,
+    //set by ServerHandle::shutdown
+    stop: Arc<AtomicBool>,
 }
 
 
 
 impl NetflowServer {
-    pub fn new(addr_and_port: String, db_conn_srv: Arc<Mutex<Connection>>) -> Self {
+    pub fn new(addr_and_port: String, db_conn_srv: Arc<Mutex<Connection>>, server_settings: ServerSettings) -> Self {
+        let sockets = bind_receive_sockets(&addr_and_port, &server_settings)
+            .expect("Unable to bind socket");
+        for socket in &sockets {
+            //wake up periodically so a stop is noticed even when no packets are arriving
+            socket.set_read_timeout(Some(Duration::from_secs(1)))
+                .expect("Unable to set socket read timeout");
+        }
+        let kernel_drops = KernelDrops::new(&sockets);
         NetflowServer {
+            sockets,
+            db_conn: db_conn_srv,
+            settings: server_settings,
+            subscribers: Vec::new(),
+            events: None,
+            stats: Arc::new(PipelineStats::default()),
+            drop_warnings: DropWarnings::new(kernel_drops),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    //subscribers have to be added before run is called
+    pub fn subscribe(&mut self, subscriber: Box<dyn FlowSubscriber>) {
+        self.subscribers.push(subscriber);
+    }
+
+    //events lost because the subscribers couldn't keep up
+    pub fn dropped_events(&self) -> u64 {
+        self.events.as_ref().map_or(0, |e| e.dropped())
+    }
+
+    //queue depths and drop counters, keep a clone before calling run to read them while it's running
+    pub fn pipeline_stats(&self) -> Arc<PipelineStats> {
+        Arc::clone(&self.stats)
+    }
+
+    //runs the server on its own thread, the handle is the only way to stop it
+    pub fn spawn(mut self) -> ServerHandle {
+        let stop = Arc::clone(&self.stop);
+        let stats = self.pipeline_stats();
+        let thread = thread::spawn(move || {
+            self.run();
+        });
+        ServerHandle {
+            stop,
+            thread,
+            stats,
+        }
+    }
+
+    //blocks until the server is stopped through the handle from spawn
+    pub fn run(&mut self) {
+        let subscribers = std::mem::take(&mut self.subscribers);
+        let pipeline = start_pipeline(&self.db_conn, &self.settings, subscribers, &self.stats);
+        self.events = pipeline.events.clone();
+
+        let receive_buffer_size = get_receive_buffer_size(&self.settings);
+        thread::scope(|scope| {
+            for socket in &self.sockets {
+                let (decoders, access, replicator) = (&pipeline.decoders, &pipeline.access, &pipeline.replicator);
+                let (stats, stop) = (&self.stats, &self.stop);
+                scope.spawn(move || {
+                    receive_datagrams(socket, receive_buffer_size, decoders, access, replicator, stats, stop);
+                });
+            }
+            while !self.stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_secs(1));
+                self.drop_warnings.check(&self.stats, self.events.as_ref());
+            }
+        });
+
+        self.events = None;
+        pipeline.stop();
+        info!("Netflow server stopped");
+    }
+
+    pub fn update_interface_names_from_config(&mut self) {
+        update_interface_names_from_config(&self.db_conn.lock().unwrap(), &self.settings);
+    }
+
+}
+
+
+//the receive stage, one per socket, only copies datagrams out of the socket so it keeps up while the db is busy
+fn receive_datagrams(socket: &UdpSocket, receive_buffer_size: usize, decoders: &[SyncSender<Datagram>], access: &ExporterAccess, replicator: &Replicator, stats: &PipelineStats, stop: &AtomicBool) {
+    let mut receive_buffer = vec![0u8; receive_buffer_size];
+    while !stop.load(Ordering::Relaxed) {
+        //an error is the read timeout expiring without a packet
+        if let Ok((byte_count, source)) = socket.recv_from(&mut receive_buffer) {
+            dispatch_datagram(&receive_buffer, byte_count, source, decoders, access, replicator, stats);
+        }
+    }
+}
+
+
+pub struct ServerHandle {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    stats: Arc<PipelineStats>,
+}
+
+impl ServerHandle {
+    pub fn pipeline_stats(&self) -> Arc<PipelineStats> {
+        Arc::clone(&self.stats)
+    }
+
+    //the receive loop sees the stop within its one second read timeout,
+    //returns once everything received is written, the open flows are closed and the in memory db is snapshotted
+    pub fn shutdown(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.thread.join().is_err() {
+            error!("The netflow server thread panicked");
+        }
+    }
+}
+
+
+//the decode stage, one per shard, owns the templates and flows of the exporters sent to its shard
+pub struct Decoder {
+    pub initial_template_received: bool,
+    pub receive_buffer: Vec<u8>,
+    byte_count: usize,
+    pub senders: Vec<NetflowSender>,
+    pub settings: ServerSettings,
+    //shared with the other decoders
+    pub dedup_table: Option<Arc<Mutex<DedupTable>>>,
+    last_aging_check: Instant,
+    events: Option<FlowEvents>,
+    writes: SyncSender<Vec<WriteOp>>,
+    pending_writes: Vec<WriteOp>,
+    //records parsed since the last flush to the writer
+    pending_records: usize,
+    last_flush: Instant,
+    stats: Arc<PipelineStats>,
+}
+
+impl Decoder {
+    pub fn new(settings: ServerSettings, dedup_table: Option<Arc<Mutex<DedupTable>>>, events: Option<FlowEvents>, writes: SyncSender<Vec<WriteOp>>, stats: Arc<PipelineStats>) -> Self {
+        Decoder {
             initial_template_received: false,
-            socket: UdpSocket::bind(addr_and_port)
-                .expect("Unable to bind socket"),
-            receive_buffer: [0; 2500],
+            receive_buffer: Vec::new(),
             byte_count: 0,
             senders: Vec::new(),
-            db_conn: db_conn_srv
+            settings,
+            dedup_table,
+            last_aging_check: Instant::now(),
+            events,
+            writes,
+            pending_writes: Vec::new(),
+            pending_records: 0,
+            last_flush: Instant::now(),
+            stats,
         }
     }
 
-    pub fn run(&mut self) {
-        let source_address =  self.wait_for_initial_template();
-        let template: NetflowTemplate = self.parse_flow_template();
-        self.update_or_create_sender(source_address, template);
+    fn send_event(&self, event: impl FnOnce() -> FlowEvent) {
+        if let Some(events) = &self.events {
+            events.send(event());
+        }
+    }
 
+    //wakes up periodically so flows can be aged out and batches written even when no packets are arriving
+    pub fn run(&mut self, datagrams: &Receiver<Datagram>) {
+        let wait = Duration::from_millis(self.settings.write_batch_interval_ms.clamp(1, 1000));
         loop {
-            let source_address = self.start_receiving();
-            let sender_ip = convert_socket_to_ipv4(source_address);
-            let packet_type = self.determine_packet_type();
-            match packet_type {
-                PacketType::Template => {
-                    let template: NetflowTemplate = self.parse_flow_template();
-                    self.update_or_create_sender(source_address, template);
+            match datagrams.recv_timeout(wait) {
+                Ok(datagram) => {
+                    self.stats.datagram_decoded();
+                    self.decode_datagram(datagram);
                 },
-                PacketType::Data => {
-                    let sender_index_result =  self.match_sender(sender_ip);
-                    let sender_index = match sender_index_result {
-                        Ok(o) => o,
-                        Err(e) => {
-                            continue;
-                        }
-                    };
-                    self.parse_data_to_packet(self.byte_count, sender_index);
-                    //let test: &mut NetflowSender = &mut self.senders[0];
-                    let senders_len = self.senders.len();
-         
-                    //re-enabled these functions so that I can store the data in sqlite and query them in cli.rs
-                    for x in 0..senders_len {
-                        self.senders[x].parse_packet_to_flow();
-                        self.senders[x].prepare_and_update_flow_in_db(&mut self.db_conn);
-                    }
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush_to_db();
+                    return;
                 },
             }
-            
+            self.flush_to_db_if_due();
+            if self.last_aging_check.elapsed() >= Duration::from_secs(1) {
+                self.last_aging_check = Instant::now();
+                self.age_flows();
+                self.check_silent_senders();
+            }
         }
     }
 
-    pub fn update_or_create_sender(&mut self, source_address: SocketAddr, template: NetflowTemplate) {
-        //check if sender exists
-        //create sender and add to vec
-        let mut found_sender = false;
-        let new_sender_ip = convert_socket_to_ipv4(source_address);
-        let vec_len = self.senders.len();
-        for x in 0..vec_len {
-            if self.senders[x].ip_addr == new_sender_ip {
-                //println!("Found the source in the senders vector");
-                found_sender = true;
-                break;
+    //records from an exporter are skipped until its template has been received
+    pub fn decode_datagram(&mut self, datagram: Datagram) {
+        let sender_ip = convert_socket_to_ipv4(datagram.source);
+        self.byte_count = datagram.bytes.len();
+        self.receive_buffer = datagram.bytes;
+        let sender_index = self.match_or_create_sender(sender_ip);
+        self.update_sender_seen(sender_index);
+
+        //the header and the id and length of the first flowset
+        if self.byte_count < 24 {
+            self.senders[sender_index].counters.parse_errors += 1;
+            return;
+        }
+        //the header counts every record in the datagram, templates included
+        let record_count = usize::from(self.parse_record_count());
+        let mut records_handled = 0;
+
+        if let PacketType::Template = self.determine_packet_type() {
+            match self.parse_flow_template() {
+                Some(template) => {
+                    self.send_event(|| FlowEvent::Template(sender_ip, template.clone()));
+                    self.update_template(sender_index, template);
+                    records_handled += 1;
+                },
+                None => {
+                    self.senders[sender_index].counters.parse_errors += 1;
+                },
             }
         }
-        if !found_sender {
-            let ip_as_str = convert_ipv4_to_string(new_sender_ip);
-            update_senders_in_db(&mut self.db_conn, ip_as_str.as_str());
-            let new_sender = NetflowSender {
-                ip_addr: new_sender_ip,
-                active_template: template,
-                flow_packets: Vec::new(),
-                flow_stats: Vec::new(),
-            };
-            self.senders.push(new_sender);
+        //the options and the data are often sent in the same datagram as the template
+        records_handled += self.parse_options_flowsets(sender_index);
+        if self.senders[sender_index].active_template.id.is_some() {
+            records_handled += self.parse_data_to_packet(self.byte_count, sender_index);
+
+            //only the sender that sent this datagram has new records to process
+            let mut dedup_table = self.dedup_table.as_ref().map(|t| t.lock().unwrap());
+            self.pending_records += self.senders[sender_index].parse_packet_to_flow(dedup_table.as_deref_mut(), &self.settings.local_subnets);
         }
+        self.senders[sender_index].counters.records_skipped += record_count.saturating_sub(records_handled) as u64;
     }
 
-    
+    fn update_sender_seen(&mut self, sender_index: usize) {
+        let sender = &mut self.senders[sender_index];
+        sender.counters.packets += 1;
+        sender.counters.bytes += self.byte_count as u64;
+        sender.last_seen = Local::now();
+        if sender.silent {
+            sender.silent = false;
+            let sender_ip = sender.ip_addr;
+            info!("Exporter {} is sending again", sender_ip);
+            self.send_event(|| FlowEvent::ExporterResumed(sender_ip));
+        }
+    }
+
+    fn flush_to_db_if_due(&mut self) {
+        if self.pending_records >= self.settings.write_batch_records
+            || self.last_flush.elapsed() >= Duration::from_millis(self.settings.write_batch_interval_ms) {
+            self.flush_to_db();
+        }
+    }
+
+    //everything parsed since the last flush goes to the writer as one batch
+    pub fn flush_to_db(&mut self) {
+        self.pending_records = 0;
+        self.last_flush = Instant::now();
+        for sender in &mut self.senders {
+            self.pending_writes.extend(sender.take_pending_writes());
+        }
+        if self.pending_writes.is_empty() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.pending_writes);
+        if let Err(e) = send_write_batch(&self.writes, batch, &self.stats) {
+            error!("The db writer thread stopped, {} writes were lost", e.0.len());
+        }
+    }
+
+    //called about once a second from run
+    pub fn age_flows(&mut self) {
+        let current_time = Local::now();
+        let (active_timeout_sec, inactive_timeout_sec) = (self.settings.active_timeout_sec, self.settings.inactive_timeout_sec);
+        if !self.senders.iter().any(|sender| sender.has_expired_flows(current_time, active_timeout_sec, inactive_timeout_sec)) {
+            return;
+        }
+        //make sure the last records of the expiring flows are written before closing them
+        for sender in &mut self.senders {
+            self.pending_writes.extend(sender.take_pending_writes());
+        }
+        let mut expired_flows = Vec::new();
+        {
+            let mut dedup_table = self.dedup_table.as_ref().map(|t| t.lock().unwrap());
+            for sender in &mut self.senders {
+                expired_flows.append(&mut sender.take_expired_flows(current_time, active_timeout_sec, inactive_timeout_sec, dedup_table.as_deref_mut()));
+            }
+        }
+        self.pending_writes.push(WriteOp::CloseFlows { flows: expired_flows, end_time: current_time });
+        self.flush_to_db();
+    }
+
+    //also called about once a second from run, the alert fires once until the exporter sends again
+    pub fn check_silent_senders(&mut self) {
+        let current_time = Local::now();
+        let mut silent_senders = Vec::new();
+        for sender in &mut self.senders {
+            let expected_interval_sec = self.settings.get_expected_interval_sec(sender.ip_addr);
+            let quiet_sec = (current_time - sender.last_seen).num_seconds();
+            if expected_interval_sec > 0 && !sender.silent && quiet_sec > expected_interval_sec {
+                sender.silent = true;
+                warn!("Exporter {} has sent nothing for {} sec, it's expected to send at least every {} sec",
+                    sender.ip_addr, quiet_sec, expected_interval_sec);
+                silent_senders.push((sender.ip_addr, sender.last_seen));
+            }
+        }
+        for (sender_ip, last_seen) in silent_senders {
+            self.send_event(|| FlowEvent::ExporterSilent(sender_ip, last_seen));
+        }
+    }
+
+    //an exporter is added on its first datagram, even if it's not a template
+    pub fn match_or_create_sender(&mut self, sender_ip: Ipv4Addr) -> usize {
+        if let Ok(sender_index) = self.match_sender(sender_ip) {
+            return sender_index;
+        }
+        self.pending_writes.push(WriteOp::Sender(sender_ip));
+        self.send_event(|| FlowEvent::ExporterAdded(sender_ip));
+        self.senders.push(NetflowSender::new(sender_ip));
+        self.senders.len() - 1
+    }
+
+    //exporters resend their template on a fixed interval, a resent template replaces the active one
+    //so a changed flow record is picked up, a template with another id is ignored like before
+    pub fn update_template(&mut self, sender_index: usize, template: NetflowTemplate) {
+        let sender = &mut self.senders[sender_index];
+        if sender.active_template.id.is_some() && sender.active_template.id != template.id {
+            return;
+        }
+        if sender.last_template.is_some() {
+            sender.counters.template_refreshes += 1;
+        }
+        else if self.settings.dedup_flows && !template.order_vec.iter().any(|f| matches!(f, FlowField::Direction)) {
+            warn!("Exporter {} doesn't send the DIRECTION field, dedup_flows can't tell its ingress and egress records apart", sender.ip_addr);
+        }
+        sender.last_template = Some(Local::now());
+        sender.active_template = template;
+    }
 
     fn decode_field_order(&self, field_id: u16, received_template: &mut NetflowTemplate) {
         match field_id {
@@ -184,12 +453,30 @@ impl NetflowServer {
                 //received_template.ipv4_next_hop = Some(Ipv4Field::Enabled(order));
                 received_template.order_vec.push(FlowField::NextHop);
             },
+            21 => {
+                received_template.order_vec.push(FlowField::LastSwitched);
+            },
+            22 => {
+                received_template.order_vec.push(FlowField::FirstSwitched);
+            },
+            58 => {
+                received_template.order_vec.push(FlowField::SrcVLAN);
+            },
+            59 => {
+                received_template.order_vec.push(FlowField::DstVLAN);
+            },
+            61 => {
+                received_template.order_vec.push(FlowField::Direction);
+            },
             80 => {
                 received_template.order_vec.push(FlowField::InDstMac);
             },
             81 => {
                 received_template.order_vec.push(FlowField::OutSrcMac);
             },
+            234 => {
+                received_template.order_vec.push(FlowField::IngressVRFID);
+            },
             _ => {
                 //println!("Unsure of the field id {field_id}");
             },
@@ -242,12 +529,30 @@ impl NetflowServer {
             FlowField::NextHop => {
                 FlowField::NextHop
             },
+            FlowField::LastSwitched => {
+                FlowField::LastSwitched
+            },
+            FlowField::FirstSwitched => {
+                FlowField::FirstSwitched
+            },
+            FlowField::SrcVLAN => {
+                FlowField::SrcVLAN
+            },
+            FlowField::DstVLAN => {
+                FlowField::DstVLAN
+            },
+            FlowField::Direction => {
+                FlowField::Direction
+            },
             FlowField::InDstMac => {
                 FlowField::InDstMac
             },
             FlowField::OutSrcMac => {
                 FlowField::OutSrcMac
             },
+            FlowField::IngressVRFID => {
+                FlowField::IngressVRFID
+            },
             _ => {
                 //println!("Unsure of the field in get_field_type");
                 FlowField::None
@@ -302,12 +607,30 @@ impl NetflowServer {
             FlowField::NextHop => {
                 4
             },
+            FlowField::LastSwitched => {
+                4
+            },
+            FlowField::FirstSwitched => {
+                4
+            },
+            FlowField::SrcVLAN => {
+                2
+            },
+            FlowField::DstVLAN => {
+                2
+            },
+            FlowField::Direction => {
+                1
+            },
             FlowField::InDstMac => {
                 6
             },
             FlowField::OutSrcMac => {
                 6
             },
+            FlowField::IngressVRFID => {
+                4
+            },
             _ => {
                 //println!("Unsure of the field size in get_field_size");
                 0
@@ -407,6 +730,11 @@ impl NetflowServer {
                 //println!("The field is SrcAddr and the converted payload is {}", field_data_ipv4);
                 new_packet.next_hop = Some(Ipv4Field::Value(field_data_ipv4));
             },
+            FlowField::Direction => {
+                let field_array: [u8; 1] = field_slice.try_into().expect("Unable to convert field_slice to array");
+                let field_data = u8::from_be_bytes(field_array);
+                new_packet.direction = Some(U8Field::Value(field_data));
+            },
             FlowField::InDstMac => {
                 let field_array: [u8; 6] = field_slice.try_into().expect("Unable to convert field_slice to array");
                 let mut field_array_64: [u8; 8] = [0; 8];
@@ -421,20 +749,37 @@ impl NetflowServer {
                 let field_data: u64 = u64::from_be_bytes(field_array_64);
                 new_packet.out_src_mac = Some(U64Field::Value(field_data));
             },
+            FlowField::LastSwitched => {
+                let field_array: [u8; 4] = field_slice.try_into().expect("Unable to convert field_slice to array");
+                let field_data = u32::from_be_bytes(field_array);
+                new_packet.last_switched = Some(U32Field::Value(field_data));
+            },
+            FlowField::FirstSwitched => {
+                let field_array: [u8; 4] = field_slice.try_into().expect("Unable to convert field_slice to array");
+                let field_data = u32::from_be_bytes(field_array);
+                new_packet.first_switched = Some(U32Field::Value(field_data));
+            },
+            FlowField::SrcVLAN => {
+                let field_array: [u8; 2] = field_slice.try_into().expect("Unable to convert field_slice to array");
+                let field_data = u16::from_be_bytes(field_array);
+                new_packet.src_vlan = Some(U16Field::Value(field_data));
+            },
+            FlowField::DstVLAN => {
+                let field_array: [u8; 2] = field_slice.try_into().expect("Unable to convert field_slice to array");
+                let field_data = u16::from_be_bytes(field_array);
+                new_packet.dst_vlan = Some(U16Field::Value(field_data));
+            },
+            FlowField::IngressVRFID => {
+                let field_array: [u8; 4] = field_slice.try_into().expect("Unable to convert field_slice to array");
+                let field_data = u32::from_be_bytes(field_array);
+                new_packet.ingress_vrf_id = Some(U32Field::Value(field_data));
+            },
             _ => {
                 //println!("Unsure of the field in get_field_type");
             },
         }
     }
 
-    pub fn start_receiving(&mut self) -> (SocketAddr) {
-        let (byte_count, socket) = self.socket.recv_from(&mut self.receive_buffer)
-            .expect("Error receiving from the socket");
-        self.byte_count = byte_count;
-        socket
-    }
-
-
     // pub fn build_netflow_packet(&self, byte_count: usize) -> NetflowPacket {
     //     netflow_packet = self.parse_packet_header();
     // }
@@ -480,7 +825,13 @@ impl NetflowServer {
     }
 
 
-    pub fn parse_flow_template(&mut self) -> NetflowTemplate {
+    pub fn parse_record_count(&self) -> u16 {
+        //record count in the header, templates and options records are counted too
+        u16::from_be_bytes([self.receive_buffer[2], self.receive_buffer[3]])
+    }
+
+    //None when the template runs past the end of the datagram
+    pub fn parse_flow_template(&mut self) -> Option<NetflowTemplate> {
         //if flowset id == 0, it's a template
         //the udp receive func starts us at byte 30 because that's the udp payload
         //byte 20 and 21 in the payload are the flowset ID,
@@ -489,6 +840,11 @@ impl NetflowServer {
         let message: &[u8]  = &self.receive_buffer[..self.byte_count];
         //println!("Parsing...");
 
+        //the template header ends at byte 28, nothing can be read from a shorter datagram
+        if message.len() < 28 {
+            return None;
+        }
+
         let mut received_template = NetflowTemplate::default();
         //flowset length
         self.parse_flow_length(message);
@@ -501,13 +857,16 @@ impl NetflowServer {
     
         //field count
         let field_count = self.parse_flow_field_count(message);
+        if 28 + 4 * usize::from(field_count) > message.len() {
+            return None;
+        }
         //save the field count so we can easily iterate later
         received_template.field_count = Some(field_count);
 
         let mut start_slice: usize = 28;
         let mut end_slice: usize = 30;
         let inc_size: usize = 4;
-        for x in 0..field_count  {
+        for _x in 0..field_count  {
             let field_slice: &[u8]  = &message[start_slice..end_slice];
             let field_array: [u8; 2] = field_slice.try_into().expect("Unable to convert field_slice to array");
             let field_data: u16 = u16::from_be_bytes(field_array);
@@ -519,67 +878,189 @@ impl NetflowServer {
 
         self.initial_template_received = true;
 
-        received_template
+        Some(received_template)
 
     }
 
 
 
 
-    pub fn parse_data_to_packet(&mut self, byte_count: usize, sender_index: usize) {
+    //returns the number of records decoded, anything malformed is counted as a parse error
+    pub fn parse_data_to_packet(&mut self, byte_count: usize, sender_index: usize) -> usize {
         let message: &[u8]  = &self.receive_buffer[..byte_count];
         //println!("Parsing...");
 
-        //flowset length
-        self.parse_flow_length(message);
-     
-        //let sender: &mut NetflowSender = &mut self.senders[sender_index];
-
-        //template id
-        let template_id = self.parse_flow_template_id_from_data(message);
-        if template_id != self.senders[sender_index].active_template.id.expect("sender.active_template.id is None") {
-            //println!("The flow data template_id does not match the sender.active_template.id");
-            return;
-        }
+        let active_template = &self.senders[sender_index].active_template;
+        let template_id = active_template.id.expect("sender.active_template.id is None");
 
         //field count
-        let field_count = self.senders[sender_index].active_template.field_count.unwrap();
-
-        //data can be parsed
-        let mut start_slice: usize = 24;
-        //get the intial size of the first field
-        let mut field_type = self.get_field_type(self.senders[sender_index].active_template.order_vec[0]);
-        let mut inc_size: usize = self.get_field_size(self.senders[sender_index].active_template.order_vec[0]);
-        let mut end_slice: usize = start_slice + inc_size;
-        let mut initial_field_parsed = false;
-        let vec_len: u16 = self.senders[sender_index].active_template.order_vec.len().try_into().unwrap();
+        let field_count = active_template.field_count.unwrap();
+        let vec_len: u16 = active_template.order_vec.len().try_into().unwrap();
         if field_count != vec_len {
             //println!("The order_vec length is not equal to the field_count, cannot parse or else we'll crash");
-            return;
+            self.senders[sender_index].counters.parse_errors += 1;
+            return 0;
+        }
+
+        //every record in a flowset has the same size, so work it out once from the template
+        let record_size: usize = active_template.order_vec.iter()
+            .map(|f| self.get_field_size(*f))
+            .sum();
+        if record_size == 0 {
+            self.senders[sender_index].counters.parse_errors += 1;
+            return 0;
         }
 
-        let mut new_packet: NetflowTemplate = NetflowTemplate::default();
+        //the switched times in each record are relative to the exporter uptime in the header
+        let sys_uptime = u32::from_be_bytes(message[4..8].try_into().expect("Unable to convert sys_uptime slice to array"));
+        let unix_secs = u32::from_be_bytes(message[8..12].try_into().expect("Unable to convert unix_secs slice to array"));
+
+        let mut new_packets: Vec<NetflowTemplate> = Vec::new();
+        let mut parse_errors: u64 = 0;
 
-        let field_count_size: usize = field_count.into();
-        for x in 0..field_count_size  {
-            //this runs after the first iteration because we already incremented before the for loop
-            if initial_field_parsed {
-                //update size per field
-                field_type = self.get_field_type(self.senders[sender_index].active_template.order_vec[x]);
-                inc_size = self.get_field_size(self.senders[sender_index].active_template.order_vec[x]);
-                start_slice = end_slice;
-                end_slice  += inc_size;
+        //a datagram can hold several flowsets after the 20 byte header, each with several records
+        let mut flowset_start: usize = 20;
+        while flowset_start + 4 <= message.len() {
+            let flowset_id = u16::from_be_bytes([message[flowset_start], message[flowset_start + 1]]);
+            let flowset_len: usize = u16::from_be_bytes([message[flowset_start + 2], message[flowset_start + 3]]).into();
+            if flowset_len < 4 {
+                parse_errors += 1;
+                break;
             }
- 
-            let field_slice: &[u8]  = &message[start_slice..end_slice];
-            self.set_field_value(field_type, &mut new_packet, field_slice);
+            //a truncated flowset still has its complete records decoded
+            if flowset_start + flowset_len > message.len() {
+                parse_errors += 1;
+            }
+            let flowset_end = (flowset_start + flowset_len).min(message.len());
 
-            if !initial_field_parsed {
-                initial_field_parsed = true;
+            if flowset_id != template_id {
+                //println!("The flow data template_id does not match the sender.active_template.id");
+                flowset_start += flowset_len;
+                continue;
             }
+
+            //anything left over that is smaller than a record is padding
+            let mut start_slice: usize = flowset_start + 4;
+            while start_slice + record_size <= flowset_end {
+                let mut new_packet: NetflowTemplate = NetflowTemplate {
+                    sys_uptime: Some(U32Field::Value(sys_uptime)),
+                    unix_secs: Some(U32Field::Value(unix_secs)),
+                    ..Default::default()
+                };
+                for flow_field in &active_template.order_vec {
+                    let field_type = self.get_field_type(*flow_field);
+                    let end_slice = start_slice + self.get_field_size(*flow_field);
+                    let field_slice: &[u8]  = &message[start_slice..end_slice];
+                    self.set_field_value(field_type, &mut new_packet, field_slice);
+                    start_slice = end_slice;
+                }
+                new_packets.push(new_packet);
+            }
+
+            flowset_start += flowset_len;
         }
-        self.senders[sender_index].flow_packets.push(new_packet);
 
+        if let Some(events) = &self.events {
+            let sender_ip = self.senders[sender_index].ip_addr;
+            for record in &new_packets {
+                events.send(FlowEvent::Record(sender_ip, record.clone()));
+            }
+        }
+        let decoded = new_packets.len();
+        let sender = &mut self.senders[sender_index];
+        sender.counters.parse_errors += parse_errors;
+        sender.counters.records_decoded += decoded as u64;
+        sender.flow_packets.append(&mut new_packets);
+        decoded
+    }
+
+
+    //options templates have flowset id 1, the options data uses the id from the options template
+    //only the interface table is used, to name the snmp indexes
+    //returns the number of options templates and records, so they aren't counted as skipped
+    pub fn parse_options_flowsets(&mut self, sender_index: usize) -> usize {
+        let message: &[u8]  = &self.receive_buffer[..self.byte_count];
+        let sender_ip = self.senders[sender_index].ip_addr;
+        let mut records_handled = 0;
+
+        let mut flowset_start: usize = 20;
+        while flowset_start + 4 <= message.len() {
+            let flowset_id = u16::from_be_bytes([message[flowset_start], message[flowset_start + 1]]);
+            let flowset_len: usize = u16::from_be_bytes([message[flowset_start + 2], message[flowset_start + 3]]).into();
+            if flowset_len < 4 {
+                break;
+            }
+            let flowset_end = (flowset_start + flowset_len).min(message.len());
+
+            if flowset_id == 1 && flowset_start + 10 <= flowset_end {
+                let read_u16 = |i: usize| u16::from_be_bytes([message[i], message[i + 1]]);
+                let mut options_template = OptionsTemplate {
+                    id: read_u16(flowset_start + 4),
+                    ..Default::default()
+                };
+                //the scope and option lengths are in bytes, each field is a type and a length
+                let scope_len = usize::from(read_u16(flowset_start + 6));
+                let option_len = usize::from(read_u16(flowset_start + 8));
+                let fields_start = flowset_start + 10;
+                let scope_end = fields_start + scope_len;
+                let option_end = scope_end + option_len;
+                if scope_len % 4 != 0 || option_len % 4 != 0 || option_end > flowset_end {
+                    self.senders[sender_index].counters.parse_errors += 1;
+                }
+                else {
+                    let mut i = fields_start;
+                    while i + 4 <= scope_end {
+                        options_template.scope_fields.push((read_u16(i), read_u16(i + 2)));
+                        i += 4;
+                    }
+                    while i + 4 <= option_end {
+                        options_template.option_fields.push((read_u16(i), read_u16(i + 2)));
+                        i += 4;
+                    }
+                    self.senders[sender_index].options_template = Some(options_template);
+                    records_handled += 1;
+                }
+            }
+            else if let Some(options_template) = &self.senders[sender_index].options_template {
+                let record_size = options_template.record_size();
+                if flowset_id == options_template.id && record_size > 0 {
+                    let mut start_slice: usize = flowset_start + 4;
+                    while start_slice + record_size <= flowset_end {
+                        let mut snmp_index: Option<u32> = None;
+                        let mut name = String::new();
+                        let mut description = String::new();
+                        let scope_fields = options_template.scope_fields.iter().map(|(t, len)| (true, *t, usize::from(*len)));
+                        let option_fields = options_template.option_fields.iter().map(|(t, len)| (false, *t, usize::from(*len)));
+                        for (is_scope, field_type, field_len) in scope_fields.chain(option_fields) {
+                            let field_slice: &[u8] = &message[start_slice..start_slice + field_len];
+                            match (field_type, is_scope) {
+                                //scope type 2 is the interface, field 10 is INPUT_SNMP
+                                (2, true) | (10, false) if field_len <= 4 => {
+                                    snmp_index = Some(field_slice.iter().fold(0u32, |acc, b| (acc << 8) | u32::from(*b)));
+                                },
+                                (82, false) => {
+                                    name = String::from_utf8_lossy(field_slice).trim_end_matches('\0').trim().to_string();
+                                },
+                                (83, false) => {
+                                    description = String::from_utf8_lossy(field_slice).trim_end_matches('\0').trim().to_string();
+                                },
+                                _ => {},
+                            }
+                            start_slice += field_len;
+                        }
+                        records_handled += 1;
+                        if let Some(snmp_index) = snmp_index {
+                            if !name.is_empty() {
+                                self.pending_writes.push(WriteOp::InterfaceName { sender_ip, snmp_index, name, description });
+                            }
+                        }
+                    }
+                }
+            }
+
+            flowset_start += flowset_len;
+        }
+        records_handled
     }
 
 
@@ -595,61 +1076,6 @@ impl NetflowServer {
         
     }
 
-    pub fn wait_for_initial_template(&mut self) -> SocketAddr  {
-
-        //need initial template data
-        loop {
-            let source_address = self.start_receiving();
-            match check_packet_size(self.byte_count) {
-                Ok(x) => {
-                    //println!("The packet size is valid");
-                },
-                Err(e) => {
-                    //println!("The packet size too small, skipping this packet");
-                    continue;
-                }
-            }
-            
-            let received_message: &[u8]  = &self.receive_buffer[..self.byte_count];
-            //byte 20 and 21 being zeroed means this is a template
-            if received_message[20] == 0 && received_message[21] == 0 {
-                //println!("Received data is a flow template");
-                return source_address;
-            }
-            else {
-                //println!("Received data is not a flow template, waiting for template");
-            }
-        }
-      
-        //account for template changing
-    }
-
-    pub fn wait_for_netflow_data(&mut self) -> SocketAddr {
-         //need initial template data
-         loop {
-            let source_address = self.start_receiving();
-            match check_packet_size(self.byte_count) {
-                Ok(x) => {
-                    //println!("The packet size is valid");
-                },
-                Err(e) => {
-                    //println!("The packet size too small, skipping this packet");
-                    continue;
-                }
-            }
-            
-            let received_message: &[u8]  = &self.receive_buffer[..self.byte_count];
-            //byte 20 and 21 being zeroed means this is a template
-            if received_message[20] != 0 && received_message[21] != 0 {
-                //println!("Received data is a netflow data");
-                return source_address;
-            }
-            else {
-                //println!("Received data is not netflow data");
-            }
-        }
-    }
-
     pub fn match_sender(&mut self, sender_ip: Ipv4Addr) -> std::result::Result<usize, std::io::Error> {
         let vec_len = self.senders.len();
         for x in 0..vec_len {
@@ -770,3 +1196,172 @@ impl NetflowServer {
     
 // }
 
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::mpsc::sync_channel;
+
+    use super::*;
+    use crate::settings::parse_config_string;
+
+    //a v9 header followed by the flowsets
+    fn v9_datagram(record_count: u16, flowsets: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&9u16.to_be_bytes());
+        datagram.extend_from_slice(&record_count.to_be_bytes());
+        //sys uptime, unix secs, sequence, source id
+        datagram.extend_from_slice(&[0, 0, 0x27, 0x10, 0x65, 0x00, 0x00, 0x00, 0, 0, 0, 1, 0, 0, 0, 0]);
+        datagram.extend_from_slice(flowsets);
+        datagram
+    }
+
+    fn decode(datagram: Vec<u8>) -> Decoder {
+        let (writes_tx, _writes_rx) = sync_channel(16);
+        let mut decoder = Decoder::new(parse_config_string(String::new()), None, None, writes_tx, Arc::new(PipelineStats::default()));
+        decoder.decode_datagram(Datagram { source: "127.0.0.1:2055".parse().unwrap(), bytes: datagram });
+        decoder
+    }
+
+    #[test]
+    fn options_template_with_uneven_lengths_is_rejected() {
+        //the scope is 6 bytes, reading it 4 at a time runs past the end of the datagram
+        let flowset = [0, 1, 0, 16, 1, 0, 0, 6, 0, 0, 0, 2, 0, 4, 0, 0];
+        let decoder = decode(v9_datagram(1, &flowset));
+        assert!(decoder.senders[0].options_template.is_none());
+        assert_eq!(decoder.senders[0].counters.parse_errors, 1);
+    }
+
+    #[test]
+    fn truncated_template_header_is_a_parse_error() {
+        //a template flowset id and length, then the datagram ends inside the template id
+        for flowset in [&[0, 0, 0, 8][..], &[0, 0, 0, 8, 1][..]] {
+            let decoder = decode(v9_datagram(1, flowset));
+            assert!(decoder.senders[0].active_template.id.is_none());
+            assert_eq!(decoder.senders[0].counters.parse_errors, 1);
+        }
+    }
+
+    #[test]
+    fn options_template_is_parsed() {
+        //scope: interface (2), 4 bytes, option: IF_NAME (82), 16 bytes
+        let flowset = [0, 1, 0, 20, 1, 0, 0, 4, 0, 4, 0, 2, 0, 4, 0, 82, 0, 16, 0, 0];
+        let decoder = decode(v9_datagram(1, &flowset));
+        let options_template = decoder.senders[0].options_template.as_ref().unwrap();
+        assert_eq!(options_template.scope_fields, vec![(2, 4)]);
+        assert_eq!(options_template.option_fields, vec![(82, 16)]);
+        assert_eq!(decoder.senders[0].counters.parse_errors, 0);
+    }
+
+    #[test]
+    fn interface_names_are_taken_from_the_options_data() {
+        //the options template from options_template_is_parsed, then two records of it
+        let mut flowsets = vec![0, 1, 0, 20, 1, 0, 0, 4, 0, 4, 0, 2, 0, 4, 0, 82, 0, 16, 0, 0];
+        flowsets.extend_from_slice(&[1, 0, 0, 44]);
+        for (snmp_index, name) in [(3u32, &b"Gi0/3"[..]), (7, &b"Gi0/7 \0"[..])] {
+            flowsets.extend_from_slice(&snmp_index.to_be_bytes());
+            let mut padded = name.to_vec();
+            padded.resize(16, 0);
+            flowsets.extend_from_slice(&padded);
+        }
+        let decoder = decode(v9_datagram(3, &flowsets));
+
+        let names: Vec<(Ipv4Addr, u32, String)> = decoder.pending_writes.iter()
+            .filter_map(|op| match op {
+                WriteOp::InterfaceName { sender_ip, snmp_index, name, .. } => Some((*sender_ip, *snmp_index, name.clone())),
+                _ => None,
+            })
+            .collect();
+        let sender_ip = Ipv4Addr::new(127, 0, 0, 1);
+        assert_eq!(names, vec![(sender_ip, 3, "Gi0/3".to_string()), (sender_ip, 7, "Gi0/7".to_string())]);
+        assert_eq!(decoder.senders[0].counters.parse_errors, 0);
+        assert_eq!(decoder.senders[0].counters.records_skipped, 0);
+    }
+
+    #[test]
+    fn shutdown_writes_what_was_received_and_closes_the_flows() {
+        let mut db_conn = Connection::open_in_memory().unwrap();
+        crate::migrations::migrate_db(&mut db_conn).unwrap();
+        let db_conn = Arc::new(Mutex::new(db_conn));
+        //nothing is written until the shutdown
+        let settings = ServerSettings { write_batch_interval_ms: 600_000, ..parse_config_string(String::new()) };
+        let server = NetflowServer::new("127.0.0.1:0".to_string(), Arc::clone(&db_conn), settings);
+        let server_addr = server.sockets[0].local_addr().unwrap();
+        let handle = server.spawn();
+
+        //the template and record datagram from the async server test
+        let mut datagram = v9_datagram(2, &[0, 0, 0, 36, 1, 0, 0, 7, 0, 8, 0, 4, 0, 12, 0, 4, 0, 4, 0, 1, 0, 7, 0, 2, 0, 11, 0, 2, 0, 1, 0, 4, 0, 2, 0, 4]);
+        datagram.extend_from_slice(&[1, 0, 0, 28, 10, 0, 0, 1, 10, 0, 0, 2, 6, 0xc3, 0x50, 0x01, 0xbb, 0, 0, 0x05, 0xdc, 0, 0, 0, 10, 0, 0, 0]);
+        UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&datagram, server_addr).unwrap();
+        let stats = handle.pipeline_stats();
+        let started = Instant::now();
+        while stats.counters().received == 0 {
+            assert!(started.elapsed() < Duration::from_secs(10), "the datagram was never received");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(db_conn.lock().unwrap().query_row("SELECT COUNT(*) FROM flows", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+
+        handle.shutdown();
+
+        //every thread that shared the db is gone
+        assert_eq!(Arc::strong_count(&db_conn), 1);
+        assert_eq!(stats.counters().written_batches, 1);
+        let db_conn = db_conn.lock().unwrap();
+        let (src_addr, dst_port, in_octets, closed): (String, u16, i64, bool) = db_conn.query_row(
+            "SELECT src_addr, dst_port, in_octets, end_time IS NOT NULL FROM flows", [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        assert_eq!((src_addr.as_str(), dst_port, in_octets, closed), ("10.0.0.1", 443, 1500, true));
+        let deltas: i64 = db_conn.query_row("SELECT COUNT(*) FROM delta", [], |row| row.get(0)).unwrap();
+        assert_eq!(deltas, 1);
+    }
+
+    struct ExporterAlerts(Arc<Mutex<Vec<String>>>);
+
+    impl FlowSubscriber for ExporterAlerts {
+        fn on_exporter_silent(&mut self, sender_ip: Ipv4Addr, _last_seen: DateTime<Local>) {
+            self.0.lock().unwrap().push(format!("silent {sender_ip}"));
+        }
+        fn on_exporter_resumed(&mut self, sender_ip: Ipv4Addr) {
+            self.0.lock().unwrap().push(format!("resumed {sender_ip}"));
+        }
+    }
+
+    #[test]
+    fn exporters_are_counted_and_alerted_on_once_when_silent() {
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let (events, dispatcher) = start_event_dispatcher(vec![Box::new(ExporterAlerts(Arc::clone(&alerts)))], 16);
+        let (writes_tx, _writes_rx) = sync_channel(16);
+        let settings = ServerSettings { exporter_expected_interval_sec: 60, ..parse_config_string(String::new()) };
+        let mut decoder = Decoder::new(settings, None, Some(events), writes_tx, Arc::new(PipelineStats::default()));
+        let mut datagram = v9_datagram(2, &[0, 0, 0, 36, 1, 0, 0, 7, 0, 8, 0, 4, 0, 12, 0, 4, 0, 4, 0, 1, 0, 7, 0, 2, 0, 11, 0, 2, 0, 1, 0, 4, 0, 2, 0, 4]);
+        datagram.extend_from_slice(&[1, 0, 0, 28, 10, 0, 0, 1, 10, 0, 0, 2, 6, 0xc3, 0x50, 0x01, 0xbb, 0, 0, 0x05, 0xdc, 0, 0, 0, 10, 0, 0, 0]);
+        let source: SocketAddr = "127.0.0.1:2055".parse().unwrap();
+
+        decoder.decode_datagram(Datagram { source, bytes: datagram.clone() });
+        decoder.decode_datagram(Datagram { source, bytes: datagram.clone() });
+        assert_eq!(decoder.senders[0].counters, SenderCounters {
+            packets: 2,
+            bytes: 2 * datagram.len() as u64,
+            records_decoded: 2,
+            records_skipped: 0,
+            parse_errors: 0,
+            template_refreshes: 1,
+        });
+
+        //quiet for longer than the expected interval, the alert only fires once
+        decoder.check_silent_senders();
+        assert!(!decoder.senders[0].silent);
+        decoder.senders[0].last_seen = Local::now() - chrono::Duration::seconds(61);
+        decoder.check_silent_senders();
+        decoder.check_silent_senders();
+        assert!(decoder.senders[0].silent);
+
+        decoder.decode_datagram(Datagram { source, bytes: datagram });
+        assert!(!decoder.senders[0].silent);
+
+        drop(decoder);
+        dispatcher.join().unwrap();
+        assert_eq!(*alerts.lock().unwrap(), vec!["silent 127.0.0.1", "resumed 127.0.0.1"]);
+    }
+}
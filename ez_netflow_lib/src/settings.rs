@@ -1,23 +1,105 @@
 
 
+use std::collections::HashMap;
 use std::fs;
 use std::io::ErrorKind;
 use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
 
-#[derive(Debug)]
+use crate::fields::ConnState;
+use crate::flow_export::{ExportFormat, parse_export_format};
+use crate::interfaces::parse_interface_names;
+use crate::replication::{ReplicationTarget, parse_replication_targets};
+use crate::senders::parse_exporter_intervals;
+use crate::utils::{Ipv4Subnet, parse_subnets};
+
+#[derive(Debug, Clone)]
 pub struct ServerSettings {
     pub conn_type: ConnType,
+    //where the db file is written when conn_type is InFile
+    pub db_path: String,
+    //sqlite pragmas applied when the db is opened
+    pub db_journal_mode: String,
+    pub db_synchronous: String,
+    //pages when positive, KiB when negative, same as PRAGMA cache_size
+    pub db_cache_size: i64,
+    //an in memory db is restored from here at startup and written back on shutdown
+    pub memory_snapshot_path: Option<String>,
     pub flow_limit: FlowsToShow,
     pub sort_by: SortBy,
     pub port: u16,
     pub address: String,
     pub unicast_only: bool,
+    //a flow is closed once it has been open this long, the next record starts a new flow
+    pub active_timeout_sec: i64,
+    //a flow is closed once no records have been received for this long
+    pub inactive_timeout_sec: i64,
+    //count each conversation once even when several exporters, interfaces or directions report it
+    pub dedup_flows: bool,
+    //print the per interface table below the flows
+    pub show_interface_stats: bool,
+    //print the per exporter ingest counters below the flows
+    pub show_sender_stats: bool,
+    //names for snmp indexes keyed by exporter ip, these win over names sent by the exporter
+    pub interface_names: HashMap<(Ipv4Addr, u32), String>,
+    //only show flows in these tcp connection states, empty shows everything
+    pub conn_states: Vec<ConnState>,
+    //used to find directed broadcasts when the exporter doesn't send the masks
+    pub local_subnets: Vec<Ipv4Subnet>,
+    //events waiting for the flow subscribers, anything past this is dropped so the receive loop never waits
+    pub event_queue_size: usize,
+    //raw per record rows, removed once they are rolled up and older than this
+    pub delta_retention_hours: i64,
+    pub rollup_1m_retention_hours: i64,
+    pub rollup_1h_retention_days: i64,
+    pub rollup_1d_retention_days: i64,
+    //closed flows are removed after this, once their deltas are gone
+    pub flow_retention_days: i64,
+    pub interface_stats_retention_days: i64,
+    //the oldest data is removed once the db gets bigger than this, 0 means no limit
+    pub max_db_size_mb: i64,
+    //rows deleted per statement, the db lock is released in between so the server keeps writing
+    pub retention_batch_size: i64,
+    //an existing db needs a full VACUUM to switch to incremental vacuum, it blocks the startup so it's only done when asked
+    pub vacuum_db_at_startup: bool,
+    //received records are written in one transaction once this many are waiting or the interval has passed
+    pub write_batch_records: usize,
+    pub write_batch_interval_ms: u64,
+    //datagrams are decoded on this many threads, each exporter always goes to the same one
+    pub decoder_threads: usize,
+    //datagrams waiting per decoder, anything past this is dropped and counted
+    pub decode_queue_size: usize,
+    //batches waiting for the db writer, the decoders wait once it's full
+    pub write_queue_size: usize,
+    //largest datagram that can be received, up to 65535, a bigger one is dropped and counted as truncated
+    pub max_datagram_size: usize,
+    //SO_RCVBUF for each socket, 0 keeps the os default
+    pub socket_receive_buffer_bytes: usize,
+    //more than one binds that many sockets to the port with SO_REUSEPORT, each read by its own thread
+    pub receive_sockets: usize,
+    //an exporter that sends nothing for this long is reported as silent, 0 never does
+    pub exporter_expected_interval_sec: i64,
+    //overrides exporter_expected_interval_sec for single exporters
+    pub exporter_expected_intervals: HashMap<Ipv4Addr, i64>,
+    //datagrams from anywhere else are dropped, empty lets every exporter in
+    pub allowed_exporters: Vec<Ipv4Subnet>,
+    //exporters past this are dropped like unknown ones, 0 means no limit
+    pub max_exporters: usize,
+    //logs every exporter that was dropped, once, so it can be added to allowed_exporters
+    pub exporter_learning_mode: bool,
+    //every accepted datagram is also sent unchanged to these collectors
+    pub replication_targets: Vec<ReplicationTarget>,
+    //the merged flows are exported again to this collector, None turns it off
+    pub flow_export_target: Option<SocketAddr>,
+    pub flow_export_format: ExportFormat,
+    //a flow that's still open is exported this often
+    pub flow_export_active_timeout_sec: i64,
 }
 
 impl ServerSettings {
     pub fn new(file: &str) -> Self {
         let default_config: &[u8] = 
-        "database_file_or_mem: file,\nflows_to_display: 30,\nsort_flows_by_bytes_or_packets: bytes,\nshow_only_unicast: false,".as_bytes();
+        "database_file_or_mem: file,\ndatabase_path: ./eznf_db.sqlite,\ndatabase_journal_mode: delete,\ndatabase_synchronous: full,\ndatabase_cache_size: -2000,\nmemory_snapshot_path: ,\nflows_to_display: 30,\nsort_flows_by_bytes_or_packets: bytes,\nshow_only_unicast: false,\nflow_active_timeout_sec: 1800,\nflow_inactive_timeout_sec: 300,\ndedup_flows: false,\nshow_interface_stats: false,\nshow_sender_stats: false,\ninterface_names: ,\nshow_conn_states: all,\nlocal_subnets: ,\nevent_queue_size: 1024,\ndelta_retention_hours: 24,\nrollup_1m_retention_hours: 48,\nrollup_1h_retention_days: 90,\nrollup_1d_retention_days: 1825,\nflow_retention_days: 30,\ninterface_stats_retention_days: 90,\nmax_db_size_mb: 0,\nretention_batch_size: 5000,\nvacuum_db_at_startup: false,\nwrite_batch_records: 1000,\nwrite_batch_interval_ms: 1000,\ndecoder_threads: 2,\ndecode_queue_size: 4096,\nwrite_queue_size: 64,\nmax_datagram_size: 65535,\nsocket_receive_buffer_bytes: 0,\nreceive_sockets: 1,\nexporter_expected_interval_sec: 60,\nexporter_expected_intervals: ,\nallowed_exporters: ,\nmax_exporters: 0,\nexporter_learning_mode: false,\nreplication_targets: ,\nflow_export_target: ,\nflow_export_format: v9,\nflow_export_active_timeout_sec: 60,".as_bytes();
 
         let config_result = fs::read_to_string(file);
             //.expect("Unable to read config.ini");
@@ -42,6 +124,9 @@ impl ServerSettings {
 
     }
 
+    pub fn get_expected_interval_sec(&self, sender_ip: Ipv4Addr) -> i64 {
+        *self.exporter_expected_intervals.get(&sender_ip).unwrap_or(&self.exporter_expected_interval_sec)
+    }
    
 }
 
@@ -49,11 +134,51 @@ pub fn parse_config_string(config_string: String) -> ServerSettings {
     
     let mut settings = ServerSettings {
         conn_type: ConnType::InFile,
+        db_path: "./eznf_db.sqlite".to_string(),
+        db_journal_mode: "delete".to_string(),
+        db_synchronous: "full".to_string(),
+        db_cache_size: -2000,
+        memory_snapshot_path: None,
         flow_limit: FlowsToShow::Limit { flows: (30) },
         sort_by: SortBy::Bytes,
         port: 2055,
         address: "0.0.0.0".to_string(),
         unicast_only: false,
+        active_timeout_sec: 1800,
+        inactive_timeout_sec: 300,
+        dedup_flows: false,
+        show_interface_stats: false,
+        show_sender_stats: false,
+        interface_names: HashMap::new(),
+        conn_states: Vec::new(),
+        local_subnets: Vec::new(),
+        event_queue_size: 1024,
+        delta_retention_hours: 24,
+        rollup_1m_retention_hours: 48,
+        rollup_1h_retention_days: 90,
+        rollup_1d_retention_days: 1825,
+        flow_retention_days: 30,
+        interface_stats_retention_days: 90,
+        max_db_size_mb: 0,
+        retention_batch_size: 5000,
+        vacuum_db_at_startup: false,
+        write_batch_records: 1000,
+        write_batch_interval_ms: 1000,
+        decoder_threads: 2,
+        decode_queue_size: 4096,
+        write_queue_size: 64,
+        max_datagram_size: 65535,
+        socket_receive_buffer_bytes: 0,
+        receive_sockets: 1,
+        exporter_expected_interval_sec: 60,
+        exporter_expected_intervals: HashMap::new(),
+        allowed_exporters: Vec::new(),
+        max_exporters: 0,
+        exporter_learning_mode: false,
+        replication_targets: Vec::new(),
+        flow_export_target: None,
+        flow_export_format: ExportFormat::NetflowV9,
+        flow_export_active_timeout_sec: 60,
     };
 
     
@@ -71,6 +196,38 @@ pub fn parse_config_string(config_string: String) -> ServerSettings {
                 }
             }
         }
+        //paths can contain ':' on windows
+        else if c.contains("database_path") {
+            if let Some((_, path)) = c.split_once(':') {
+                if !path.trim().is_empty() {
+                    settings.db_path = path.trim().to_string();
+                }
+            }
+        }
+        else if c.contains("database_journal_mode") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.db_journal_mode = c2[1].trim().to_lowercase();
+            }
+        }
+        else if c.contains("database_synchronous") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.db_synchronous = c2[1].trim().to_lowercase();
+            }
+        }
+        else if c.contains("database_cache_size") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.db_cache_size = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("memory_snapshot_path") {
+            if let Some((_, path)) = c.split_once(':') {
+                let path = path.trim();
+                settings.memory_snapshot_path = if path.is_empty() { None } else { Some(path.to_string()) };
+            }
+        }
         else if c.contains("flows_to_display") {
             let c2: Vec<&str> = c.split(":").collect();
             //println!("c2 is {}, AND {}", c2[0], c2[1]);
@@ -96,33 +253,252 @@ pub fn parse_config_string(config_string: String) -> ServerSettings {
             let c2: Vec<&str> = c.split(":").collect();
             //println!("c2 is {}, AND {}", c2[0], c2[1]);
             if c2.len() == 2 {
-                if c2[1].contains("true") {
-                    settings.unicast_only = true;
-                }
-                else {
-                    settings.unicast_only = false;
+                settings.unicast_only = c2[1].contains("true");
+            }
+        }
+        else if c.contains("flow_active_timeout_sec") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.active_timeout_sec = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("flow_inactive_timeout_sec") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.inactive_timeout_sec = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("dedup_flows") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.dedup_flows = c2[1].contains("true");
+            }
+        }
+        else if c.contains("show_interface_stats") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.show_interface_stats = c2[1].contains("true");
+            }
+        }
+        else if c.contains("show_sender_stats") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.show_sender_stats = c2[1].contains("true");
+            }
+        }
+        else if c.contains("show_conn_states") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.conn_states = parse_conn_states(c2[1]);
+            }
+        }
+        else if c.contains("local_subnets") {
+            if let Some((_, subnets)) = c.split_once(':') {
+                settings.local_subnets = parse_subnets(subnets);
+            }
+        }
+        else if c.contains("event_queue_size") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.event_queue_size = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("delta_retention_hours") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.delta_retention_hours = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("rollup_1m_retention_hours") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.rollup_1m_retention_hours = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("rollup_1h_retention_days") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.rollup_1h_retention_days = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("rollup_1d_retention_days") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.rollup_1d_retention_days = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("flow_retention_days") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.flow_retention_days = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("interface_stats_retention_days") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.interface_stats_retention_days = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("max_db_size_mb") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.max_db_size_mb = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("retention_batch_size") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.retention_batch_size = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("vacuum_db_at_startup") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.vacuum_db_at_startup = c2[1].contains("true");
+            }
+        }
+        else if c.contains("write_batch_records") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.write_batch_records = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("write_batch_interval_ms") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.write_batch_interval_ms = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("decoder_threads") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.decoder_threads = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("decode_queue_size") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.decode_queue_size = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("write_queue_size") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.write_queue_size = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("max_datagram_size") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.max_datagram_size = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("socket_receive_buffer_bytes") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.socket_receive_buffer_bytes = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("receive_sockets") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.receive_sockets = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("exporter_expected_interval_sec") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.exporter_expected_interval_sec = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("exporter_expected_intervals") {
+            if let Some((_, intervals)) = c.split_once(':') {
+                settings.exporter_expected_intervals = parse_exporter_intervals(intervals);
+            }
+        }
+        else if c.contains("allowed_exporters") {
+            if let Some((_, subnets)) = c.split_once(':') {
+                settings.allowed_exporters = parse_subnets(subnets);
+                //an empty list lets every exporter in, so a list of typos must not turn into one
+                if settings.allowed_exporters.is_empty() && !subnets.trim().is_empty() {
+                    panic!("None of the allowed_exporters in config.ini are valid ipv4 addresses or subnets: {}", subnets.trim());
                 }
             }
         }
+        else if c.contains("max_exporters") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.max_exporters = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("exporter_learning_mode") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.exporter_learning_mode = c2[1].contains("true");
+            }
+        }
+        else if c.contains("replication_targets") {
+            if let Some((_, targets)) = c.split_once(':') {
+                settings.replication_targets = parse_replication_targets(targets);
+            }
+        }
+        else if c.contains("flow_export_target") {
+            if let Some((_, target)) = c.split_once(':') {
+                settings.flow_export_target = target.trim().parse().ok();
+            }
+        }
+        else if c.contains("flow_export_format") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.flow_export_format = parse_export_format(c2[1]);
+            }
+        }
+        else if c.contains("flow_export_active_timeout_sec") {
+            let c2: Vec<&str> = c.split(":").collect();
+            if c2.len() == 2 {
+                settings.flow_export_active_timeout_sec = c2[1].trim().parse().unwrap();
+            }
+        }
+        else if c.contains("interface_names") {
+            if let Some((_, names)) = c.split_once(':') {
+                settings.interface_names = parse_interface_names(names);
+            }
+        }
     }
     
     settings
 
 }
 
-#[derive(Debug)]
+//parses "show_conn_states: half_open; reset", anything unknown (like all) is ignored
+fn parse_conn_states(value: &str) -> Vec<ConnState> {
+    let mut states = Vec::new();
+    for state in value.split(';') {
+        match state.trim() {
+            "established" => states.push(ConnState::Established),
+            "half_open" => states.push(ConnState::HalfOpen),
+            "reset" => states.push(ConnState::Reset),
+            "fin_closed" => states.push(ConnState::FinClosed),
+            "none" => states.push(ConnState::None),
+            _ => {},
+        }
+    }
+    states
+}
+
+#[derive(Debug, Clone)]
 pub enum FlowsToShow {
     Limit{flows: i32},
     NoLimit,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ConnType {
     InMemory,
     InFile,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SortBy {
     Pkts,
     Bytes,
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::settings::ServerSettings;
+
+//the largest datagram udp can carry
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+//one byte more than the largest datagram accepted, so a datagram that fills the buffer is known to be bigger
+pub fn get_receive_buffer_size(server_settings: &ServerSettings) -> usize {
+    server_settings.max_datagram_size.clamp(1, MAX_DATAGRAM_SIZE) + 1
+}
+
+//with more than one socket the kernel spreads the exporters over them, an exporter always lands on the same one
+pub fn bind_receive_sockets(addr_and_port: &str, server_settings: &ServerSettings) -> std::io::Result<Vec<UdpSocket>> {
+    let mut addr: SocketAddr = addr_and_port.to_socket_addrs()?.next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{addr_and_port} is not a valid address")))?;
+    let socket_count = server_settings.receive_sockets.max(1);
+    let mut sockets = Vec::new();
+    for _ in 0..socket_count {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if socket_count > 1 {
+            set_reuse_port(&socket)?;
+        }
+        if server_settings.socket_receive_buffer_bytes > 0 {
+            socket.set_recv_buffer_size(server_settings.socket_receive_buffer_bytes)?;
+        }
+        socket.bind(&addr.into())?;
+        if sockets.is_empty() {
+            check_receive_buffer_size(&socket, server_settings.socket_receive_buffer_bytes)?;
+            //port 0 picks a free port, the other sockets have to join that one instead of picking their own
+            if let Some(bound) = socket.local_addr()?.as_socket() {
+                addr = bound;
+            }
+        }
+        sockets.push(socket.into());
+    }
+    Ok(sockets)
+}
+
+//the kernel silently caps SO_RCVBUF, net.core.rmem_max on linux
+fn check_receive_buffer_size(socket: &Socket, requested: usize) -> std::io::Result<()> {
+    let size = socket.recv_buffer_size()?;
+    if requested == 0 {
+        info!("Socket receive buffer is the os default of {} bytes", size);
+    }
+    else if size < requested {
+        warn!("Socket receive buffer is {} bytes instead of {}, the os limits it (net.core.rmem_max on linux)", size, requested);
+    }
+    else {
+        info!("Socket receive buffer is {} bytes", size);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> std::io::Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "receive_sockets above 1 needs SO_REUSEPORT, which this os doesn't have"))
+}
+
+
+//datagrams the kernel dropped because a socket's receive buffer was full, only linux reports them
+pub struct KernelDrops {
+    inodes: HashSet<u64>,
+}
+
+impl KernelDrops {
+    pub fn new(sockets: &[UdpSocket]) -> Self {
+        KernelDrops {
+            inodes: sockets.iter().filter_map(get_socket_inode).collect(),
+        }
+    }
+
+    //the total for all the sockets, None when the os doesn't report it
+    pub fn read(&self) -> Option<u64> {
+        if self.inodes.is_empty() {
+            return None;
+        }
+        let mut drops = 0;
+        for table in ["/proc/net/udp", "/proc/net/udp6"] {
+            if let Ok(contents) = fs::read_to_string(table) {
+                drops += count_drops(&contents, &self.inodes);
+            }
+        }
+        Some(drops)
+    }
+}
+
+//sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ref pointer drops
+fn count_drops(contents: &str, inodes: &HashSet<u64>) -> u64 {
+    let mut drops = 0;
+    for line in contents.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 13 {
+            continue;
+        }
+        if let (Ok(inode), Ok(socket_drops)) = (columns[9].parse::<u64>(), columns[12].parse::<u64>()) {
+            if inodes.contains(&inode) {
+                drops += socket_drops;
+            }
+        }
+    }
+    drops
+}
+
+#[cfg(target_os = "linux")]
+fn get_socket_inode(socket: &UdpSocket) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+    //the fd links to "socket:[inode]"
+    let link = fs::read_link(format!("/proc/self/fd/{}", socket.as_raw_fd())).ok()?;
+    link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_socket_inode(_socket: &UdpSocket) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::settings::parse_config_string;
+
+    //from a linux box with two sockets on 2055 and one on 68
+    const PROC_NET_UDP: &str = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  123: 00000000:0807 00000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 4180421 2 0000000000000000 17
+  123: 00000000:0807 00000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 4180422 2 0000000000000000 5
+  456: 00000000:0044 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 21873 2 0000000000000000 300
+";
+
+    #[test]
+    fn kernel_drops_are_summed_for_our_sockets_only() {
+        let inodes = HashSet::from([4180421, 4180422]);
+        assert_eq!(count_drops(PROC_NET_UDP, &inodes), 22);
+        assert_eq!(count_drops(PROC_NET_UDP, &HashSet::from([21873])), 300);
+        assert_eq!(count_drops(PROC_NET_UDP, &HashSet::new()), 0);
+        //the header and cut off lines are skipped
+        assert_eq!(count_drops("garbage\n  1: 00000000:0807 4180421", &inodes), 0);
+    }
+
+    #[test]
+    fn receive_buffer_is_one_byte_bigger_than_the_largest_datagram() {
+        let settings = parse_config_string(String::new());
+        assert_eq!(get_receive_buffer_size(&settings), MAX_DATAGRAM_SIZE + 1);
+        for (max_datagram_size, buffer_size) in [(1500, 1501), (0, 2), (100_000, MAX_DATAGRAM_SIZE + 1)] {
+            let settings = ServerSettings { max_datagram_size, ..parse_config_string(String::new()) };
+            assert_eq!(get_receive_buffer_size(&settings), buffer_size);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn receive_sockets_share_the_port() {
+        let settings = ServerSettings { receive_sockets: 2, ..parse_config_string(String::new()) };
+        let sockets = bind_receive_sockets("127.0.0.1:0", &settings).unwrap();
+        assert_eq!(sockets.len(), 2);
+        let port = sockets[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(sockets[1].local_addr().unwrap().port(), port);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kernel_drops_find_our_sockets() {
+        let settings = ServerSettings { receive_sockets: 2, ..parse_config_string(String::new()) };
+        let sockets = bind_receive_sockets("127.0.0.1:0", &settings).unwrap();
+        assert_eq!(KernelDrops::new(&sockets).read(), Some(0));
+    }
+}
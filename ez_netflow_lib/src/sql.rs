@@ -1,110 +1,177 @@
 
-use std::io::ErrorKind;
-use std::str::FromStr;
-use std::sync::{Arc, Mutex, MutexGuard};
 use std::net::Ipv4Addr;
-use log::{error, info, debug};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use log::{error, info};
 
-use rusqlite::ffi::Error;
-use rusqlite::OptionalExtension;
-use rusqlite::{ErrorCode, Connection, params};
+use rusqlite::{Connection, DatabaseName, params};
+use rusqlite::backup::Progress;
 use tabled::{builder::Builder, settings::Style};
 use chrono::prelude::*;
 
+use crate::interfaces::*;
+use crate::json::*;
+use crate::migrations::*;
+use crate::query::*;
+use crate::retention::enable_incremental_vacuum;
+use crate::senders::*;
 use crate::settings::*;
 use crate::templates::*;
 use crate::utils::*;
-use crate::fields::*;
-use crate::time::*;
 
 
-pub fn setup_db(conn_type: &ConnType) -> Connection {
+// opens the db and brings its schema up to date, see migrations.rs
+pub fn setup_db(server_settings: &ServerSettings) -> Result<Connection, DbSetupError> {
 
-    let db_conn: Connection = match conn_type {
+    let mut db_conn: Connection = match server_settings.conn_type {
         ConnType::InMemory => {
-            Connection::open_in_memory().expect("Unable to open SQLITE db connection in memory")
+            let mut db_conn = Connection::open_in_memory()?;
+            //the snapshot may be from an older version, it's migrated below like a file would be
+            if let Some(path) = &server_settings.memory_snapshot_path {
+                if Path::new(path).exists() {
+                    db_conn.restore(DatabaseName::Main, path, None::<fn(Progress)>)
+                        .map_err(|error| DbSetupError::Open { path: path.clone(), error })?;
+                    info!("Restored the in memory db from {}", path);
+                }
+            }
+            db_conn
         },
         ConnType::InFile => {
-            Connection::open("./eznf_db.sqlite").expect("Unable to open SQLITE db connection in memory")
+            Connection::open(&server_settings.db_path)
+                .map_err(|error| DbSetupError::Open { path: server_settings.db_path.clone(), error })?
         }
     };
 
-    db_conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
-
-    //create tables
-    db_conn.execute("CREATE TABLE IF NOT EXISTS senders (
-        ip TEXT PRIMARY KEY
-        )",
-        [],
-        ).expect("Unable to create senders table in DB");
-
-    db_conn.execute("CREATE TABLE IF NOT EXISTS delta (
-        id INTEGER PRIMARY KEY,
-        flow_id INTERGER NOT NULL,
-        updated_time TEXT,
-        in_octets INTEGER,
-        in_pkts INTEGER,
-        bps INTEGER,
-        pps INTEGER,
-        FOREIGN KEY (flow_id) REFERENCES flows(id)
-        )",
-        [],
-        ).expect("Unable to create delta table in DB");
-    
-    db_conn.execute("CREATE TABLE IF NOT EXISTS flows (
-        id INTEGER PRIMARY KEY,
-        sender_ip TEXT NOT NULL,
-        src_addr TEXT,
-        dst_addr TEXT,
-        protocol INTEGER,
-        src_port INTEGER,
-        dst_port INTEGER,
-        tcp_flags INTEGER,
-        input_snmp INTEGER,
-        output_snmp INTEGER,
-        in_octets INTEGER,
-        in_pkts INTEGER,
-        src_tos INTEGER,
-        src_mask INTEGER,
-        dst_mask INTEGER,
-        next_hop TEXT,
-        icmp TEXT,
-        traffic_type TEXT,
-        created_time TEXT,
-        FOREIGN KEY (sender_ip) REFERENCES senders(ip)
-        )",
-        [],
-        ).expect("Unable to create flows table in DB");
-
-    db_conn
+    //journal_mode returns the mode it ended up in, an in memory db always stays in memory mode
+    let journal_mode: String = db_conn.pragma_update_and_check(None, "journal_mode", &server_settings.db_journal_mode, |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case(&server_settings.db_journal_mode) {
+        info!("Requested journal_mode {} but the db is using {}", server_settings.db_journal_mode, journal_mode);
+    }
+    db_conn.pragma_update(None, "synchronous", &server_settings.db_synchronous)?;
+    db_conn.pragma_update(None, "cache_size", server_settings.db_cache_size)?;
+    db_conn.execute("PRAGMA foreign_keys = ON", [])?;
+    enable_incremental_vacuum(&db_conn, server_settings.vacuum_db_at_startup)?;
+
+    migrate_db(&mut db_conn)?;
+
+    Ok(db_conn)
 }
 
-pub fn update_senders_in_db(db_conn: &mut Arc<Mutex<Connection>>, sender_ip: &str) {
-    let db_conn_unlocked: MutexGuard<Connection> = db_conn.lock().unwrap();
-    db_conn_unlocked.execute( 
+// copies an in memory db to memory_snapshot_path, does nothing for a file db or without a path
+// sqlite writes the snapshot in a transaction, so a crash part way keeps the previous snapshot
+pub fn snapshot_db(db_conn: &Connection, server_settings: &ServerSettings) -> rusqlite::Result<()> {
+    let path = match (&server_settings.conn_type, &server_settings.memory_snapshot_path) {
+        (ConnType::InMemory, Some(path)) => path,
+        _ => return Ok(()),
+    };
+    db_conn.backup(DatabaseName::Main, path, None)?;
+    info!("Wrote the in memory db snapshot to {}", path);
+    Ok(())
+}
+
+pub fn update_senders_in_db(db_conn: &Connection, sender_ip: &str) -> rusqlite::Result<()> {
+    db_conn.prepare_cached( 
         "INSERT OR IGNORE INTO senders (ip) VALUES (?)",
-        [sender_ip.to_string()],
-        ).expect("Unable to execute SQL in update_senders_in_db");
+        )?
+        .execute([sender_ip.to_string()])?;
+    Ok(())
 }
 
+pub fn get_sender_ips_from_db(db_conn: &Connection) -> Vec<Ipv4Addr> {
+    let mut stmt = db_conn.prepare("SELECT ip FROM senders ORDER BY rowid")
+        .expect("Unable to prepare SQL in get_sender_ips_from_db");
+    stmt.query_map([], |row| row.get::<_, String>(0))
+        .expect("Unable to execute SQL in get_sender_ips_from_db")
+        .filter_map(|ip| convert_string_to_ipv4(&ip.ok()?).ok())
+        .collect()
+}
+
+
+//the counters are added to the totals, so they survive restarts
+pub fn update_sender_counters_in_db(db_conn: &Connection, sender_ip: &str, counters: &SenderCounters, last_seen: &DateTime<Local>, last_template: Option<&DateTime<Local>>) -> rusqlite::Result<()> {
+    db_conn.prepare_cached(
+        "INSERT INTO senders (ip, packets, bytes, records_decoded, records_skipped, parse_errors, template_refreshes, last_seen, last_template)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ON CONFLICT (ip) DO UPDATE SET
+            packets = packets + excluded.packets,
+            bytes = bytes + excluded.bytes,
+            records_decoded = records_decoded + excluded.records_decoded,
+            records_skipped = records_skipped + excluded.records_skipped,
+            parse_errors = parse_errors + excluded.parse_errors,
+            template_refreshes = template_refreshes + excluded.template_refreshes,
+            last_seen = excluded.last_seen,
+            last_template = COALESCE(excluded.last_template, last_template)",
+        )?
+        .execute(params![
+            sender_ip,
+            counters.packets as i64,
+            counters.bytes as i64,
+            counters.records_decoded as i64,
+            counters.records_skipped as i64,
+            counters.parse_errors as i64,
+            counters.template_refreshes as i64,
+            last_seen.to_rfc3339_opts(SecondsFormat::Secs, true),
+            last_template.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            ])?;
+    Ok(())
+}
 
-pub fn create_flow_in_db(db_conn: &mut Connection, flow: &NetFlow, sender_ip: &String, current_time: &DateTime<Local>) {
+
+// one statement creates the flow or updates the open flow with the same key, and returns its row id
+// the flow key covers the sender, protocol, vrf, vlans and both directions of the 4-tuple
+// so a single row is updated no matter which direction the record was exported in
+pub fn upsert_flow_in_db(db_conn: &Connection, flow: &NetFlow, current_time: &DateTime<Local>) -> rusqlite::Result<i64> {
 
     //let traffic_type = handle_traffic_cast(&flow.src_and_dst_ip.0.to_string(), &flow.src_and_dst_ip.1.to_string());
     //let traffic_type = handle_traffic_type(&flow);
     //moved traffic type processing to the same func that processes the flow
     
-    let traffic_type = match flow.traffic_type {
-        TrafficType::Broadcast => "Broadcast",
-        TrafficType::Multicast => "Multicast",
-        TrafficType::Unicast => "Unicast",
-    };
+    let traffic_type = flow.traffic_type.as_str();
+    let (input_snmp, output_snmp) = flow.snmp_indexes();
+    let (avg_bps, avg_pps) = flow.average_rate();
+
+    //the sender may have been purged by the retention while the exporter was quiet
+    if flow.db_id.is_none() {
+        db_conn.prepare_cached("INSERT OR IGNORE INTO senders (ip) VALUES (?1)")?
+            .execute([flow.sender_ip.to_string()])?;
+    }
 
-    db_conn.execute( 
+    // the addresses are updated too because the initiator can change once the reverse side is seen
+    let mut stmt = db_conn.prepare_cached(
         "INSERT INTO flows 
-            (sender_ip, src_addr, dst_addr, src_port, dst_port, protocol, in_octets, in_pkts, traffic_type, created_time) 
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-        (sender_ip.to_string(), 
+            (sender_ip, src_addr, dst_addr, src_port, dst_port, protocol, in_octets, in_pkts, traffic_type, created_time, flow_key,
+            rev_octets, rev_pkts, first_seen, last_seen, rev_first_seen, rev_last_seen, dedup_primary, input_snmp, output_snmp,
+            tcp_flags, conn_state, avg_bps, avg_pps, peak_bps, peak_pps, last_seen_epoch) 
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22,
+            ?23, ?24, ?25, ?26, ?27)
+            ON CONFLICT (flow_key) WHERE end_time IS NULL DO UPDATE SET
+            in_octets = excluded.in_octets,
+            in_pkts = excluded.in_pkts,
+            rev_octets = excluded.rev_octets,
+            rev_pkts = excluded.rev_pkts,
+            src_addr = excluded.src_addr,
+            dst_addr = excluded.dst_addr,
+            src_port = excluded.src_port,
+            dst_port = excluded.dst_port,
+            first_seen = excluded.first_seen,
+            last_seen = excluded.last_seen,
+            last_seen_epoch = excluded.last_seen_epoch,
+            rev_first_seen = excluded.rev_first_seen,
+            rev_last_seen = excluded.rev_last_seen,
+            dedup_primary = excluded.dedup_primary,
+            input_snmp = excluded.input_snmp,
+            output_snmp = excluded.output_snmp,
+            tcp_flags = excluded.tcp_flags,
+            conn_state = excluded.conn_state,
+            avg_bps = excluded.avg_bps,
+            avg_pps = excluded.avg_pps,
+            peak_bps = excluded.peak_bps,
+            peak_pps = excluded.peak_pps
+            RETURNING id",
+        )?;
+
+    let flow_id: i64 = stmt.query_row(
+        params![flow.sender_ip.to_string(), 
             flow.src_ip.to_string(), 
             flow.dst_ip.to_string(),
             flow.src_port, 
@@ -113,122 +180,141 @@ pub fn create_flow_in_db(db_conn: &mut Connection, flow: &NetFlow, sender_ip: &S
             flow.in_octets, 
             flow.in_packets,
             traffic_type,
-            current_time.to_rfc3339_opts(SecondsFormat::Secs, true)),
-        ).expect("Unable to execute SQL in create_flow_in_db");
+            current_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            flow.key().to_string(),
+            flow.rev_octets,
+            flow.rev_packets,
+            flow.first_seen.to_rfc3339_opts(SecondsFormat::Secs, true),
+            flow.last_seen.to_rfc3339_opts(SecondsFormat::Secs, true),
+            flow.rev_first_seen.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            flow.rev_last_seen.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            flow.dedup_primary,
+            input_snmp,
+            output_snmp,
+            flow.combined_tcp_flags(),
+            flow.conn_state().as_str(),
+            avg_bps,
+            avg_pps,
+            flow.peak_bps,
+            flow.peak_pps,
+            flow.last_seen.timestamp()],
+        |row| row.get(0),
+        )?;
+
+    insert_deltas_in_db(db_conn, flow_id, flow)?;
+    Ok(flow_id)
 }
 
-// I can't remove the "WHERE sender_ip = ?1" because it will update all of the flows
-// I first need to make sure a flow is not created twice, no matter the sender
-pub fn update_flow_in_db(db_conn: &mut Connection, flow: &NetFlow, sender_ip: &String, current_time: &DateTime<Local>) {
-    // info!("running update_flow_in_db for flow src_ip {}, dst_ip {}, src_port {}, dst_port {}",
-    //     flow.src_and_dst_ip.0.to_string(), 
-    //     flow.src_and_dst_ip.1.to_string(),
-    //     flow.src_and_dst_port.0,
-    //     flow.src_and_dst_port.1,
-    // );
-    // update the flow packets and bytes
-    db_conn.execute( 
+// every record counted since the last write gets a row, the rollups are built from these
+fn insert_deltas_in_db(db_conn: &Connection, flow_id: i64, flow: &NetFlow) -> rusqlite::Result<()> {
+    let mut stmt = db_conn.prepare_cached(
+        "INSERT INTO delta 
+            (flow_id, updated_time, in_octets, in_pkts, bps, pps, updated_epoch) 
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+    for delta in &flow.deltas {
+        stmt.execute((
+            flow_id,
+            delta.updated_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            delta.in_octets,
+            delta.in_pkts,
+            delta.bps,
+            delta.pps,
+            delta.updated_time.timestamp(),
+            ))?;
+    }
+    Ok(())
+}
+
+// the row id is known once the flow has been written, a flow that never was has nothing to close
+pub fn close_flow_in_db(db_conn: &Connection, flow: &NetFlow, end_time: &DateTime<Local>) -> rusqlite::Result<()> {
+    let flow_id = match flow.db_id {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    let (avg_bps, avg_pps) = flow.average_rate();
+    db_conn.prepare_cached( 
         "UPDATE flows SET 
             in_octets = ?1,
-            in_pkts = ?2
-            WHERE src_addr = ?3
-            AND dst_addr = ?4
-            AND src_port = ?5
-            AND dst_port = ?6
-            AND protocol = ?7",
-        params![
+            in_pkts = ?2,
+            rev_octets = ?3,
+            rev_pkts = ?4,
+            tcp_flags = ?5,
+            conn_state = ?6,
+            avg_bps = ?7,
+            avg_pps = ?8,
+            peak_bps = ?9,
+            peak_pps = ?10,
+            end_time = ?11,
+            end_epoch = ?12
+            WHERE id = ?13",
+        )?
+        .execute(params![
             flow.in_octets,
             flow.in_packets,
-            flow.src_ip.to_string(), 
-            flow.dst_ip.to_string(),
-            flow.src_port, 
-            flow.dst_port, 
-            flow.protocol
-            ]
-        ).expect("Unable to execute SQL on flows table in update_flow_in_db");
-
-    // get flow id
-    let flow_id = db_conn.query_row(
-        "SELECT id FROM flows
-        WHERE src_addr = ?1
-        AND dst_addr = ?2
-        AND src_port = ?3
-        AND dst_port = ?4
-        AND protocol = ?5",
-        params![
-            flow.src_ip.to_string(), 
-            flow.dst_ip.to_string(),
-            flow.src_port, 
-            flow.dst_port, 
-            flow.protocol],
-        |row| row.get::<_, i32>(0),
-    ).expect("Unable to get flow id in update_flow_in_db");
-    //info!("got flow_id {}", flow_id);
-
-    let delta_oct_pk: (i64, i64) = match flow.deltas.last() {
-        Some(delta) => (delta.in_octets, delta.in_pkts),
-        None => (0,0),
-    };
-    let delta_bps_pps: (i64, i64) = match flow.deltas.last() {
-        Some(delta) => (delta.bps, delta.pps),
-        None => (0,0),
-    };
-
-    // update delta table with flow id, time, bytes, and packets
-    db_conn.execute( 
-        "INSERT INTO delta 
-            (flow_id, updated_time, in_octets, in_pkts, bps, pps) 
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        (
+            flow.rev_octets,
+            flow.rev_packets,
+            flow.combined_tcp_flags(),
+            flow.conn_state().as_str(),
+            avg_bps,
+            avg_pps,
+            flow.peak_bps,
+            flow.peak_pps,
+            end_time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            end_time.timestamp(),
             flow_id,
-            current_time.to_rfc3339_opts(SecondsFormat::Secs, true),
-            delta_oct_pk.0,
-            delta_oct_pk.1,
-            delta_bps_pps.0,
-            delta_bps_pps.1,
-
-        ),
-        ).expect("Unable to execute SQL on delta table in update_flow_in_db");
-        //info!("inserted updated_time {}", current_time.to_rfc3339_opts(SecondsFormat::Secs, true));
+            ])?;
+    Ok(())
 }
 
-pub fn check_if_flow_exists_in_db(db_conn: &mut Connection, flow: &NetFlow) -> bool {
-
-    let row_result = db_conn.query_row(
-            "SELECT * FROM flows WHERE 
-             ((src_addr = ?1 AND dst_addr = ?2) OR (src_addr = ?2 AND dst_addr = ?1)) AND
-             ((src_port = ?3 AND dst_port = ?4) OR (src_port = ?4 AND dst_port = ?3)) AND
-            protocol = ?5",
-            params![
-                flow.src_ip.to_string(), 
-                flow.dst_ip.to_string(),
-                flow.src_port, 
-                flow.dst_port, 
-                flow.protocol],
-            |row| row.get::<_, i32>(0),
-    );
-
-    match row_result {
-        Ok(s) => {
-            //println!("existing flow found, id is {s}");
-            true 
-        },
-         Err(rusqlite::Error::QueryReturnedNoRows) => {
-            //println!("existing flow NOT found because query returned no rows");
-            false
-         },
-         Err(e) => {
-            println!("Error in checking for existing fow in SQL, setting flow as existing,  error is {e}");
-            error!("Error in checking for existing fow in SQL, setting flow as existing,  error is {e}");
-            false
-         }
-    }
-    
+// flows left open by a previous run can't be continued because their in-memory totals are gone
+pub fn close_open_flows_in_db(db_conn: &mut Arc<Mutex<Connection>>, end_time: &DateTime<Local>) {
+    let db_conn_unlocked: MutexGuard<Connection> = db_conn.lock().unwrap();
+    db_conn_unlocked.execute( 
+        "UPDATE flows SET end_time = ?1, end_epoch = ?2 WHERE end_time IS NULL",
+        params![end_time.to_rfc3339_opts(SecondsFormat::Secs, true), end_time.timestamp()],
+        ).expect("Unable to execute SQL in close_open_flows_in_db");
 }
 
+pub fn update_interface_counters_in_db(db_conn: &Connection, sender_ip: &str, counters: impl Iterator<Item = (InterfaceBucket, InterfaceCounters)>) -> rusqlite::Result<()> {
+    let mut stmt = db_conn.prepare_cached(
+        "INSERT INTO interface_stats 
+            (sender_ip, snmp_index, bucket_start, in_octets, in_pkts, out_octets, out_pkts) 
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (sender_ip, snmp_index, bucket_start) DO UPDATE SET
+            in_octets = in_octets + excluded.in_octets,
+            in_pkts = in_pkts + excluded.in_pkts,
+            out_octets = out_octets + excluded.out_octets,
+            out_pkts = out_pkts + excluded.out_pkts",
+        )?;
+    for ((snmp_index, bucket_start), c) in counters {
+        stmt.execute(params![
+            sender_ip,
+            snmp_index,
+            bucket_start,
+            c.in_octets as i64,
+            c.in_pkts as i64,
+            c.out_octets as i64,
+            c.out_pkts as i64,
+            ])?;
+    }
+    Ok(())
+}
 
-
-
+// names from the config are never replaced by names learned from options data
+pub fn update_interface_name_in_db(db_conn: &Connection, sender_ip: &str, snmp_index: u32, name: &str, description: &str, source: &str) -> rusqlite::Result<()> {
+    db_conn.execute( 
+        "INSERT INTO interfaces (sender_ip, snmp_index, name, description, source) 
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (sender_ip, snmp_index) DO UPDATE SET
+            name = excluded.name,
+            description = excluded.description,
+            source = excluded.source
+            WHERE interfaces.source != 'config' OR excluded.source = 'config'",
+        params![sender_ip, snmp_index, name, description, source],
+        )?;
+    Ok(())
+}
 
 pub fn get_all_flows_from_sender(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings) -> tabled::Table {
 
@@ -240,94 +326,50 @@ pub fn get_all_flows_from_sender(db_conn_cli: &mut Arc<Mutex<Connection>>, serve
         "protocol", 
         "src_port", 
         "dst_port", 
-        "in_pkts", 
-        "in_bytes",
+        "fwd_pkts", 
+        "fwd_bytes",
+        "rev_pkts", 
+        "rev_bytes",
+        "avg_bps",
+        "peak_bps",
         "icmp_type",
+        "tcp_flags",
+        "conn_state",
         "traffic_type",
         "created",
         ]);
     
-    let mut conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
-
-    let flow_limit = match server_settings.flow_limit {
-        FlowsToShow::Limit { flows } => flows,
-        FlowsToShow::NoLimit => 1000,
-    };
-
-    let select_statement = "SELECT * FROM flows ".to_string();
-    let filter_statement = match server_settings.unicast_only {
-        true => {
-            "WHERE traffic_type = \'Unicast\' "
-        },
-        false => {" "},
-    };
-    
-    let order_statement = match server_settings.sort_by {
-        SortBy::Bytes => { 
-            "ORDER BY in_octets DESC LIMIT ?"
-        },
-        SortBy::Pkts => {
-            "ORDER BY in_pkts DESC LIMIT ?"
-        },
-        SortBy::None => {
-            "LIMIT ?"
-        },
-    } ;
-
-    let joined_statement = select_statement + filter_statement + order_statement;
-
-    let mut stmt: rusqlite::Statement = conn.prepare(&joined_statement)
-        .expect("Unable to prepare query");
-
+    let conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
 
-    let mut rows = stmt.query(params![flow_limit])
-        .expect("Unable to query rows");
-
-    while let Some(row) = rows.next().expect("no more rows") {
-        let sender_ip: String = row.get(1).expect("Unable to open column 1");
-        //println!("sender_ip is {sender_ip}");
-        let src_addr: String = row.get(2).expect("Unable to open column 2");
-        //println!("src_addr is {src_addr}");
-        let dst_addr: String = row.get(3).expect("Unable to open column 3");
-        //println!("dst_addr is {dst_addr}");
-        let protocol: i32 = row.get(4).expect("Unable to open column 4");
-        //println!("protocol is {protocol}");
-        let src_port: i32 = row.get(5).expect("Unable to open column 5");
-        //println!("src_port is {src_port}");
-        let dst_port: i32 = row.get(6).expect("Unable to open column 6");
-        //println!("dst_port is {dst_port}");
-        let in_pkts: i32 = row.get(10).expect("Unable to open column 10");
-        //println!("in_pkts is {in_pkts}");
-        let in_bytes: i32 = row.get(11).expect("Unable to open column 11");
-        //println!("in_bytes is {in_bytes}");
-        let traffic_cast: String = row.get(17).expect("Unable to open column 17");
-
-        let created_time: String = row.get(18).expect("Unable to open column 18");
-        //println!("created_time is {created_time}");
-
-
-
-        let (icmp_type, src_port2,dst_port2) = handle_icmp_code(protocol, src_port, dst_port);
-    
-        //let ip_cast = handle_traffic_cast(&src_addr, &dst_addr);
+    //the table is shown empty when the db can't be read, the server keeps running
+    let flows = query_flows(&conn, &FlowQuery::from_settings(server_settings)).unwrap_or_else(|e| {
+        error!("Unable to read the flows for the flow table: {e}");
+        Vec::new()
+    });
+    for flow in flows {
+        let (_, src_port, dst_port) = handle_icmp_code(flow.protocol as i32, flow.src_port as i32, flow.dst_port as i32);
 
         builder.push_record([
-            sender_ip, 
-            src_addr, 
-            dst_addr, 
-            protocol.to_string(), 
-            src_port2.to_string(), 
-            dst_port2.to_string(), 
-            in_pkts.to_string(), 
-            in_bytes.to_string(),
-            icmp_type,
-            traffic_cast,
-            created_time,
+            flow.sender_ip, 
+            flow.src_addr, 
+            flow.dst_addr, 
+            flow.protocol.to_string(), 
+            src_port.to_string(), 
+            dst_port.to_string(), 
+            flow.fwd_pkts.to_string(), 
+            flow.fwd_octets.to_string(),
+            flow.rev_pkts.to_string(), 
+            flow.rev_octets.to_string(),
+            flow.avg_bps.to_string(),
+            flow.peak_bps.to_string(),
+            flow.icmp_type,
+            flow.tcp_flags.map(format_tcp_flags).unwrap_or_default(),
+            flow.conn_state.unwrap_or_default(),
+            flow.traffic_type,
+            flow.created_time.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_default(),
             ]);
-
     }
 
-
     let mut table = builder.build();
     table.with(Style::ascii_rounded());
     table
@@ -335,155 +377,134 @@ pub fn get_all_flows_from_sender(db_conn_cli: &mut Arc<Mutex<Connection>>, serve
 }
 
 
-pub fn get_all_hosts_as_json(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings) -> String {
-
-    let mut all_hosts: Vec<String> = Vec::new();
-   
-    let mut conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
-
-    let flow_limit = 10;
-
-    let select_statement = "SELECT src_ip FROM flows ".to_string();
-
-
-    let mut stmt: rusqlite::Statement = conn.prepare(&select_statement)
-        .expect("Unable to prepare query");
+pub fn get_interface_table(db_conn_cli: &mut Arc<Mutex<Connection>>) -> tabled::Table {
 
+    let mut builder = Builder::new();
+    builder.push_record([
+        "sender_ip", 
+        "snmp_index", 
+        "name", 
+        "in_bytes", 
+        "in_pkts", 
+        "in_bps", 
+        "out_bytes", 
+        "out_pkts", 
+        "out_bps", 
+        ]);
 
-    let mut rows = stmt.query(params![flow_limit])
-        .expect("Unable to query rows");
-
-       while let Some(row) = rows.next().expect("no more rows") {
-          let src_addr: String = row.get(2).expect("Unable to open column 2");
-         // build json here and add it to vec
-            all_hosts.push(src_addr);
-    
-        }
+    let conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
+    let all_stats = query_interface_stats(&conn, &Local::now()).unwrap_or_else(|e| {
+        error!("Unable to read the interface stats: {e}");
+        Vec::new()
+    });
+    for s in all_stats {
+        builder.push_record([
+            s.sender_ip,
+            s.snmp_index.to_string(),
+            s.name,
+            s.in_octets.to_string(),
+            s.in_pkts.to_string(),
+            s.in_bps.to_string(),
+            s.out_octets.to_string(),
+            s.out_pkts.to_string(),
+            s.out_bps.to_string(),
+            ]);
+    }
 
-    serde_json::to_string(&all_hosts).unwrap()
+    let mut table = builder.build();
+    table.with(Style::ascii_rounded());
+    table
 
 }
 
 
+//an exporter is silent once it has been quiet for longer than its expected interval
+pub fn get_sender_stats(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings, current_time: &DateTime<Local>) -> Vec<SenderStats> {
+    let conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
+    query_senders(&conn, server_settings, current_time).unwrap_or_else(|e| {
+        error!("Unable to read the sender stats: {e}");
+        Vec::new()
+    })
+}
 
+pub fn get_sender_table(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings) -> tabled::Table {
+    let current_time = Local::now();
+    let seconds_ago = |time: Option<DateTime<Local>>| match time {
+        Some(time) => format!("{}s ago", (current_time - time).num_seconds()),
+        None => "never".to_string(),
+    };
 
+    let mut builder = Builder::new();
+    builder.push_record([
+        "sender_ip",
+        "status",
+        "packets",
+        "bytes",
+        "records",
+        "skipped",
+        "parse_errors",
+        "template_refreshes",
+        "last_seen",
+        "last_template",
+        ]);
 
-// pub fn get_all_flows_as_json(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings) -> String {
+    for s in get_sender_stats(db_conn_cli, server_settings, &current_time) {
+        builder.push_record([
+            s.sender_ip,
+            if s.silent { "silent".to_string() } else { "ok".to_string() },
+            s.packets.to_string(),
+            s.bytes.to_string(),
+            s.records_decoded.to_string(),
+            s.records_skipped.to_string(),
+            s.parse_errors.to_string(),
+            s.template_refreshes.to_string(),
+            seconds_ago(s.last_seen),
+            seconds_ago(s.last_template),
+            ]);
+    }
 
-//     let mut all_flows: Vec<NetflowJson> = Vec::new();
-   
-//     let mut conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
+    let mut table = builder.build();
+    table.with(Style::ascii_rounded());
+    table
 
-//     let flow_limit = match server_settings.flow_limit {
-//         FlowsToShow::Limit { flows } => flows,
-//         FlowsToShow::NoLimit => 1000,
-//     };
+}
 
-//     let select_statement = "SELECT * FROM flows ".to_string();
-//     let filter_statement = match server_settings.unicast_only {
-//         true => {
-//             "WHERE traffic_type = \'Unicast\'"
-//         },
-//         false => {""},
-//     };
-    
-//     let order_statement = match server_settings.sort_by {
-//         SortBy::Bytes => { 
-//             " ORDER BY in_octets DESC LIMIT ?"
-//         },
-//         SortBy::Pkts => {
-//             "SELECT * FROM flows ORDER BY in_pkts DESC LIMIT ?"
-//         },
-//         SortBy::None => {
-//             "SELECT * FROM flows LIMIT ?"
-//         },
-//     } ;
-
-//     let joined_statement = select_statement + filter_statement + order_statement;
-
-//     let mut stmt: rusqlite::Statement = conn.prepare(&joined_statement)
-//         .expect("Unable to prepare query");
-
-
-//     let mut rows = stmt.query(params![flow_limit])
-//         .expect("Unable to query rows");
-
-//        while let Some(row) = rows.next().expect("no more rows") {
-//           let sender_ip: String = row.get(1).expect("Unable to open column 1");
-//           //println!("sender_ip is {sender_ip}");
-//           let src_addr: String = row.get(2).expect("Unable to open column 2");
-//           //println!("src_addr is {src_addr}");
-//           let dst_addr: String = row.get(3).expect("Unable to open column 3");
-//           //println!("dst_addr is {dst_addr}");
-//           let proto: i32 = row.get(4).expect("Unable to open column 4");
-//           //println!("protocol is {protocol}");
-//           let src_port: i32 = row.get(5).expect("Unable to open column 5");
-//           //println!("src_port is {src_port}");
-//           let dst_port: i32 = row.get(6).expect("Unable to open column 6");
-//           //println!("dst_port is {dst_port}");
-//           let in_pkts: i32 = row.get(10).expect("Unable to open column 10");
-//           //println!("in_pkts is {in_pkts}");
-//           let in_bytes: i32 = row.get(11).expect("Unable to open column 11");
-//           //println!("in_bytes is {in_bytes}");
-//           let traffic_cast: String = row.get(17).expect("Unable to open column 17");
-
-//           let (icmp_type, src_port2,dst_port2) = handle_icmp_code(proto, src_port, dst_port);
-       
-//           //let ip_cast = handle_traffic_cast(&src_addr, &dst_addr);
-//           let unicast_str: String = String::from("Unicast");
-//           let multicast_str: String = String::from("Multicast");
-//           let broadcast_str: String = String::from("Broadcoast");
-
-//           let traffic_type_as_enum: TrafficType = match traffic_cast {
-//             unicast_str => TrafficType::Unicast,
-//             multicast_str => TrafficType::Multicast,
-//             broadcast_str => TrafficType::Broadcast,
-//             _ => TrafficType::Unicast,
-//           };
-
-//         // let current_flow: NetFlow =  NetFlow {
-//         //     src_and_dst_ip: (convert_string_to_ipv4(&src_addr).unwrap(), convert_string_to_ipv4(&dst_addr).unwrap()),
-//         //     src_and_dst_port: (src_port.try_into().unwrap(), dst_port.try_into().unwrap()),
-//         //     protocol: proto.try_into().unwrap(),
-//         //     in_octets: in_bytes.try_into().unwrap(),
-//         //     in_packets: in_pkts.try_into().unwrap(),
-//         //     in_db: true,
-//         //     traffic_type: traffic_type_as_enum,
-//         // };
-
-//         let current_flow = NetflowJson {
-//             flow_src_ip: src_addr,
-//             flow_bytes: in_bytes,
-//         };
-//         //{"src_ip": "192.168.1.1", "bytes": 1000}
-
-//         // build json here and add it to vec
-//         all_flows.push(current_flow);
-
-//     }
-
-//     serde_json::to_string(&all_flows).unwrap()
-
-// }
-
-
-
-// pub fn get_all_senders_in_db() {
-//             // {
-//         //     let mut conn: MutexGuard<Connection> = db_conn_cli.lock().unwrap();
-//         //     let mut stmt: rusqlite::Statement = conn.prepare("SELECT * FROM senders")
-//         //         .expect("Unable to prepare query");
-
-//         //     let mut rows = stmt.query([])
-//         //         .expect("Unable to query rows");
-
-//         //    while let Some(row) = rows.next().expect("no more rows") {
-//         //       let ip_from_db: String = row.get(0).expect("Unable to open column 0");
-//         //       //println!("ip_from_db is {ip_from_db}");
-//         //     }
-//         // }
-// }
 
+//the same flows as the cli table, use json::write_flows_json to stream a bigger result
+pub fn get_all_flows_as_json(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings) -> String {
+    let mut json = Vec::new();
+    let written = write_flows_json(db_conn_cli, &FlowQuery::from_settings(server_settings), JsonFormat::Array, &mut json);
+    json_or_empty_array(written, json, "flows")
+}
 
+//the writers only fail on a db error when writing to memory, the output is cut short then so an empty array is returned
+fn json_or_empty_array(written: std::io::Result<usize>, json: Vec<u8>, rows: &str) -> String {
+    match written {
+        Ok(_) => String::from_utf8(json).expect("Unable to convert the json to a string"),
+        Err(e) => {
+            error!("Unable to read the {rows} for the json output: {e}");
+            "[]".to_string()
+        },
+    }
+}
 
+//every address on either end of the flows the cli shows, with its totals, busiest first
+pub fn get_all_hosts_as_json(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings) -> String {
+    let flow_query = FlowQuery::from_settings(server_settings);
+    let query = AggregateQuery {
+        group_by: GroupBy::Host,
+        filter: flow_query.filter,
+        sort: flow_query.sort,
+        limit: flow_query.limit,
+        offset: 0,
+    };
+    let mut json = Vec::new();
+    let written = write_aggregates_json(db_conn_cli, &query, JsonFormat::Array, &mut json);
+    json_or_empty_array(written, json, "hosts")
+}
 
+pub fn get_all_senders_as_json(db_conn_cli: &mut Arc<Mutex<Connection>>, server_settings: &ServerSettings) -> String {
+    let mut json = Vec::new();
+    let written = write_senders_json(db_conn_cli, server_settings, &Local::now(), JsonFormat::Array, &mut json);
+    json_or_empty_array(written, json, "senders")
+}
@@ -1,9 +1,11 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::net::Ipv4Addr;
 
-use chrono::TimeDelta;
 use serde::Serialize;
 use chrono::prelude::*;
 
+use crate::dedup::*;
 use crate::fields::*;
 use crate::time::*;
 
@@ -62,14 +64,37 @@ pub struct NetflowTemplate {
     pub direction: Option<U8Field>,
     pub in_dst_mac: Option<U64Field>,
     pub out_src_mac: Option<U64Field>,
+    pub ingress_vrf_id: Option<U32Field>,
     //if_name: u64, //not sure since it's specified in the template
     //if_desc: u64, //not sure since it's specified in the template
-    in_permanent_bytes: Option<U32Field>, /// Can be higher
-    in_permanent_pkts: Option<U32Field>, /// Can be higher
-    fragment_offset: Option<U16Field>,
-    forwarding_status: Option<U8Field>,
-    replication_factor: Option<U32Field>,
+    pub in_permanent_bytes: Option<U32Field>, /// Can be higher
+    pub in_permanent_pkts: Option<U32Field>, /// Can be higher
+    pub fragment_offset: Option<U16Field>,
+    pub forwarding_status: Option<U8Field>,
+    pub replication_factor: Option<U32Field>,
     //nothing for l2_packet section yet
+    //copied from the packet header so the switched times can be converted to wall clock time
+    pub sys_uptime: Option<U32Field>,
+    pub unix_secs: Option<U32Field>,
+}
+
+impl NetflowTemplate {
+    //returns the first and last switched times of a record, or the fallback when the exporter doesn't send them
+    pub fn switched_times(&self, fallback: DateTime<Local>) -> (DateTime<Local>, DateTime<Local>) {
+        let (sys_uptime, unix_secs) = match (self.sys_uptime, self.unix_secs) {
+            (Some(U32Field::Value(u)), Some(U32Field::Value(s))) => (u, s),
+            _ => return (fallback, fallback),
+        };
+        let first = match self.first_switched {
+            Some(U32Field::Value(v)) => convert_uptime_to_time(unix_secs, sys_uptime, v),
+            _ => fallback,
+        };
+        let last = match self.last_switched {
+            Some(U32Field::Value(v)) => convert_uptime_to_time(unix_secs, sys_uptime, v),
+            _ => fallback,
+        };
+        (first, last)
+    }
 }
 
 #[derive(Default, Clone, Serialize)]
@@ -78,12 +103,106 @@ pub struct NetFlowDelta {
     pub updated_time: DateTime<Local>,
     pub in_octets: i64,
     pub in_pkts: i64,
+    //bits and packets per second over the first/last switched interval of the record
     pub bps: i64,
     pub pps: i64,
 }
 
+//flow rates are worked out per minute, the peak is the busiest minute
+pub const RATE_BUCKET_SEC: i64 = 60;
+//records are rarely exported more than a few minutes late, older buckets are dropped
+const RATE_BUCKETS_TO_KEEP: i64 = 5;
+//a record with the same first and last switched time is counted as lasting a second
+const MIN_RATE_DURATION_MS: i64 = 1000;
+
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct RateBucket {
+    pub octets: f64,
+    pub packets: f64,
+    //the part of the bucket the flow was active in
+    pub start_ms: i64,
+    pub end_ms: i64,
+}
+
+//a record that opens a tcp conversation, a SYN-ACK is the reply
+pub fn is_initial_syn(tcp_flags: u8) -> bool {
+    tcp_flags & TCP_SYN != 0 && tcp_flags & TCP_ACK == 0
+}
+
+pub fn get_record_rate(octets: u32, packets: u32, first: DateTime<Local>, last: DateTime<Local>) -> (i64, i64) {
+    let duration_ms = (last - first).num_milliseconds().max(MIN_RATE_DURATION_MS);
+    (i64::from(octets) * 8000 / duration_ms, i64::from(packets) * 1000 / duration_ms)
+}
+
+//identifies a single conversation on a single exporter
+//the endpoints are ordered so both directions of a conversation produce the same key
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize)]
+pub struct FlowKey {
+    pub sender_ip: Ipv4Addr,
+    pub protocol: u8,
+    pub vrf_id: u32,
+    pub low_ip: Ipv4Addr,
+    pub low_port: u16,
+    pub low_vlan: u16,
+    pub high_ip: Ipv4Addr,
+    pub high_port: u16,
+    pub high_vlan: u16,
+}
+
+//an endpoint is (ip, port, vlan)
+impl FlowKey {
+    pub fn new(sender_ip: Ipv4Addr, protocol: u8, vrf_id: u32, src: (Ipv4Addr, u16, u16), dst: (Ipv4Addr, u16, u16)) -> Self {
+        let (low, high) = if (src.0, src.1) <= (dst.0, dst.1) {
+            (src, dst)
+        }
+        else {
+            (dst, src)
+        };
+        FlowKey {
+            sender_ip,
+            protocol,
+            vrf_id,
+            low_ip: low.0,
+            low_port: low.1,
+            low_vlan: low.2,
+            high_ip: high.0,
+            high_port: high.1,
+            high_vlan: high.2,
+        }
+    }
+
+    //the same conversation seen by any exporter, vlans are left out because they change hop by hop
+    //the vrf is kept, the same addresses in two vrfs are two different conversations
+    pub fn conversation_key(&self) -> FlowKey {
+        FlowKey {
+            sender_ip: Ipv4Addr::UNSPECIFIED,
+            low_vlan: 0,
+            high_vlan: 0,
+            ..*self
+        }
+    }
+}
+
+//this is the value stored in the flow_key column of the flows table
+impl fmt::Display for FlowKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}|{}|{}|{}:{}/{}|{}:{}/{}",
+            self.sender_ip,
+            self.protocol,
+            self.vrf_id,
+            self.low_ip,
+            self.low_port,
+            self.low_vlan,
+            self.high_ip,
+            self.high_port,
+            self.high_vlan,
+        )
+    }
+}
+
 #[derive(Clone, Serialize)]
 pub struct NetFlow {
+    pub sender_ip: Ipv4Addr,
     //pub src_and_dst_ip: (Ipv4Addr, Ipv4Addr),
     pub src_ip: Ipv4Addr,
     pub dst_ip: Ipv4Addr,
@@ -91,50 +210,246 @@ pub struct NetFlow {
     pub dst_port: u16,
     //pub src_and_dst_port: (u16, u16),
     pub protocol: u8,
-    pub in_octets: u32,
-    pub in_packets: u32,
-    pub in_db: bool,
+    pub src_vlan: u16,
+    pub dst_vlan: u16,
+    //from the first record, 0 when the exporter doesn't send them
+    pub src_mask: u8,
+    pub dst_mask: u8,
+    pub vrf_id: u32,
+    //forward is the side that initiated the conversation, reverse is the reply
+    //summed over the life of the flow, a long flow goes past what a single record can hold
+    pub in_octets: u64,
+    pub in_packets: u64,
+    pub rev_octets: u64,
+    pub rev_packets: u64,
+    pub tcp_flags: u8,
+    pub rev_tcp_flags: u8,
+    //the first record of the direction had a SYN without an ACK, the flags above are OR-ed so they can't tell
+    pub fwd_initial_syn: bool,
+    pub rev_initial_syn: bool,
+    pub first_seen: DateTime<Local>,
+    pub last_seen: DateTime<Local>,
+    pub rev_first_seen: Option<DateTime<Local>>,
+    pub rev_last_seen: Option<DateTime<Local>>,
+    //only used when dedup is enabled
+    pub fwd_observation: Option<ObservationPoint>,
+    pub rev_observation: Option<ObservationPoint>,
+    pub dedup_primary: bool,
+    //the row in the flows table, only known to the db writer
+    pub db_id: Option<i64>,
     pub traffic_type: TrafficType,
-    pub needs_db_update: bool,
     pub deltas: Vec<NetFlowDelta>,
+    //keyed by bucket start in unix seconds
+    pub rate_buckets: BTreeMap<i64, RateBucket>,
+    pub peak_bps: i64,
+    pub peak_pps: i64,
     pub created_time: DateTime<Local>,
+    pub last_updated_time: DateTime<Local>,
+    pub end_time: Option<DateTime<Local>>,
 }
 
 impl NetFlow {
-    pub fn update_throughput(&mut self) {
-        let current_time = match &self.deltas.last() {
-            Some(s) => s.updated_time,
-            None => Local::now(),
-        };
-        let old_time = if self.deltas.len() > 2 {
-            self.deltas.get(self.deltas.len() -2).unwrap().updated_time
+    pub fn key(&self) -> FlowKey {
+        FlowKey::new(
+            self.sender_ip,
+            self.protocol,
+            self.vrf_id,
+            (self.src_ip, self.src_port, self.src_vlan),
+            (self.dst_ip, self.dst_port, self.dst_vlan),
+        )
+    }
+
+    pub fn is_forward(&self, src_ip: Ipv4Addr, src_port: u16) -> bool {
+        self.src_ip == src_ip && self.src_port == src_port
+    }
+
+    pub fn add_forward_record(&mut self, octets: u32, packets: u32, tcp_flags: u8, first: DateTime<Local>, last: DateTime<Local>) {
+        self.in_octets = self.in_octets.saturating_add(u64::from(octets));
+        self.in_packets = self.in_packets.saturating_add(u64::from(packets));
+        self.tcp_flags |= tcp_flags;
+        self.first_seen = self.first_seen.min(first);
+        self.last_seen = self.last_seen.max(last);
+    }
+
+    pub fn add_reverse_record(&mut self, octets: u32, packets: u32, tcp_flags: u8, first: DateTime<Local>, last: DateTime<Local>) {
+        if self.rev_first_seen.is_none() {
+            self.rev_initial_syn = is_initial_syn(tcp_flags);
+        }
+        self.rev_octets = self.rev_octets.saturating_add(u64::from(octets));
+        self.rev_packets = self.rev_packets.saturating_add(u64::from(packets));
+        self.rev_tcp_flags |= tcp_flags;
+        self.rev_first_seen = Some(self.rev_first_seen.map_or(first, |t| t.min(first)));
+        self.rev_last_seen = Some(self.rev_last_seen.map_or(last, |t| t.max(last)));
+    }
+
+    //input and output interface of the forward direction
+    pub fn snmp_indexes(&self) -> (u32, u32) {
+        match self.fwd_observation {
+            Some(o) => (o.input_snmp, o.output_snmp),
+            None => (0, 0),
+        }
+    }
+
+    //flags seen in either direction of the conversation
+    pub fn combined_tcp_flags(&self) -> u8 {
+        self.tcp_flags | self.rev_tcp_flags
+    }
+
+    //a reset or fin ends the conversation, a syn that was never acked is half-open
+    pub fn conn_state(&self) -> ConnState {
+        if self.protocol != 6 {
+            return ConnState::None;
+        }
+        let flags = self.combined_tcp_flags();
+        if flags & TCP_RST != 0 {
+            ConnState::Reset
+        }
+        else if flags & TCP_FIN != 0 {
+            ConnState::FinClosed
+        }
+        else if flags & TCP_ACK != 0 {
+            ConnState::Established
+        }
+        else if flags & TCP_SYN != 0 {
+            ConnState::HalfOpen
         }
         else {
-            current_time - TimeDelta::try_seconds(60).unwrap()
+            //the exporter isn't sending TCP_FLAGS
+            ConnState::None
+        }
+    }
+
+    //tells if the record is a copy of packets already counted from another observation point
+    pub fn select_observation(&mut self, is_forward: bool, observed: ObservationPoint) -> ObservationMatch {
+        if is_forward {
+            select_observation_point(&mut self.fwd_observation, observed)
+        }
+        else {
+            select_observation_point(&mut self.rev_observation, observed)
+        }
+    }
+
+    //drops what was counted in one direction, returns the octets and packets taken off
+    //used when the observation point it was counted from turns out to be a copy
+    pub fn reset_direction(&mut self, is_forward: bool) -> (u64, u64) {
+        if is_forward {
+            let counted = (self.in_octets, self.in_packets);
+            self.in_octets = 0;
+            self.in_packets = 0;
+            counted
+        }
+        else {
+            let counted = (self.rev_octets, self.rev_packets);
+            self.rev_octets = 0;
+            self.rev_packets = 0;
+            counted
+        }
+    }
+
+    //the initiator is the only side that opened with a SYN, the reply is a SYN-ACK
+    //otherwise the side that was seen first
+    pub fn orient_by_initiator(&mut self) {
+        let rev_first_seen = match self.rev_first_seen {
+            Some(t) => t,
+            None => return,
         };
-        let diff_sec = get_time_delta_in_sec(current_time, old_time); 
-        //println!("diff_sec is {}", diff_sec);
-
-        let conn_timeout_in_sec = 3900;
-        if diff_sec < conn_timeout_in_sec {
-            //calc bps and pps
-            //println!("self.deltas.last_mut().unwrap().in_octets is {}", self.deltas.last_mut().unwrap().in_octets);
-            //println!("self.deltas.last_mut().unwrap().in_pkts is {}", self.deltas.last_mut().unwrap().in_pkts);
-
-            if  self.deltas.last_mut().unwrap().in_octets > 0 {
-                self.deltas.last_mut().unwrap().bps = self.deltas.last_mut().unwrap().in_octets / diff_sec;
-            }
-            if  self.deltas.last_mut().unwrap().in_pkts > 0 {
-                self.deltas.last_mut().unwrap().pps = self.deltas.last_mut().unwrap().in_pkts / diff_sec;
-            } 
+        let reverse_initiated = if self.protocol == 6 && self.fwd_initial_syn != self.rev_initial_syn {
+            self.rev_initial_syn
         }
         else {
-            //if flow has no data for longer than 1 hr and 5 min assume it's ended
-            //println!("flow diff_sec is greater than 3900, setting pps and bps to 0");
-            self.deltas.last_mut().unwrap().pps = 0;
-            self.deltas.last_mut().unwrap().bps = 0;
+            rev_first_seen < self.first_seen
+        };
+        if reverse_initiated {
+            self.swap_direction();
+        }
+    }
+
+    fn swap_direction(&mut self) {
+        std::mem::swap(&mut self.src_ip, &mut self.dst_ip);
+        std::mem::swap(&mut self.src_port, &mut self.dst_port);
+        std::mem::swap(&mut self.src_vlan, &mut self.dst_vlan);
+        std::mem::swap(&mut self.src_mask, &mut self.dst_mask);
+        std::mem::swap(&mut self.in_octets, &mut self.rev_octets);
+        std::mem::swap(&mut self.in_packets, &mut self.rev_packets);
+        std::mem::swap(&mut self.tcp_flags, &mut self.rev_tcp_flags);
+        std::mem::swap(&mut self.fwd_initial_syn, &mut self.rev_initial_syn);
+        std::mem::swap(&mut self.fwd_observation, &mut self.rev_observation);
+        //orient_by_initiator only swaps once the reverse side has been seen
+        let rev_first_seen = self.rev_first_seen.replace(self.first_seen).unwrap_or(self.first_seen);
+        let rev_last_seen = self.rev_last_seen.replace(self.last_seen).unwrap_or(self.last_seen);
+        self.first_seen = rev_first_seen;
+        self.last_seen = rev_last_seen;
+    }
+
+    //rates come from the switched times of the record, not from when the export packet arrived
+    pub fn update_throughput(&mut self, octets: u32, packets: u32, first: DateTime<Local>, last: DateTime<Local>) {
+        let (bps, pps) = get_record_rate(octets, packets, first, last);
+        if let Some(last_delta) = self.deltas.last_mut() {
+            last_delta.bps = bps;
+            last_delta.pps = pps;
+        }
+        self.add_to_rate_buckets(octets, packets, first, last);
+    }
+
+    //a record that spans several buckets is split by how much of its interval falls in each one
+    fn add_to_rate_buckets(&mut self, octets: u32, packets: u32, first: DateTime<Local>, last: DateTime<Local>) {
+        let bucket_ms = RATE_BUCKET_SEC * 1000;
+        let first_ms = first.timestamp_millis();
+        let last_ms = last.timestamp_millis().max(first_ms);
+        let total_ms = last_ms - first_ms;
+        //only the newest buckets are kept, so a record spanning more than that is only split over them
+        //an exporter with a wrong clock or a wrapped uptime can send records that span years
+        let split_from_ms = first_ms.max(last_ms - (RATE_BUCKETS_TO_KEEP + 1) * bucket_ms);
+
+        let mut bucket_start = split_from_ms - split_from_ms.rem_euclid(bucket_ms);
+        while bucket_start <= last_ms {
+            let start_ms = first_ms.max(bucket_start);
+            let end_ms = last_ms.min(bucket_start + bucket_ms);
+            let share = if total_ms == 0 { 1.0 } else { (end_ms - start_ms) as f64 / total_ms as f64 };
+
+            let bucket = self.rate_buckets.entry(bucket_start / 1000).or_insert(RateBucket {
+                start_ms,
+                end_ms,
+                ..Default::default()
+            });
+            bucket.octets += f64::from(octets) * share;
+            bucket.packets += f64::from(packets) * share;
+            bucket.start_ms = bucket.start_ms.min(start_ms);
+            bucket.end_ms = bucket.end_ms.max(end_ms);
+
+            //the rate is over the part of the bucket the flow was active in, so short bursts aren't averaged away
+            let active_ms = (bucket.end_ms - bucket.start_ms).max(MIN_RATE_DURATION_MS) as f64;
+            self.peak_bps = self.peak_bps.max((bucket.octets * 8000.0 / active_ms) as i64);
+            self.peak_pps = self.peak_pps.max((bucket.packets * 1000.0 / active_ms) as i64);
+
+            bucket_start += bucket_ms;
+        }
+
+        if let Some(&newest) = self.rate_buckets.keys().next_back() {
+            self.rate_buckets.retain(|bucket, _| *bucket >= newest - RATE_BUCKETS_TO_KEEP * RATE_BUCKET_SEC);
         }
     }
+
+    //average over the whole flow, both directions included
+    pub fn average_rate(&self) -> (i64, i64) {
+        let first = self.rev_first_seen.map_or(self.first_seen, |t| t.min(self.first_seen));
+        let last = self.rev_last_seen.map_or(self.last_seen, |t| t.max(self.last_seen));
+        let duration_ms = (last - first).num_milliseconds().max(MIN_RATE_DURATION_MS);
+        //the counters are u64, a rate that doesn't fit in the db's i64 is kept at the largest one that does
+        let octets = i64::try_from(self.in_octets.saturating_add(self.rev_octets)).unwrap_or(i64::MAX);
+        let packets = i64::try_from(self.in_packets.saturating_add(self.rev_packets)).unwrap_or(i64::MAX);
+        (octets.saturating_mul(8000) / duration_ms, packets.saturating_mul(1000) / duration_ms)
+    }
+
+    //the deltas are in the db once the flow has been written
+    pub fn clear_deltas(&mut self) {
+        self.deltas.clear();
+    }
+
+    pub fn is_expired(&self, current_time: DateTime<Local>, active_timeout_sec: i64, inactive_timeout_sec: i64) -> bool {
+        get_time_delta_in_sec(current_time, self.created_time) >= active_timeout_sec
+            || get_time_delta_in_sec(current_time, self.last_updated_time) >= inactive_timeout_sec
+    }
 }
 
 
@@ -142,7 +457,7 @@ impl NetFlow {
 #[derive(Clone, Serialize)]
 pub struct NetflowBytesJson {
     pub flow_src_ip: String,
-    pub flow_bytes: i32,
+    pub flow_bytes: i64,
 }
 
 #[derive(Clone, Serialize)]
@@ -153,15 +468,15 @@ pub struct NetflowIpsJson {
 #[derive(Clone, Serialize)]
 pub struct NetflowPacketsJson {
     pub flow_src_ip: String,
-    pub flow_packets: i32,
+    pub flow_packets: i64,
 }
 
 #[derive(Clone, Serialize)]
 pub struct NetflowPortsAndProtocolsJson {
-    pub flow_src_port: i32,
-    pub flow_dst_port: i32,
-    pub flow_protocol: i32,
-    pub flow_bytes: i32,
+    pub flow_src_port: i64,
+    pub flow_dst_port: i64,
+    pub flow_protocol: i64,
+    pub flow_bytes: i64,
 }
 
 #[derive(Clone, Serialize)]
@@ -171,9 +486,10 @@ pub struct NetFlowJson {
     pub src_port: u16,
     pub dst_port: u16,
     pub protocol: u8,
-    pub in_octets: u32,
-    pub in_packets: u32,
-    pub traffic_type: TrafficType,
+    pub in_octets: i64,
+    pub in_packets: i64,
+    //as stored, dbs from before the finer traffic types have Unicast
+    pub traffic_type: String,
     pub icmp: String,
     pub created_time: String,
 }
@@ -190,4 +506,178 @@ pub struct NetflowPacket {
     pub flowset_id: u16,
     pub flow_length: u16,
     pub flow_template: Option<NetflowTemplate>,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_sec(sec: i64) -> DateTime<Local> {
+        DateTime::from_timestamp(1_700_000_000 + sec, 0).unwrap().with_timezone(&Local)
+    }
+
+    //a flow as senders.rs creates it from its first record
+    fn new_flow(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), tcp_flags: u8, first: DateTime<Local>) -> NetFlow {
+        NetFlow {
+            sender_ip: Ipv4Addr::new(192, 168, 0, 1),
+            src_ip: src.0,
+            dst_ip: dst.0,
+            src_port: src.1,
+            dst_port: dst.1,
+            protocol: 6,
+            src_vlan: 0,
+            dst_vlan: 0,
+            src_mask: 0,
+            dst_mask: 0,
+            vrf_id: 0,
+            in_octets: 100,
+            in_packets: 1,
+            rev_octets: 0,
+            rev_packets: 0,
+            tcp_flags,
+            rev_tcp_flags: 0,
+            fwd_initial_syn: is_initial_syn(tcp_flags),
+            rev_initial_syn: false,
+            first_seen: first,
+            last_seen: first,
+            rev_first_seen: None,
+            rev_last_seen: None,
+            fwd_observation: None,
+            rev_observation: None,
+            dedup_primary: true,
+            db_id: None,
+            traffic_type: TrafficType::Private,
+            deltas: Vec::new(),
+            rate_buckets: BTreeMap::new(),
+            peak_bps: 0,
+            peak_pps: 0,
+            created_time: first,
+            last_updated_time: first,
+            end_time: None,
+        }
+    }
+
+    const CLIENT: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 50000);
+    const SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 2), 443);
+
+    #[test]
+    fn record_spanning_a_year_only_fills_the_kept_buckets() {
+        let last = time_sec(0);
+        let first = last - chrono::Duration::days(365);
+        let mut flow = new_flow(CLIENT, SERVER, 0, first);
+        flow.update_throughput(1_000_000, 1000, first, last);
+
+        assert!(flow.rate_buckets.len() as i64 <= RATE_BUCKETS_TO_KEEP + 2);
+        let octets: f64 = flow.rate_buckets.values().map(|b| b.octets).sum();
+        assert!(octets < 100.0);
+        assert!(flow.peak_bps < 10);
+    }
+
+    #[test]
+    fn client_record_first_keeps_the_client_forward() {
+        let mut flow = new_flow(CLIENT, SERVER, TCP_SYN, time_sec(0));
+        flow.add_reverse_record(200, 2, TCP_SYN | TCP_ACK, time_sec(1), time_sec(1));
+        flow.orient_by_initiator();
+        flow.add_forward_record(300, 3, TCP_ACK | TCP_PSH, time_sec(2), time_sec(3));
+        flow.orient_by_initiator();
+
+        assert_eq!((flow.src_ip, flow.src_port), CLIENT);
+        assert_eq!((flow.in_octets, flow.rev_octets), (400, 200));
+    }
+
+    #[test]
+    fn server_record_first_is_flipped_to_the_client() {
+        //the server's syn-ack record is exported first and even claims to have started first
+        let mut flow = new_flow(SERVER, CLIENT, TCP_SYN | TCP_ACK, time_sec(0));
+        flow.add_reverse_record(300, 3, TCP_SYN, time_sec(1), time_sec(1));
+        flow.orient_by_initiator();
+
+        assert_eq!((flow.src_ip, flow.src_port), CLIENT);
+        assert_eq!((flow.dst_ip, flow.dst_port), SERVER);
+        assert_eq!((flow.in_octets, flow.rev_octets), (300, 100));
+        assert!(flow.fwd_initial_syn && !flow.rev_initial_syn);
+        assert_eq!(flow.first_seen, time_sec(1));
+        assert_eq!(flow.rev_first_seen, Some(time_sec(0)));
+
+        //later records of both sides have syn and ack or-ed in, the orientation has to stay
+        flow.add_forward_record(100, 1, TCP_ACK | TCP_FIN, time_sec(2), time_sec(2));
+        flow.orient_by_initiator();
+        assert_eq!((flow.src_ip, flow.src_port), CLIENT);
+    }
+
+    #[test]
+    fn counters_go_past_what_a_record_can_hold() {
+        let mut flow = new_flow(CLIENT, SERVER, TCP_SYN, time_sec(0));
+        flow.add_forward_record(u32::MAX, u32::MAX, TCP_ACK, time_sec(1), time_sec(1));
+        flow.add_reverse_record(u32::MAX, 1, TCP_ACK, time_sec(1), time_sec(1));
+        flow.add_reverse_record(u32::MAX, 1, TCP_ACK, time_sec(2), time_sec(2));
+
+        assert_eq!((flow.in_octets, flow.in_packets), (u64::from(u32::MAX) + 100, u64::from(u32::MAX) + 1));
+        assert_eq!(flow.rev_octets, 2 * u64::from(u32::MAX));
+        assert!(flow.average_rate().0 > 0);
+    }
+
+    #[test]
+    fn rate_of_a_huge_flow_is_capped_instead_of_wrapping() {
+        let mut flow = new_flow(CLIENT, SERVER, TCP_SYN, time_sec(0));
+        flow.add_forward_record(100, 1, TCP_ACK, time_sec(1), time_sec(1));
+        flow.in_octets = u64::MAX;
+        flow.rev_octets = 1;
+        let (bps, pps) = flow.average_rate();
+        assert_eq!(bps, i64::MAX / 1000);
+        assert!(pps > 0);
+    }
+
+    #[test]
+    fn no_syn_falls_back_to_first_seen() {
+        let mut flow = new_flow(SERVER, CLIENT, TCP_ACK, time_sec(5));
+        flow.add_reverse_record(100, 1, TCP_ACK, time_sec(1), time_sec(6));
+        flow.orient_by_initiator();
+
+        assert_eq!((flow.src_ip, flow.src_port), CLIENT);
+    }
+
+    const EXPORTER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+
+    fn key(protocol: u8, vrf_id: u32, src: (Ipv4Addr, u16, u16), dst: (Ipv4Addr, u16, u16)) -> FlowKey {
+        FlowKey::new(EXPORTER, protocol, vrf_id, src, dst)
+    }
+
+    #[test]
+    fn tcp_and_udp_on_the_same_port_are_different_flows() {
+        let client = (CLIENT.0, 40000, 0);
+        let dns = (SERVER.0, 53, 0);
+
+        assert_ne!(key(6, 0, client, dns), key(17, 0, client, dns));
+    }
+
+    #[test]
+    fn both_directions_have_the_same_key() {
+        let client = (CLIENT.0, CLIENT.1, 0);
+        let server = (SERVER.0, SERVER.1, 0);
+
+        assert_eq!(key(6, 0, client, server), key(6, 0, server, client));
+        assert_eq!(key(6, 0, client, server).to_string(), key(6, 0, server, client).to_string());
+    }
+
+    #[test]
+    fn different_exporters_have_different_keys() {
+        let client = (CLIENT.0, CLIENT.1, 0);
+        let server = (SERVER.0, SERVER.1, 0);
+        let other = FlowKey::new(Ipv4Addr::new(192, 168, 0, 2), 6, 0, client, server);
+
+        assert_ne!(key(6, 0, client, server), other);
+        assert_eq!(key(6, 0, client, server).conversation_key(), other.conversation_key());
+    }
+
+    #[test]
+    fn same_five_tuple_in_different_vlans_or_vrfs_are_different_flows() {
+        let client = (CLIENT.0, CLIENT.1, 0);
+        let server = (SERVER.0, SERVER.1, 0);
+        let base = key(6, 0, client, server);
+
+        assert_ne!(base, key(6, 0, (CLIENT.0, CLIENT.1, 10), server));
+        assert_ne!(base, key(6, 0, client, (SERVER.0, SERVER.1, 20)));
+        assert_ne!(base, key(6, 7, client, server));
+    }
+}
@@ -1,5 +1,4 @@
 use chrono::prelude::*;
-use chrono::TimeDelta;
 
 pub fn convert_str_to_time(time_string: String) -> DateTime<FixedOffset> {
     DateTime::parse_from_rfc3339(time_string.as_str()).unwrap()
@@ -20,4 +19,14 @@ pub fn get_time_delta_in_sec(current_time: DateTime<Local>, old_time: DateTime<L
     let diff = current_time - old_time;
     diff.num_seconds()
 
-}
\ No newline at end of file
+}
+
+// netflow v9 switched times are the exporter uptime in ms when the first/last packet was seen
+// the header gives the uptime and wall clock at export, so the difference gives the wall clock of the packet
+pub fn convert_uptime_to_time(unix_secs: u32, sys_uptime: u32, switched: u32) -> DateTime<Local> {
+    let export_time_ms = i64::from(unix_secs) * 1000;
+    let age_ms = i64::from(sys_uptime.wrapping_sub(switched));
+    let switched_time = DateTime::from_timestamp_millis(export_time_ms - age_ms)
+        .unwrap_or_default();
+    switched_time.with_timezone(&Local)
+}
@@ -3,6 +3,8 @@ use std::io::{Error, ErrorKind, Result};
 use std::net::SocketAddr;
 use std::str::FromStr;
 
+use log::warn;
+
 use crate::templates::*;
 use crate::fields::*;
 
@@ -25,8 +27,8 @@ pub fn convert_socket_to_ipv4(source_address: SocketAddr) -> Ipv4Addr {
         .expect("Unable to convert string to ipv4")
 }
 
-pub fn convert_string_to_ipv4(ip_string: &String) -> std::result::Result<Ipv4Addr, AddrParseError> {
-     Ipv4Addr::from_str(ip_string.as_str().trim())
+pub fn convert_string_to_ipv4(ip_string: &str) -> std::result::Result<Ipv4Addr, AddrParseError> {
+     Ipv4Addr::from_str(ip_string.trim())
 }
 
 pub fn convert_ipv4_to_string(ip: Ipv4Addr) -> String {
@@ -36,43 +38,104 @@ pub fn convert_ipv4_to_string(ip: Ipv4Addr) -> String {
 
 pub fn get_ip_cast_type(ip: Ipv4Addr) -> TrafficType {
 
-    if ip.is_broadcast() { 
+    if ip.octets()[0] == 0 {
+        TrafficType::Unspecified
+    }
+    else if ip.is_broadcast() { 
         TrafficType::Broadcast
     }
     else if ip.is_multicast() { 
         TrafficType::Multicast
     }
+    else if ip.is_loopback() {
+        TrafficType::Loopback
+    }
+    else if ip.is_link_local() {
+        TrafficType::LinkLocal
+    }
+    else if ip.is_documentation() {
+        TrafficType::Documentation
+    }
+    else if Ipv4Subnet::new(Ipv4Addr::new(192, 88, 99, 0), 24).contains(ip) {
+        TrafficType::Anycast
+    }
+    else if ip.is_private() {
+        TrafficType::Private
+    }
     else {
-        TrafficType::Unicast
+        TrafficType::Public
     }
 
 }
 
-// pub fn is_flow_match(flow_ip: (Ipv4Addr, Ipv4Addr), pkt_ip: (Ipv4Addr, Ipv4Addr), flow_port: (u16, u16), pkt_port: (u16, u16)) -> bool {
-//     // Matches IPs bidirectionally
-//    let ip_match = (flow.src_ip == pkt_src_ip && flow.dst_ip == pkt_dst_ip) || 
-//    (flow.src_ip == pkt_dst_ip && flow.dst_ip == pkt_src_ip);
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ipv4Subnet {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+}
 
-//    // Matches Ports bidirectionally
-//    let port_match = (flow_port.0 == pkt_port.0 && flow_port.1 == pkt_port.1) || 
-//        (flow_port.0 == pkt_port.1 && flow_port.1 == pkt_port.0);
+impl Ipv4Subnet {
+    pub fn new(network: Ipv4Addr, prefix_len: u8) -> Self {
+        let prefix_len = prefix_len.min(32);
+        Ipv4Subnet {
+            network: Ipv4Addr::from(u32::from(network) & prefix_to_mask(prefix_len)),
+            prefix_len,
+        }
+    }
 
-//    ip_match && port_match
-// }
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & prefix_to_mask(self.prefix_len) == u32::from(self.network)
+    }
 
-pub fn is_flow_match(flow_src_ip: Ipv4Addr, flow_dst_ip: Ipv4Addr, pkt_src_ip: Ipv4Addr, pkt_dst_ip: Ipv4Addr, 
-    flow_src_port: u16, flow_dst_port: u16, pkt_src_port: u16, pkt_dst_port: u16) -> bool {
-        // Matches IPs bidirectionally
-        let ip_match = (flow_src_ip == pkt_src_ip && flow_dst_ip == pkt_dst_ip) || 
-        (flow_src_ip == pkt_dst_ip && flow_dst_ip == pkt_src_ip);
+    //point to point /31s and host /32s have no broadcast address
+    pub fn broadcast(&self) -> Option<Ipv4Addr> {
+        if self.prefix_len == 0 || self.prefix_len > 30 {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from(self.network) | !prefix_to_mask(self.prefix_len)))
+    }
+}
 
-        // Matches Ports bidirectionally
-        let port_match = (flow_src_port == pkt_src_port && flow_dst_port == pkt_dst_port) || 
-            (flow_src_port == pkt_dst_port && flow_dst_port == pkt_src_port);
+pub fn prefix_to_mask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        p => u32::MAX << (32 - u32::from(p.min(32))),
+    }
+}
 
-        ip_match && port_match
+//parses "local_subnets: 10.0.0.0/24; 192.168.1.0/24" from config.ini, an address without a prefix is a /32
+//an entry that isn't an address or a subnet is logged and left out
+pub fn parse_subnets(value: &str) -> Vec<Ipv4Subnet> {
+    let mut subnets = Vec::new();
+    for entry in value.split(';') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let subnet = match entry.split_once('/') {
+            Some((network, prefix_len)) => match (convert_string_to_ipv4(network.trim()), prefix_len.trim().parse::<u8>()) {
+                (Ok(network), Ok(prefix_len)) if prefix_len <= 32 => Some(Ipv4Subnet::new(network, prefix_len)),
+                _ => None,
+            },
+            None => convert_string_to_ipv4(entry).ok().map(|network| Ipv4Subnet::new(network, 32)),
+        };
+        match subnet {
+            Some(subnet) => subnets.push(subnet),
+            None => warn!("Ignoring \"{}\", it isn't an ipv4 address or subnet", entry),
+        }
+    }
+    subnets
 }
 
+//a local subnet from config.ini wins over the mask in the record, the exported mask is the mask of the route
+//so it only matches the real subnet when the destination is directly connected to the exporter
+pub fn is_directed_broadcast(dst_addr: Ipv4Addr, dst_mask: u8, local_subnets: &[Ipv4Subnet]) -> bool {
+    let subnet = match local_subnets.iter().find(|s| s.contains(dst_addr)) {
+        Some(s) => *s,
+        None => Ipv4Subnet::new(dst_addr, dst_mask),
+    };
+    subnet.broadcast() == Some(dst_addr)
+}
 
 pub fn handle_icmp_code(protocol: i32, src_port:i32, dst_port:i32) -> (String, i32, i32) {
     //returning tuple in case I want to actually return type and code later
@@ -106,47 +169,82 @@ pub fn handle_icmp_code(protocol: i32, src_port:i32, dst_port:i32) -> (String, i
 }
 
 pub fn handle_traffic_type(flow: &NetFlow) -> String {
-    //returning tuple in case I want to actually return type and code later
+    //local_subnets aren't known here, so directed broadcasts are only found from the exported mask
+    handle_traffic_type_in_flow(flow.src_ip, flow.dst_ip, flow.dst_mask, &[]).as_str().to_string()
+}
 
-    //This only partially works because we don't know the mask for some broadcast traffic 
-    //e.g. 10.0.0.255 could be valid unicast in a /16.
+pub fn handle_traffic_type_in_flow(src_addr: Ipv4Addr, dst_addr: Ipv4Addr, dst_mask: u8, local_subnets: &[Ipv4Subnet]) -> TrafficType {
 
-    let src_ip_cast = get_ip_cast_type(flow.src_ip);
-    let dst_ip_cast = get_ip_cast_type(flow.dst_ip);
+    let src_ip_cast = get_ip_cast_type(src_addr);
+    let dst_ip_cast = get_ip_cast_type(dst_addr);
 
-    //let dst_mac = flow.
 
     if src_ip_cast == TrafficType::Multicast || dst_ip_cast == TrafficType::Multicast {
-        "Multicast".to_string()
+        TrafficType::Multicast
     }
     else if src_ip_cast == TrafficType::Broadcast || dst_ip_cast == TrafficType::Broadcast {
-        "Broadcast".to_string()
+        TrafficType::Broadcast
+    }
+    else if is_directed_broadcast(dst_addr, dst_mask, local_subnets) {
+        TrafficType::DirectedBroadcast
     }
-    // else if src_ip_cast == TrafficType::Broadcast || dst_ip_cast == TrafficType::Broadcast {
-    //     "Broadcast".to_string()
-    // }
-    else 
-    {
-        "Unicast".to_string()
+    else {
+        //the most unusual end decides, so a private host talking to the internet is public
+        //a missing address can't be placed at all, so it goes first
+        [
+            TrafficType::Unspecified,
+            TrafficType::Loopback,
+            TrafficType::LinkLocal,
+            TrafficType::Documentation,
+            TrafficType::Anycast,
+            TrafficType::Public,
+        ].into_iter()
+            .find(|t| *t == src_ip_cast || *t == dst_ip_cast)
+            .unwrap_or(TrafficType::Private)
     }
 
 }
 
-pub fn handle_traffic_type_in_flow(src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> TrafficType {
-
-    let src_ip_cast = get_ip_cast_type(src_addr);
-    let dst_ip_cast = get_ip_cast_type(dst_addr);
+//same layout as nfdump, a dot for every flag that isn't set
+pub fn format_tcp_flags(flags: u8) -> String {
+    [(TCP_URG, 'U'), (TCP_ACK, 'A'), (TCP_PSH, 'P'), (TCP_RST, 'R'), (TCP_SYN, 'S'), (TCP_FIN, 'F')]
+        .iter()
+        .map(|(bit, c)| if flags & bit != 0 { *c } else { '.' })
+        .collect()
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if src_ip_cast == TrafficType::Multicast || dst_ip_cast == TrafficType::Multicast {
-        TrafficType::Multicast
+    fn classify(src_addr: [u8; 4], dst_addr: [u8; 4], dst_mask: u8) -> TrafficType {
+        handle_traffic_type_in_flow(Ipv4Addr::from(src_addr), Ipv4Addr::from(dst_addr), dst_mask, &[])
     }
-    else if src_ip_cast == TrafficType::Broadcast || dst_ip_cast == TrafficType::Broadcast {
-        TrafficType::Broadcast
+
+    #[test]
+    fn the_most_unusual_end_decides_the_scope() {
+        assert_eq!(classify([10, 0, 0, 1], [192, 168, 1, 1], 0), TrafficType::Private);
+        assert_eq!(classify([10, 0, 0, 1], [8, 8, 8, 8], 0), TrafficType::Public);
+        assert_eq!(classify([127, 0, 0, 1], [127, 0, 0, 2], 0), TrafficType::Loopback);
+        assert_eq!(classify([169, 254, 1, 1], [10, 0, 0, 1], 0), TrafficType::LinkLocal);
+        assert_eq!(classify([198, 51, 100, 7], [8, 8, 8, 8], 0), TrafficType::Documentation);
+        assert_eq!(classify([10, 0, 0, 1], [192, 88, 99, 1], 0), TrafficType::Anycast);
+        assert_eq!(classify([10, 0, 0, 1], [224, 0, 0, 251], 0), TrafficType::Multicast);
+        assert_eq!(classify([10, 0, 0, 1], [10, 0, 0, 255], 24), TrafficType::DirectedBroadcast);
     }
-    else 
-    {
-        TrafficType::Unicast
+
+    #[test]
+    fn missing_addresses_are_not_public() {
+        assert_eq!(classify([0, 0, 0, 0], [0, 0, 0, 0], 0), TrafficType::Unspecified);
+        assert_eq!(classify([10, 0, 0, 1], [0, 0, 0, 0], 0), TrafficType::Unspecified);
+        assert_eq!(classify([0, 0, 0, 0], [8, 8, 8, 8], 0), TrafficType::Unspecified);
+        //a dhcp discover comes from 0.0.0.0 and is still a broadcast
+        assert_eq!(classify([0, 0, 0, 0], [255, 255, 255, 255], 0), TrafficType::Broadcast);
     }
 
+    #[test]
+    fn invalid_subnets_are_left_out() {
+        let subnets = parse_subnets(" 10.0.0.0/8; 10.0.0.300; 192.168.1.0/33; 172.16.0.1 ;");
+        assert_eq!(subnets, vec![Ipv4Subnet::new(Ipv4Addr::new(10, 0, 0, 0), 8), Ipv4Subnet::new(Ipv4Addr::new(172, 16, 0, 1), 32)]);
+    }
 }